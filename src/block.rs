@@ -0,0 +1,155 @@
+//! Rectangular (block) selections, for column mode editing.
+
+use std::ops::Range;
+use string_utils::is_line_ending;
+use super::{Rope, RopeSlice};
+
+
+/// A rectangular selection: the same range of columns on each of a range
+/// of lines.
+///
+/// Columns are counted in graphemes from the start of the line, except
+/// that a tab advances to the next multiple of the tab width.  A grapheme
+/// is in the block if the column that it starts at is.  Columns aren't
+/// display widths: a full-width CJK char or an emoji counts as a single
+/// column, so an editor that lays text out by display width has to
+/// convert its columns to these for lines with wide chars.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockSelection {
+    pub lines: Range<usize>,
+    pub columns: Range<usize>,
+    pub tab_width: usize,
+}
+
+
+impl BlockSelection {
+    /// Creates a block selection, with a tab width of 4.
+    pub fn new(lines: Range<usize>, columns: Range<usize>) -> BlockSelection {
+        assert!(lines.start <= lines.end, "BlockSelection::new(): the line range must not be reversed.");
+        assert!(columns.start <= columns.end, "BlockSelection::new(): the column range must not be reversed.");
+
+        BlockSelection {
+            lines,
+            columns,
+            tab_width: 4,
+        }
+    }
+
+
+    pub fn with_tab_width(self, tab_width: usize) -> BlockSelection {
+        assert!(tab_width > 0, "BlockSelection::with_tab_width(): tab_width must be greater than zero.");
+
+        BlockSelection {
+            tab_width,
+            ..self
+        }
+    }
+}
+
+
+/// Where a block's columns are on one line.
+struct BlockLine {
+    /// The chars in the block's columns.
+    chars: Range<usize>,
+
+    /// The column of the end of the line, if it's before the start of the
+    /// block.
+    short_by: usize,
+}
+
+
+impl Rope {
+    /// Returns the text of a block selection, as a slice for each line.
+    /// Lines that end before the block's columns get an empty slice at
+    /// their end.
+    pub fn block_slices<'a>(&'a self, block: &BlockSelection) -> Vec<RopeSlice<'a>> {
+        self.check_block_lines(block, "Rope::block_slices()");
+
+        block.lines.clone().map(|li| {
+            let line = self.block_line(li, block);
+            self.slice(line.chars.start, line.chars.end)
+        }).collect()
+    }
+
+
+    /// Inserts text at the start column of a block selection, on each of
+    /// its lines.  Lines that end before that column are padded with
+    /// spaces up to it.  All of the lines are rebuilt in a single edit.
+    pub fn insert_text_at_block(&mut self, block: &BlockSelection, text: &str) {
+        self.check_block_lines(block, "Rope::insert_text_at_block()");
+
+        let edits: Vec<(Range<usize>, String)> = block.lines.clone().map(|li| {
+            let line = self.block_line(li, block);
+            (line.chars.start..line.chars.start, " ".repeat(line.short_by) + text)
+        }).collect();
+        self.replace_block_ranges(&edits[..]);
+    }
+
+
+    /// Removes the text of a block selection from each of its lines, in a
+    /// single edit.
+    pub fn remove_block(&mut self, block: &BlockSelection) {
+        self.check_block_lines(block, "Rope::remove_block()");
+
+        let edits: Vec<(Range<usize>, String)> = block.lines.clone().map(|li| {
+            (self.block_line(li, block).chars, String::new())
+        }).collect();
+        self.replace_block_ranges(&edits[..]);
+    }
+
+
+    /// Replaces ordered, non-overlapping char ranges with new text, as a
+    /// single edit that rebuilds the text from the start of the first to
+    /// the end of the last.
+    fn replace_block_ranges(&mut self, edits: &[(Range<usize>, String)]) {
+        if edits.iter().all(|(range, text)| range.start == range.end && text.is_empty()) {
+            return;
+        }
+        let start = edits[0].0.start;
+        let end = edits[edits.len() - 1].0.end;
+
+        let mut new_text = String::new();
+        let mut pos = start;
+        for (range, text) in edits {
+            new_text.extend(self.char_iter_between_indices(pos, range.start));
+            new_text.push_str(&text[..]);
+            pos = range.end;
+        }
+
+        self.rebuild_between_char_indices(start, end, &new_text[..]);
+    }
+
+
+    fn check_block_lines(&self, block: &BlockSelection, function: &str) {
+        assert!(block.lines.end <= self.line_ending_count() + 1, "{}: attempted to select lines beyond the end of the text.", function);
+    }
+
+
+    /// Finds the chars of a line that are in a block's columns.
+    fn block_line(&self, li: usize, block: &BlockSelection) -> BlockLine {
+        let line_start = self.line_index_to_char_index(li);
+        let mut col = 0;
+        let mut ci = line_start;
+        let mut start = None;
+
+        for g in self.grapheme_iter_at_char_index(line_start) {
+            if is_line_ending(g) {
+                break;
+            }
+            if start.is_none() && col >= block.columns.start {
+                start = Some(ci);
+            }
+            if col >= block.columns.end {
+                break;
+            }
+            col = if g == "\t" { (col / block.tab_width + 1) * block.tab_width } else { col + 1 };
+            ci += g.chars().count();
+        }
+
+        let short_by = if start.is_none() && col < block.columns.start { block.columns.start - col } else { 0 };
+        return BlockLine {
+            chars: start.unwrap_or(ci)..ci,
+            short_by,
+        };
+    }
+}
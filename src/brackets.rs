@@ -0,0 +1,232 @@
+//! Bracket matching.
+
+use std::cmp::{min, max};
+use diff::RevCharIter;
+use super::{Rope, RopeData};
+
+
+/// The bracket pairs that Rope::matching_bracket() matches.
+pub const DEFAULT_BRACKET_PAIRS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+
+/// The bracket pairs whose depths are cached with bracket caching.
+const CACHED_BRACKET_PAIRS: [(char, char); 4] = [('(', ')'), ('[', ']'), ('{', '}'), ('<', '>')];
+
+
+/// How the depth of one kind of bracket changes over a node's text, with
+/// depth being the number of opening brackets minus the number of
+/// closing ones.
+#[derive(Debug, Copy, Clone, Default)]
+struct BracketDepth {
+    /// The change over the whole text.
+    net: isize,
+
+    /// The lowest change over a prefix of the text, which is never above
+    /// zero.
+    min_prefix: isize,
+
+    /// The highest change over a suffix of the text, which is never below
+    /// zero.
+    max_suffix: isize,
+}
+
+
+/// The bracket depths of a node's text, for each of the cached bracket
+/// pairs.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BracketSummary {
+    depths: [BracketDepth; 4],
+}
+
+
+impl Rope {
+    /// Returns the char index of the bracket that matches the one at the
+    /// given char index, or None if there isn't a bracket there or it
+    /// isn't matched.  The brackets are those of DEFAULT_BRACKET_PAIRS.
+    pub fn matching_bracket(&self, index: usize) -> Option<usize> {
+        self.matching_bracket_with_pairs(index, &DEFAULT_BRACKET_PAIRS[..])
+    }
+
+
+    /// Same as matching_bracket(), with the given bracket pairs, e.g.
+    /// `&[('(', ')'), ('<', '>')]`.  Only brackets of the same pair are
+    /// counted when looking for the match, so differently nested
+    /// brackets of other pairs don't stop it.
+    ///
+    /// This scans the text between the brackets, except with bracket
+    /// caching where pairs among `()[]{}<>` are matched in O(log N) time.
+    pub fn matching_bracket_with_pairs(&self, index: usize, pairs: &[(char, char)]) -> Option<usize> {
+        assert!(index < self.char_count(), "Rope::matching_bracket(): attempted to index beyond the end of the text.");
+
+        let c = self.char_at_index(index);
+        let &(open, close) = pairs.iter().find(|&&(open, close)| c == open || c == close)?;
+        assert!(open != close, "Rope::matching_bracket(): a bracket pair must have different opening and closing brackets.");
+
+        let cached = if self.config.cache_brackets { CACHED_BRACKET_PAIRS.iter().position(|&pair| pair == (open, close)) } else { None };
+        let mut depth = 0;
+        if c == open {
+            if let Some(pair) = cached {
+                return self.find_closing_bracket(pair, index + 1, &mut depth);
+            }
+            for (i, c) in self.char_iter_at_index(index + 1).enumerate() {
+                depth += bracket_step(c, open, close);
+                if depth < 0 {
+                    return Some(index + 1 + i);
+                }
+            }
+        }
+        else {
+            if let Some(pair) = cached {
+                return self.find_opening_bracket(pair, index, &mut depth);
+            }
+            for (i, c) in RevCharIter::new(self, index).enumerate() {
+                depth -= bracket_step(c, open, close);
+                if depth < 0 {
+                    return Some(index - 1 - i);
+                }
+            }
+        }
+
+        return None;
+    }
+
+
+    //================================================================
+    // Cached bracket depths
+    //================================================================
+
+    /// Returns the node's bracket summary, which is boxed to keep nodes
+    /// small when bracket caching isn't used.
+    fn bracket_summary(&self) -> &BracketSummary {
+        self.bracket_summary_.get_or_init(|| {
+            let mut summary = BracketSummary::default();
+            match self.data {
+                RopeData::Leaf(ref text) => {
+                    for (depth, &(open, close)) in summary.depths.iter_mut().zip(CACHED_BRACKET_PAIRS.iter()) {
+                        for c in text.chars() {
+                            depth.net += bracket_step(c, open, close);
+                            depth.min_prefix = min(depth.min_prefix, depth.net);
+                        }
+                        let mut suffix = 0;
+                        for c in text.chars().rev() {
+                            suffix += bracket_step(c, open, close);
+                            depth.max_suffix = max(depth.max_suffix, suffix);
+                        }
+                    }
+                },
+
+                RopeData::Branch(ref children) => {
+                    for (pair, depth) in summary.depths.iter_mut().enumerate() {
+                        for child in children.iter() {
+                            let child_depth = child.bracket_summary().depths[pair];
+                            depth.min_prefix = min(depth.min_prefix, depth.net + child_depth.min_prefix);
+                            depth.net += child_depth.net;
+                        }
+                        let mut suffix = 0;
+                        for child in children.iter().rev() {
+                            let child_depth = child.bracket_summary().depths[pair];
+                            depth.max_suffix = max(depth.max_suffix, suffix + child_depth.max_suffix);
+                            suffix += child_depth.net;
+                        }
+                    }
+                },
+            }
+            Box::new(summary)
+        })
+    }
+
+
+    /// Finds the first char at or after `from` where the depth, starting
+    /// at `depth`, drops below zero.  Nodes that it can't drop below zero
+    /// in are skipped using their cached depths.
+    fn find_closing_bracket(&self, pair: usize, from: usize, depth: &mut isize) -> Option<usize> {
+        if from == 0 {
+            let node_depth = self.bracket_summary().depths[pair];
+            if *depth + node_depth.min_prefix >= 0 {
+                *depth += node_depth.net;
+                return None;
+            }
+        }
+
+        let (open, close) = CACHED_BRACKET_PAIRS[pair];
+        match self.data {
+            RopeData::Leaf(ref text) => {
+                for (i, c) in text.chars().enumerate().skip(from) {
+                    *depth += bracket_step(c, open, close);
+                    if *depth < 0 {
+                        return Some(i);
+                    }
+                }
+            },
+
+            RopeData::Branch(ref children) => {
+                let mut offset = 0;
+                for child in children.iter() {
+                    if from < offset + child.char_count_ {
+                        let child_from = from.saturating_sub(offset);
+                        if let Some(i) = child.find_closing_bracket(pair, child_from, depth) {
+                            return Some(offset + i);
+                        }
+                    }
+                    offset += child.char_count_;
+                }
+            },
+        }
+
+        return None;
+    }
+
+
+    /// Finds the last char before `to` where the depth, starting at
+    /// `depth` and going backwards, drops below zero.
+    fn find_opening_bracket(&self, pair: usize, to: usize, depth: &mut isize) -> Option<usize> {
+        if to == self.char_count_ {
+            let node_depth = self.bracket_summary().depths[pair];
+            if *depth - node_depth.max_suffix >= 0 {
+                *depth -= node_depth.net;
+                return None;
+            }
+        }
+
+        let (open, close) = CACHED_BRACKET_PAIRS[pair];
+        match self.data {
+            RopeData::Leaf(ref text) => {
+                let chars: Vec<char> = text.chars().take(to).collect();
+                for (i, &c) in chars.iter().enumerate().rev() {
+                    *depth -= bracket_step(c, open, close);
+                    if *depth < 0 {
+                        return Some(i);
+                    }
+                }
+            },
+
+            RopeData::Branch(ref children) => {
+                let mut offset = self.char_count_;
+                for child in children.iter().rev() {
+                    offset -= child.char_count_;
+                    if offset < to {
+                        let child_to = min(to - offset, child.char_count_);
+                        if let Some(i) = child.find_opening_bracket(pair, child_to, depth) {
+                            return Some(offset + i);
+                        }
+                    }
+                }
+            },
+        }
+
+        return None;
+    }
+}
+
+
+/// Returns how a char changes the depth of a bracket pair.
+fn bracket_step(c: char, open: char, close: char) -> isize {
+    if c == open {
+        1
+    }
+    else if c == close {
+        -1
+    }
+    else {
+        0
+    }
+}
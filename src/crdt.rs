@@ -0,0 +1,610 @@
+//! Collaborative text editing without a central server, as a CRDT in
+//! the style of YATA.
+//!
+//! Every inserted char gets a unique id, made of the id of the replica
+//! that inserted it and a counter of that replica.  Inserts remember the
+//! ids of the chars that were on either side of them, and concurrent
+//! inserts at the same place are ordered by the same rules on every
+//! replica, so all replicas that have applied the same ops have the same
+//! text, whatever order the ops arrived in.  Removed chars are kept as
+//! tombstones, so that later ops can still refer to them.
+//!
+//! The chars are kept in runs, in a treap ordered by their position in
+//! the text and counting the visible chars of each subtree, which makes
+//! finding the run at a char index and the char index of a run O(log N).
+//! The visible text itself is kept in a Rope.
+
+use std::collections::{BTreeMap, HashSet};
+use super::Rope;
+
+
+const NIL: usize = usize::MAX;
+
+
+/// The id of an inserted char.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OpId {
+    pub replica: u64,
+    pub counter: u64,
+}
+
+
+impl OpId {
+    pub fn new(replica: u64, counter: u64) -> OpId {
+        OpId {
+            replica,
+            counter,
+        }
+    }
+
+
+    fn offset(&self, n: usize) -> OpId {
+        OpId::new(self.replica, self.counter + n as u64)
+    }
+}
+
+
+/// An edit, to be sent to the other replicas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrdtOp {
+    /// Inserts text whose chars get consecutive ids starting at `id`,
+    /// between the chars with the `origin` and `right_origin` ids, or
+    /// the start or end of the text if they're None.
+    Insert {
+        id: OpId,
+        origin: Option<OpId>,
+        right_origin: Option<OpId>,
+        text: String,
+    },
+
+    /// Removes the chars with the ids of each run of consecutive ids,
+    /// given as the first id and the length.
+    Remove {
+        runs: Vec<(OpId, usize)>,
+    },
+}
+
+
+/// A run of chars with consecutive ids, that were inserted together.
+#[derive(Debug, Clone)]
+struct Item {
+    id: OpId,
+    len: usize,
+    origin: Option<OpId>,
+    right_origin: Option<OpId>,
+    is_removed: bool,
+
+    // The treap
+    priority: u64,
+    parent: usize,
+    left: usize,
+    right: usize,
+    visible_count: usize,
+}
+
+
+impl Item {
+    fn visible_len(&self) -> usize {
+        if self.is_removed { 0 } else { self.len }
+    }
+}
+
+
+/// One replica of a collaboratively edited text.
+#[derive(Debug, Clone)]
+pub struct CrdtText {
+    replica: u64,
+    counter: u64,
+    rope: Rope,
+    items: Vec<Item>,
+    root: usize,
+    item_ids: BTreeMap<OpId, usize>,
+    pending: Vec<CrdtOp>,
+}
+
+
+impl CrdtText {
+    /// Creates an empty text, for the replica with the given id.  Every
+    /// replica needs an id of its own.
+    pub fn new(replica: u64) -> CrdtText {
+        CrdtText {
+            replica,
+            counter: 0,
+            rope: Rope::new(),
+            items: Vec::new(),
+            root: NIL,
+            item_ids: BTreeMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+
+    pub fn replica(&self) -> u64 {
+        self.replica
+    }
+
+
+    /// Returns the current text.
+    pub fn rope(&self) -> &Rope {
+        &self.rope
+    }
+
+
+    pub fn char_count(&self) -> usize {
+        self.rope.char_count()
+    }
+
+
+    /// Returns the number of received ops that are waiting for ops that
+    /// they depend on.
+    pub fn pending_op_count(&self) -> usize {
+        self.pending.len()
+    }
+
+
+    /// Inserts text at a char index, and returns the op to send to the
+    /// other replicas.
+    pub fn insert_text_at_char_index(&mut self, text: &str, pos: usize) -> CrdtOp {
+        assert!(pos <= self.char_count(), "CrdtText::insert_text_at_char_index(): attempted to insert past the end of the text.");
+
+        let (origin, right) = if pos == 0 {
+            (None, self.first())
+        }
+        else {
+            let (i, offset) = self.find_visible(pos - 1);
+            self.split(i, offset + 1);
+            (Some(self.items[i].id.offset(self.items[i].len - 1)), self.next(i))
+        };
+        let right_origin = if right == NIL { None } else { Some(self.items[right].id) };
+
+        let op = CrdtOp::Insert {
+            id: OpId::new(self.replica, self.counter),
+            origin,
+            right_origin,
+            text: text.to_string(),
+        };
+        self.apply(op.clone());
+        return op;
+    }
+
+
+    /// Removes the text between two char indices, and returns the op to
+    /// send to the other replicas.
+    pub fn remove_text_between_char_indices(&mut self, pos_a: usize, pos_b: usize) -> CrdtOp {
+        assert!(pos_a <= pos_b, "CrdtText::remove_text_between_char_indices(): pos_a must be less than or equal to pos_b.");
+        assert!(pos_b <= self.char_count(), "CrdtText::remove_text_between_char_indices(): attempted to remove past the end of the text.");
+
+        let mut runs: Vec<(OpId, usize)> = Vec::new();
+        let mut remaining = pos_b - pos_a;
+        while remaining > 0 {
+            let (mut i, offset) = self.find_visible(pos_a);
+            if offset > 0 {
+                i = self.split(i, offset);
+            }
+            self.split(i, remaining);
+
+            let (id, len) = (self.items[i].id, self.items[i].len);
+            self.remove_item(i);
+            remaining -= len;
+
+            if let Some(last) = runs.last_mut() {
+                if last.0.offset(last.1) == id {
+                    last.1 += len;
+                    continue;
+                }
+            }
+            runs.push((id, len));
+        }
+
+        return CrdtOp::Remove { runs };
+    }
+
+
+    /// Applies an op from any replica.  Ops can be applied in any order
+    /// and more than once; ops whose dependencies haven't been applied
+    /// yet are kept until they have.
+    pub fn apply(&mut self, op: CrdtOp) {
+        if !self.try_apply(&op) {
+            self.pending.push(op);
+            return;
+        }
+
+        // Retry the pending ops until none of them can be applied
+        loop {
+            let count = self.pending.len();
+            let pending = ::std::mem::take(&mut self.pending);
+            for op in pending {
+                if !self.try_apply(&op) {
+                    self.pending.push(op);
+                }
+            }
+            if self.pending.len() == count {
+                break;
+            }
+        }
+    }
+
+
+    /// Returns the id of the char at a char index.
+    pub fn char_index_to_id(&self, pos: usize) -> OpId {
+        assert!(pos < self.char_count(), "CrdtText::char_index_to_id(): attempted to index past the end of the text.");
+
+        let (i, offset) = self.find_visible(pos);
+        return self.items[i].id.offset(offset);
+    }
+
+
+    /// Returns the char index of the char with an id, or None if it
+    /// isn't known.  Removed chars map to where they were, i.e. the index
+    /// of the next char that isn't removed.
+    pub fn id_to_char_index(&self, id: OpId) -> Option<usize> {
+        let i = self.find_id(id)?;
+        let pos = self.item_char_index(i);
+        if self.items[i].is_removed {
+            return Some(pos);
+        }
+        return Some(pos + (id.counter - self.items[i].id.counter) as usize);
+    }
+
+
+    //-----------------------------------------------------------------
+    // Applying ops
+
+    fn try_apply(&mut self, op: &CrdtOp) -> bool {
+        match *op {
+            CrdtOp::Insert { id, origin, right_origin, ref text } => {
+                let len = text.chars().count();
+                if len == 0 || self.find_id(id).is_some() {
+                    return true;
+                }
+                if origin.map(|id| self.find_id(id).is_none()) == Some(true) || right_origin.map(|id| self.find_id(id).is_none()) == Some(true) {
+                    return false;
+                }
+                if id.replica == self.replica {
+                    self.counter = ::std::cmp::max(self.counter, id.counter + len as u64);
+                }
+
+                let i = self.integrate(id, len, origin, right_origin);
+                let pos = self.item_char_index(i);
+                self.rope.insert_text_at_char_index(text, pos);
+                return true;
+            },
+
+            CrdtOp::Remove { ref runs } => {
+                if !runs.iter().all(|&(id, len)| self.has_ids(id, len)) {
+                    return false;
+                }
+                for &(id, len) in runs.iter() {
+                    let mut counter = id.counter;
+                    let end = id.counter + len as u64;
+                    while counter < end {
+                        let mut i = self.find_id(OpId::new(id.replica, counter)).unwrap();
+                        let offset = (counter - self.items[i].id.counter) as usize;
+                        if offset > 0 {
+                            i = self.split(i, offset);
+                        }
+                        self.split(i, (end - counter) as usize);
+                        counter += self.items[i].len as u64;
+                        self.remove_item(i);
+                    }
+                }
+                return true;
+            },
+        }
+    }
+
+
+    /// Places a new run among the existing ones, and returns its index.
+    fn integrate(&mut self, id: OpId, len: usize, origin: Option<OpId>, right_origin: Option<OpId>) -> usize {
+        // The runs that the new one was inserted between, split so that
+        // the origins are at their ends
+        let mut left = match origin {
+            Some(origin) => {
+                let i = self.find_id(origin).unwrap();
+                self.split(i, (origin.counter - self.items[i].id.counter) as usize + 1);
+                i
+            },
+            None => NIL,
+        };
+        let right = match right_origin {
+            Some(right_origin) => {
+                let i = self.find_id(right_origin).unwrap();
+                let offset = (right_origin.counter - self.items[i].id.counter) as usize;
+                if offset > 0 { self.split(i, offset) } else { i }
+            },
+            None => NIL,
+        };
+
+        // Order the new run among the concurrently inserted ones between
+        // them
+        let mut items_before_origin = HashSet::new();
+        let mut conflicting_items = HashSet::new();
+        let mut o = if left == NIL { self.first() } else { self.next(left) };
+        while o != right && o != NIL {
+            items_before_origin.insert(o);
+            conflicting_items.insert(o);
+            let o_origin = self.items[o].origin;
+            if o_origin == origin {
+                if self.items[o].id.replica < id.replica {
+                    left = o;
+                    conflicting_items.clear();
+                }
+                else if self.items[o].right_origin == right_origin {
+                    break;
+                }
+            }
+            else {
+                let o_origin_item = o_origin.and_then(|id| self.find_id(id));
+                match o_origin_item {
+                    Some(item) if items_before_origin.contains(&item) => {
+                        if !conflicting_items.contains(&item) {
+                            left = o;
+                            conflicting_items.clear();
+                        }
+                    },
+                    _ => break,
+                }
+            }
+            o = self.next(o);
+        }
+
+        let i = self.new_item(Item {
+            id,
+            len,
+            origin,
+            right_origin,
+            is_removed: false,
+            priority: 0,
+            parent: NIL,
+            left: NIL,
+            right: NIL,
+            visible_count: len,
+        });
+        self.insert_after(left, i);
+        return i;
+    }
+
+
+    fn remove_item(&mut self, i: usize) {
+        if self.items[i].is_removed {
+            return;
+        }
+        let pos = self.item_char_index(i);
+        self.rope.remove_text_between_char_indices(pos, pos + self.items[i].len);
+        self.items[i].is_removed = true;
+        self.update_up(i);
+    }
+
+
+    /// Splits a run so that it's `len` chars long, and returns the index
+    /// of the run with the rest of its chars.  Does nothing and returns
+    /// NIL if the run isn't longer than that.
+    fn split(&mut self, i: usize, len: usize) -> usize {
+        if len == 0 || len >= self.items[i].len {
+            return NIL;
+        }
+
+        let id = self.items[i].id.offset(len);
+        let rest = self.new_item(Item {
+            id,
+            len: self.items[i].len - len,
+            origin: Some(OpId::new(id.replica, id.counter - 1)),
+            right_origin: self.items[i].right_origin,
+            is_removed: self.items[i].is_removed,
+            priority: 0,
+            parent: NIL,
+            left: NIL,
+            right: NIL,
+            visible_count: 0,
+        });
+        self.items[rest].visible_count = self.items[rest].visible_len();
+        self.items[i].len = len;
+        self.insert_after(i, rest);
+        return rest;
+    }
+
+
+    fn find_id(&self, id: OpId) -> Option<usize> {
+        let (&start, &i) = self.item_ids.range(..=id).next_back()?;
+        if start.replica == id.replica && id.counter < start.counter + self.items[i].len as u64 {
+            return Some(i);
+        }
+        return None;
+    }
+
+
+    /// Returns whether all chars of a run of ids are known.
+    fn has_ids(&self, id: OpId, len: usize) -> bool {
+        let end = id.counter + len as u64;
+        let mut counter = id.counter;
+        while counter < end {
+            match self.find_id(OpId::new(id.replica, counter)) {
+                Some(i) => counter = self.items[i].id.counter + self.items[i].len as u64,
+                None => return false,
+            }
+        }
+        return true;
+    }
+
+
+    //-----------------------------------------------------------------
+    // The treap
+
+    fn new_item(&mut self, mut item: Item) -> usize {
+        item.priority = hash_id(item.id);
+        self.item_ids.insert(item.id, self.items.len());
+        self.items.push(item);
+        return self.items.len() - 1;
+    }
+
+
+    fn visible_count(&self, i: usize) -> usize {
+        if i == NIL { 0 } else { self.items[i].visible_count }
+    }
+
+
+    /// Returns the number of visible chars before a run.
+    fn item_char_index(&self, i: usize) -> usize {
+        let mut pos = self.visible_count(self.items[i].left);
+        let mut i = i;
+        while self.items[i].parent != NIL {
+            let parent = self.items[i].parent;
+            if self.items[parent].right == i {
+                pos += self.visible_count(self.items[parent].left) + self.items[parent].visible_len();
+            }
+            i = parent;
+        }
+        return pos;
+    }
+
+
+    /// Finds the run with the visible char at a char index, and the
+    /// offset of the char in it.
+    fn find_visible(&self, pos: usize) -> (usize, usize) {
+        let mut pos = pos;
+        let mut i = self.root;
+        loop {
+            let left_count = self.visible_count(self.items[i].left);
+            if pos < left_count {
+                i = self.items[i].left;
+            }
+            else if pos < left_count + self.items[i].visible_len() {
+                return (i, pos - left_count);
+            }
+            else {
+                pos -= left_count + self.items[i].visible_len();
+                i = self.items[i].right;
+            }
+        }
+    }
+
+
+    fn first(&self) -> usize {
+        let mut i = self.root;
+        while i != NIL && self.items[i].left != NIL {
+            i = self.items[i].left;
+        }
+        return i;
+    }
+
+
+    fn next(&self, i: usize) -> usize {
+        if self.items[i].right != NIL {
+            let mut i = self.items[i].right;
+            while self.items[i].left != NIL {
+                i = self.items[i].left;
+            }
+            return i;
+        }
+
+        let mut i = i;
+        loop {
+            let parent = self.items[i].parent;
+            if parent == NIL || self.items[parent].left == i {
+                return parent;
+            }
+            i = parent;
+        }
+    }
+
+
+    /// Inserts a new run right after another one, or at the start if
+    /// that's NIL.
+    fn insert_after(&mut self, after: usize, i: usize) {
+        if self.root == NIL {
+            self.root = i;
+            return;
+        }
+
+        // Attach it as a leaf where it belongs in the order
+        let (parent, is_left) = if after == NIL {
+            (self.first(), true)
+        }
+        else if self.items[after].right == NIL {
+            (after, false)
+        }
+        else {
+            let mut parent = self.items[after].right;
+            while self.items[parent].left != NIL {
+                parent = self.items[parent].left;
+            }
+            (parent, true)
+        };
+        if is_left {
+            self.items[parent].left = i;
+        }
+        else {
+            self.items[parent].right = i;
+        }
+        self.items[i].parent = parent;
+
+        // And restore the heap order of the priorities
+        while self.items[i].parent != NIL && self.items[i].priority > self.items[self.items[i].parent].priority {
+            self.rotate_up(i);
+        }
+        self.update_up(i);
+    }
+
+
+    fn rotate_up(&mut self, i: usize) {
+        let parent = self.items[i].parent;
+        let grandparent = self.items[parent].parent;
+
+        if self.items[parent].left == i {
+            let child = self.items[i].right;
+            self.items[parent].left = child;
+            if child != NIL {
+                self.items[child].parent = parent;
+            }
+            self.items[i].right = parent;
+        }
+        else {
+            let child = self.items[i].left;
+            self.items[parent].right = child;
+            if child != NIL {
+                self.items[child].parent = parent;
+            }
+            self.items[i].left = parent;
+        }
+        self.items[parent].parent = i;
+        self.items[i].parent = grandparent;
+
+        if grandparent == NIL {
+            self.root = i;
+        }
+        else if self.items[grandparent].left == parent {
+            self.items[grandparent].left = i;
+        }
+        else {
+            self.items[grandparent].right = i;
+        }
+
+        self.update(parent);
+        self.update(i);
+    }
+
+
+    fn update(&mut self, i: usize) {
+        let count = self.visible_count(self.items[i].left) + self.items[i].visible_len() + self.visible_count(self.items[i].right);
+        self.items[i].visible_count = count;
+    }
+
+
+    /// Updates the visible char counts of a run and its ancestors.
+    fn update_up(&mut self, i: usize) {
+        let mut i = i;
+        while i != NIL {
+            self.update(i);
+            i = self.items[i].parent;
+        }
+    }
+}
+
+
+/// Spreads ids over the priorities of the treap.
+fn hash_id(id: OpId) -> u64 {
+    let mut x = id.replica.rotate_left(32) ^ id.counter;
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    return x ^ (x >> 31);
+}
@@ -0,0 +1,448 @@
+//! Line and char level diffs between ropes.
+
+use std::cmp::min;
+use std::iter::Rev;
+use std::ops::Range;
+use std::ptr;
+use std::str::Chars;
+use string_utils::char_pos_to_byte_pos;
+use super::{Rope, RopeData};
+
+
+/// The largest edit distance that is searched for an optimal diff of a
+/// changed region.  Regions that differ more than this are replaced
+/// wholesale, which keeps the time and memory of a diff bounded.
+const MAX_EDIT_COST: usize = 2000;
+
+/// The largest changed region, in chars, that is diffed by char.  Larger
+/// ones are left as replaced lines in the char level script too.
+pub const MAX_CHAR_DIFF_SIZE: usize = 1 << 16;
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DiffKind {
+    Equal,
+    Delete,
+    Insert,
+}
+
+
+/// One step of an edit script.  The old range is in the rope that was
+/// diffed, and the new range in the one that it was diffed against.
+/// Deletions have an empty new range and insertions an empty old range,
+/// at the position where they happen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffOp {
+    pub kind: DiffKind,
+    pub old: Range<usize>,
+    pub new: Range<usize>,
+}
+
+
+/// The differences between two ropes, as edit scripts of line indices
+/// and of char indices.  Consecutive ops never have the same kind, and
+/// a deletion always comes before an insertion at the same place.
+///
+/// Lines include their line endings, and a rope has one line more than
+/// it has line endings, so the last line may be empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RopeDiff {
+    pub lines: Vec<DiffOp>,
+    pub chars: Vec<DiffOp>,
+}
+
+
+impl Rope {
+    /// Computes the differences between this rope and another one.
+    ///
+    /// Subtrees that the ropes share, e.g. because one is an edited clone
+    /// of the other, are skipped without looking at their text.
+    pub fn diff(&self, other: &Rope) -> RopeDiff {
+        let old_len = self.char_count();
+        let new_len = other.char_count();
+
+        // The common prefix and suffix, in chars
+        let prefix = common_prefix(self, other);
+        let max_suffix = min(old_len, new_len) - prefix;
+        let suffix = min(common_suffix(self, other), max_suffix);
+
+        // Widen the changed region to whole lines that start at the same
+        // place in both ropes.  Whether a position starts a line depends
+        // on the chars on both sides of it (to not split a CRLF), so the
+        // region starts before any line start that's right at the end of
+        // the prefix, and ends after the first line start that's inside
+        // the suffix.
+        let mut start_line = self.char_index_to_line_index(prefix);
+        if self.line_index_to_char_index(start_line) == prefix && prefix > 0 && self.char_at_index(prefix - 1) == '\u{000D}' {
+            start_line -= 1;
+        }
+        let old_line_count = self.line_ending_count() + 1;
+        let new_line_count = other.line_ending_count() + 1;
+        let old_end_line = self.char_index_to_line_index(old_len - suffix) + 1;
+        let (old_end_line, new_end_line) = if old_end_line < old_line_count {
+            let offset = self.line_index_to_char_index(old_end_line) - (old_len - suffix);
+            (old_end_line, other.char_index_to_line_index(new_len - suffix + offset))
+        }
+        else {
+            (old_line_count, new_line_count)
+        };
+
+        let old_lines = collect_lines(self, start_line, old_end_line);
+        let new_lines = collect_lines(other, start_line, new_end_line);
+        let middle_ops = diff_slices(&old_lines[..], &new_lines[..]);
+
+        // Line level script
+        let mut lines = DiffBuilder::new();
+        lines.push(DiffKind::Equal, start_line, start_line);
+        for &(kind, old_len, new_len) in middle_ops.iter() {
+            lines.push(kind, old_len, new_len);
+        }
+        lines.push(DiffKind::Equal, old_line_count - old_end_line, new_line_count - new_end_line);
+        let lines = lines.finish();
+
+        // Char level script, with changed lines diffed by char
+        let mut chars = DiffBuilder::new();
+        let mut i = 0;
+        while i < lines.len() {
+            let op = &lines[i];
+            let old_chars = line_range_to_char_range(self, &op.old);
+            let new_chars = line_range_to_char_range(other, &op.new);
+
+            let next_new_chars = if op.kind == DiffKind::Delete && (i + 1) < lines.len() && lines[i + 1].kind == DiffKind::Insert {
+                Some(line_range_to_char_range(other, &lines[i + 1].new))
+            }
+            else {
+                None
+            };
+
+            if let Some(new_chars) = next_new_chars {
+                if (old_chars.len() + new_chars.len()) > MAX_CHAR_DIFF_SIZE {
+                    chars.push(DiffKind::Delete, old_chars.len(), 0);
+                    chars.push(DiffKind::Insert, 0, new_chars.len());
+                    i += 2;
+                    continue;
+                }
+                let old_text: Vec<char> = self.char_iter_between_indices(old_chars.start, old_chars.end).collect();
+                let new_text: Vec<char> = other.char_iter_between_indices(new_chars.start, new_chars.end).collect();
+                for (kind, old_len, new_len) in diff_slices(&old_text[..], &new_text[..]) {
+                    chars.push(kind, old_len, new_len);
+                }
+                i += 2;
+            }
+            else {
+                chars.push(op.kind, old_chars.end - old_chars.start, new_chars.end - new_chars.start);
+                i += 1;
+            }
+        }
+
+        return RopeDiff {
+            lines,
+            chars: chars.finish(),
+        };
+    }
+}
+
+
+impl RopeDiff {
+    /// Applies the char level script to the rope that was diffed, turning
+    /// it into the one that it was diffed against.
+    pub fn apply(&self, rope: &mut Rope, new: &Rope) {
+        let old_len = self.chars.last().map(|op| op.old.end).unwrap_or(0);
+        assert!(rope.char_count() == old_len, "RopeDiff::apply(): the rope does not match the diffed rope.");
+
+        // Going from the end keeps the positions of the earlier ops valid
+        for op in self.chars.iter().rev() {
+            match op.kind {
+                DiffKind::Equal => {},
+                DiffKind::Delete => rope.remove_text_between_char_indices(op.old.start, op.old.end),
+                DiffKind::Insert => {
+                    let text: String = new.char_iter_between_indices(op.new.start, op.new.end).collect();
+                    rope.insert_text_at_char_index(&text[..], op.old.start);
+                },
+            }
+        }
+    }
+
+
+    /// Applies the line level script to the rope that was diffed, turning
+    /// it into the one that it was diffed against.
+    pub fn apply_lines(&self, rope: &mut Rope, new: &Rope) {
+        let old_line_count = self.lines.last().map(|op| op.old.end).unwrap_or(0);
+        assert!(rope.line_ending_count() + 1 == old_line_count, "RopeDiff::apply_lines(): the rope does not match the diffed rope.");
+
+        // The char ranges are found before editing, since an insertion
+        // after a last line without a line ending joins with that line
+        let old_chars: Vec<Range<usize>> = self.lines.iter().map(|op| line_range_to_char_range(rope, &op.old)).collect();
+        for (op, old_chars) in self.lines.iter().zip(old_chars).rev() {
+            match op.kind {
+                DiffKind::Equal => {},
+                DiffKind::Delete => rope.remove_text_between_char_indices(old_chars.start, old_chars.end),
+                DiffKind::Insert => {
+                    let new_chars = line_range_to_char_range(new, &op.new);
+                    let text: String = new.char_iter_between_indices(new_chars.start, new_chars.end).collect();
+                    rope.insert_text_at_char_index(&text[..], old_chars.start);
+                },
+            }
+        }
+    }
+}
+
+
+/// Builds an edit script from the lengths of its steps, merging steps of
+/// the same kind and dropping empty ones.
+struct DiffBuilder {
+    ops: Vec<DiffOp>,
+    old_pos: usize,
+    new_pos: usize,
+}
+
+
+impl DiffBuilder {
+    fn new() -> DiffBuilder {
+        DiffBuilder {
+            ops: Vec::new(),
+            old_pos: 0,
+            new_pos: 0,
+        }
+    }
+
+
+    fn push(&mut self, kind: DiffKind, old_len: usize, new_len: usize) {
+        let old = self.old_pos..(self.old_pos + old_len);
+        let new = self.new_pos..(self.new_pos + new_len);
+        self.old_pos = old.end;
+        self.new_pos = new.end;
+
+        if old.is_empty() && new.is_empty() {
+            return;
+        }
+
+        // Keep deletions before insertions, so that adjacent ones of each
+        // kind can always be merged
+        if kind == DiffKind::Delete && self.ops.last().map(|op| op.kind) == Some(DiffKind::Insert) {
+            let insert = self.ops.pop().unwrap();
+            self.push_merged(DiffKind::Delete, insert.old.start..old.end, insert.new.start..insert.new.start);
+            self.push_merged(DiffKind::Insert, old.end..old.end, insert.new);
+            return;
+        }
+
+        self.push_merged(kind, old, new);
+    }
+
+
+    fn push_merged(&mut self, kind: DiffKind, old: Range<usize>, new: Range<usize>) {
+        if let Some(last) = self.ops.last_mut() {
+            if last.kind == kind {
+                last.old.end = old.end;
+                last.new.end = new.end;
+                return;
+            }
+        }
+        self.ops.push(DiffOp { kind, old, new });
+    }
+
+
+    fn finish(self) -> Vec<DiffOp> {
+        self.ops
+    }
+}
+
+
+/// Returns the length of the common prefix of two ropes, in chars.
+pub fn common_prefix(a: &Rope, b: &Rope) -> usize {
+    // Skip the shared subtrees at the start
+    let mut skipped = 0;
+    let mut node_a = a;
+    let mut node_b = b;
+    loop {
+        if ptr::eq(node_a, node_b) {
+            return skipped + node_a.char_count_;
+        }
+        match (&node_a.data, &node_b.data) {
+            (RopeData::Branch(children_a), RopeData::Branch(children_b)) => {
+                let mut i = 0;
+                while i < min(children_a.len(), children_b.len()) && ptr::eq(children_a.get(i), children_b.get(i)) {
+                    skipped += children_a.get(i).char_count_;
+                    i += 1;
+                }
+                if i == min(children_a.len(), children_b.len()) {
+                    break;
+                }
+                node_a = children_a.get(i);
+                node_b = children_b.get(i);
+            },
+            _ => break,
+        }
+    }
+
+    return skipped + a.char_iter_at_index(skipped).zip(b.char_iter_at_index(skipped)).take_while(|&(c1, c2)| c1 == c2).count();
+}
+
+
+/// Returns the length of the common suffix of two ropes, in chars.
+pub fn common_suffix(a: &Rope, b: &Rope) -> usize {
+    // Skip the shared subtrees at the end
+    let mut skipped = 0;
+    let mut node_a = a;
+    let mut node_b = b;
+    loop {
+        if ptr::eq(node_a, node_b) {
+            return skipped + node_a.char_count_;
+        }
+        match (&node_a.data, &node_b.data) {
+            (RopeData::Branch(children_a), RopeData::Branch(children_b)) => {
+                let (len_a, len_b) = (children_a.len(), children_b.len());
+                let mut i = 0;
+                while i < min(len_a, len_b) && ptr::eq(children_a.get(len_a - 1 - i), children_b.get(len_b - 1 - i)) {
+                    skipped += children_a.get(len_a - 1 - i).char_count_;
+                    i += 1;
+                }
+                if i == min(len_a, len_b) {
+                    break;
+                }
+                node_a = children_a.get(len_a - 1 - i);
+                node_b = children_b.get(len_b - 1 - i);
+            },
+            _ => break,
+        }
+    }
+
+    let iter_a = RevCharIter::new(a, a.char_count() - skipped);
+    let iter_b = RevCharIter::new(b, b.char_count() - skipped);
+    return skipped + iter_a.zip(iter_b).take_while(|&(c1, c2)| c1 == c2).count();
+}
+
+
+/// Iterates over the chars of a rope backwards, starting before the
+/// given char index.
+pub struct RevCharIter<'a> {
+    rope: &'a Rope,
+    pos: usize,
+    chunk: Rev<Chars<'a>>,
+}
+
+
+impl<'a> RevCharIter<'a> {
+    pub fn new(rope: &'a Rope, pos: usize) -> RevCharIter<'a> {
+        RevCharIter {
+            rope,
+            pos,
+            chunk: "".chars().rev(),
+        }
+    }
+}
+
+
+impl<'a> Iterator for RevCharIter<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        loop {
+            if let Some(c) = self.chunk.next() {
+                self.pos -= 1;
+                return Some(c);
+            }
+            if self.pos == 0 {
+                return None;
+            }
+
+            let (chunk_start, mut chunk_iter) = self.rope.chunk_iter_at_char_index(self.pos - 1);
+            let text = chunk_iter.next().unwrap();
+            self.chunk = text[..char_pos_to_byte_pos(text, self.pos - chunk_start)].chars().rev();
+        }
+    }
+}
+
+
+fn collect_lines(rope: &Rope, start_line: usize, end_line: usize) -> Vec<String> {
+    (start_line..end_line).map(|li| {
+        let chars = line_range_to_char_range(rope, &(li..(li + 1)));
+        rope.char_iter_between_indices(chars.start, chars.end).collect()
+    }).collect()
+}
+
+
+/// Converts a range of line indices to the range of chars of those
+/// lines, including their line endings.
+pub fn line_range_to_char_range(rope: &Rope, lines: &Range<usize>) -> Range<usize> {
+    let line_to_char = |li: usize| {
+        if li > rope.line_ending_count() { rope.char_count() } else { rope.line_index_to_char_index(li) }
+    };
+    line_to_char(lines.start)..line_to_char(lines.end)
+}
+
+
+/// Diffs two slices with Myers' algorithm, returning the steps of the
+/// edit script as (kind, old length, new length).  If the edit distance
+/// is larger than MAX_EDIT_COST the whole slices are replaced instead.
+pub fn diff_slices<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(DiffKind, usize, usize)> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max_d = min(a.len() + b.len(), MAX_EDIT_COST) as isize;
+
+    // The furthest x reached on each diagonal k = x - y, for each edit
+    // distance d.  The vector for d holds diagonals -(d + 1) to d + 1.
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    let mut v = vec![0isize; 3];
+    let mut found = None;
+
+    for d in 0..(max_d + 1) {
+        let mut next_v = vec![0isize; (2 * d + 3) as usize];
+        for k in (-d..(d + 1)).step_by(2) {
+            let get = |k: isize| v[(k + d) as usize];
+            let mut x = if k == -d || (k != d && get(k - 1) < get(k + 1)) { get(k + 1) } else { get(k - 1) + 1 };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            next_v[(k + d + 1) as usize] = x;
+            if x >= n && y >= m {
+                found = Some(d);
+                break;
+            }
+        }
+        trace.push(v);
+        v = next_v;
+        if found.is_some() {
+            break;
+        }
+    }
+
+    let d_final = match found {
+        Some(d) => d,
+        None => return vec![(DiffKind::Delete, a.len(), 0), (DiffKind::Insert, 0, b.len())],
+    };
+
+    // Walk back through the trace to recover the steps
+    let mut steps = Vec::new();
+    let mut x = n;
+    let mut y = m;
+    for d in (0..(d_final + 1)).rev() {
+        let v = &trace[d as usize];
+        let get = |k: isize| v[(k + d) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && get(k - 1) < get(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = if d == 0 { 0 } else { get(prev_k) };
+        let prev_y = if d == 0 { 0 } else { prev_x - prev_k };
+
+        let snake = min(x - prev_x, y - prev_y);
+        steps.push((DiffKind::Equal, snake as usize, snake as usize));
+        x -= snake;
+        y -= snake;
+
+        if d > 0 {
+            if x == prev_x {
+                steps.push((DiffKind::Insert, 0, 1));
+            }
+            else {
+                steps.push((DiffKind::Delete, 1, 0));
+            }
+            x = prev_x;
+            y = prev_y;
+        }
+    }
+
+    steps.reverse();
+    return steps;
+}
@@ -0,0 +1,387 @@
+//! A rope that notifies observers of its edits.
+
+use std::cmp::min;
+use std::fmt;
+use std::ops::Range;
+use block::BlockSelection;
+use diff::{common_prefix, common_suffix, RevCharIter};
+use indent::IndentStyle;
+use save::LineEnding;
+use selection::{Selection, SelectionSet};
+use super::Rope;
+
+
+/// A position in a rope's text, in the coordinates that the various
+/// consumers of edits want.  Lines are counted the same way as
+/// Rope::char_index_to_line_index() does.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TextPosition {
+    pub char: usize,
+    pub byte: usize,
+    pub line: usize,
+
+    /// The column, in chars from the start of the line.
+    pub col: usize,
+
+    /// The column, in bytes from the start of the line.
+    pub byte_col: usize,
+
+    /// The line counting only LF as a line break, as tree-sitter does.
+    pub lf_line: usize,
+
+    /// The column, in bytes since the last LF.
+    pub lf_byte_col: usize,
+}
+
+
+impl TextPosition {
+    /// Returns the position of a char index in a rope.
+    pub fn of_char_index(rope: &Rope, pos: usize) -> TextPosition {
+        let line = rope.char_index_to_line_index(pos);
+        let line_start = rope.line_index_to_char_index(line);
+        let byte = rope.char_index_to_byte_index(pos);
+        TextPosition {
+            char: pos,
+            byte,
+            line,
+            col: pos - line_start,
+            byte_col: byte - rope.char_index_to_byte_index(line_start),
+            lf_line: rope.lf_count_before_char_index(pos),
+            lf_byte_col: RevCharIter::new(rope, pos).take_while(|&c| c != '\n').map(char::len_utf8).sum(),
+        }
+    }
+}
+
+
+/// A change to the text of a document: the text between `start` and
+/// `old_end` was replaced by the text between `start` and `new_end`.
+/// `old_end` is a position in the text from before the edit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Edit {
+    pub start: TextPosition,
+    pub old_end: TextPosition,
+    pub new_end: TextPosition,
+}
+
+
+type Observer = Box<dyn FnMut(&Edit, &Rope)>;
+
+
+/// Identifies an observer of a document, for removing it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ObserverId(usize);
+
+
+/// A rope with observers that are called after each edit to it, with the
+/// edit and the edited rope.
+///
+/// The document's own editing methods notify the observers, including
+/// those that wrap the batch edits of the rope, like indenting lines or
+/// trimming whitespace, which are reported as a single edit each.  Edits
+/// made to the rope through other means aren't observed.
+pub struct Document {
+    rope: Rope,
+    observers: Vec<(ObserverId, Observer)>,
+    next_observer_id: usize,
+}
+
+
+impl Document {
+    pub fn new(rope: Rope) -> Document {
+        Document {
+            rope,
+            observers: Vec::new(),
+            next_observer_id: 0,
+        }
+    }
+
+
+    pub fn rope(&self) -> &Rope {
+        &self.rope
+    }
+
+
+    pub fn into_rope(self) -> Rope {
+        self.rope
+    }
+
+
+    /// Adds an observer.  Observers are called in the order they were
+    /// added.
+    pub fn add_observer<F: FnMut(&Edit, &Rope) + 'static>(&mut self, observer: F) -> ObserverId {
+        let id = ObserverId(self.next_observer_id);
+        self.next_observer_id += 1;
+        self.observers.push((id, Box::new(observer)));
+        return id;
+    }
+
+
+    /// Removes an observer, returning whether it was there.
+    pub fn remove_observer(&mut self, id: ObserverId) -> bool {
+        let count = self.observers.len();
+        self.observers.retain(|&(observer_id, _)| observer_id != id);
+        return self.observers.len() < count;
+    }
+
+
+    /// Inserts text at a char index, and notifies the observers.
+    pub fn insert_text_at_char_index(&mut self, text: &str, pos: usize) -> Edit {
+        let start = TextPosition::of_char_index(&self.rope, pos);
+        self.rope.insert_text_at_char_index(text, pos);
+        let new_end = TextPosition::of_char_index(&self.rope, pos + text.chars().count());
+
+        return self.notify(Edit {
+            start,
+            old_end: start,
+            new_end,
+        });
+    }
+
+
+    /// Removes the text between two char indices, and notifies the
+    /// observers.
+    pub fn remove_text_between_char_indices(&mut self, pos_a: usize, pos_b: usize) -> Edit {
+        let start = TextPosition::of_char_index(&self.rope, pos_a);
+        let old_end = TextPosition::of_char_index(&self.rope, pos_b);
+        self.rope.remove_text_between_char_indices(pos_a, pos_b);
+
+        return self.notify(Edit {
+            start,
+            old_end,
+            new_end: start,
+        });
+    }
+
+
+    /// Replaces the text between two char indices, and notifies the
+    /// observers of it as a single edit.
+    pub fn replace_text_between_char_indices(&mut self, pos_a: usize, pos_b: usize, text: &str) -> Edit {
+        let start = TextPosition::of_char_index(&self.rope, pos_a);
+        let old_end = TextPosition::of_char_index(&self.rope, pos_b);
+        self.rope.remove_text_between_char_indices(pos_a, pos_b);
+        self.rope.insert_text_at_char_index(text, pos_a);
+        let new_end = TextPosition::of_char_index(&self.rope, pos_a + text.chars().count());
+
+        return self.notify(Edit {
+            start,
+            old_end,
+            new_end,
+        });
+    }
+
+
+    /// Inserts text at the start column of a block selection, as with
+    /// Rope::insert_text_at_block(), and notifies the observers of it as
+    /// a single edit of the block's lines.
+    pub fn insert_text_at_block(&mut self, block: &BlockSelection, text: &str) -> Edit {
+        let (pos_a, pos_b) = self.line_span(&block.lines);
+        return self.edit_between_char_indices(pos_a, pos_b, |rope| rope.insert_text_at_block(block, text));
+    }
+
+
+    /// Removes the text of a block selection, as with Rope::remove_block(),
+    /// and notifies the observers of it as a single edit of the block's
+    /// lines.
+    pub fn remove_block(&mut self, block: &BlockSelection) -> Edit {
+        let (pos_a, pos_b) = self.line_span(&block.lines);
+        return self.edit_between_char_indices(pos_a, pos_b, |rope| rope.remove_block(block));
+    }
+
+
+    /// Indents a range of lines, as with Rope::indent_lines(), and
+    /// notifies the observers of it as a single edit of the lines.
+    pub fn indent_lines(&mut self, lines: Range<usize>, style: IndentStyle) -> Edit {
+        let (pos_a, pos_b) = self.line_span(&lines);
+        return self.edit_between_char_indices(pos_a, pos_b, |rope| rope.indent_lines(lines, style));
+    }
+
+
+    /// Dedents a range of lines, as with Rope::dedent_lines(), and
+    /// notifies the observers of it as a single edit of the lines.
+    pub fn dedent_lines(&mut self, lines: Range<usize>, style: IndentStyle) -> Edit {
+        let (pos_a, pos_b) = self.line_span(&lines);
+        return self.edit_between_char_indices(pos_a, pos_b, |rope| rope.dedent_lines(lines, style));
+    }
+
+
+    /// Maps the chars between two char indices, as with
+    /// Rope::map_chars_between_char_indices(), and notifies the observers.
+    pub fn map_chars_between_char_indices<F, I>(&mut self, pos_a: usize, pos_b: usize, f: F) -> Edit
+        where F: FnMut(char) -> I, I: IntoIterator<Item = char>
+    {
+        return self.edit_between_char_indices(pos_a, pos_b, |rope| { rope.map_chars_between_char_indices(pos_a, pos_b, f); });
+    }
+
+
+    /// Maps the graphemes between two char indices, as with
+    /// Rope::map_graphemes_between_char_indices(), and notifies the
+    /// observers.
+    pub fn map_graphemes_between_char_indices<F, S>(&mut self, pos_a: usize, pos_b: usize, f: F) -> Edit
+        where F: FnMut(&str) -> S, S: AsRef<str>
+    {
+        return self.edit_between_char_indices(pos_a, pos_b, |rope| { rope.map_graphemes_between_char_indices(pos_a, pos_b, f); });
+    }
+
+
+    /// Converts the text between two char indices to upper case, and notifies
+    /// the observers.
+    pub fn uppercase_between_char_indices(&mut self, pos_a: usize, pos_b: usize) -> Edit {
+        return self.edit_between_char_indices(pos_a, pos_b, |rope| { rope.uppercase_between_char_indices(pos_a, pos_b); });
+    }
+
+
+    /// Converts the text between two char indices to lower case, and notifies
+    /// the observers.
+    pub fn lowercase_between_char_indices(&mut self, pos_a: usize, pos_b: usize) -> Edit {
+        return self.edit_between_char_indices(pos_a, pos_b, |rope| { rope.lowercase_between_char_indices(pos_a, pos_b); });
+    }
+
+
+    /// Converts the text between two char indices to title case, and notifies
+    /// the observers.
+    pub fn titlecase_between_char_indices(&mut self, pos_a: usize, pos_b: usize) -> Edit {
+        return self.edit_between_char_indices(pos_a, pos_b, |rope| { rope.titlecase_between_char_indices(pos_a, pos_b); });
+    }
+
+
+    /// Converts the text between two char indices to Unicode Normalization
+    /// Form C, and notifies the observers.
+    #[cfg(feature = "unicode-normalization")]
+    pub fn nfc_between_char_indices(&mut self, pos_a: usize, pos_b: usize) -> Edit {
+        return self.edit_between_char_indices(pos_a, pos_b, |rope| { rope.nfc_between_char_indices(pos_a, pos_b); });
+    }
+
+
+    /// Converts the text between two char indices to Unicode Normalization
+    /// Form D, and notifies the observers.
+    #[cfg(feature = "unicode-normalization")]
+    pub fn nfd_between_char_indices(&mut self, pos_a: usize, pos_b: usize) -> Edit {
+        return self.edit_between_char_indices(pos_a, pos_b, |rope| { rope.nfd_between_char_indices(pos_a, pos_b); });
+    }
+
+
+    /// Adds a line ending to the end of the text if it doesn't end with
+    /// one already, and notifies the observers if it did.
+    pub fn ensure_trailing_newline(&mut self, line_ending: LineEnding) -> Option<Edit> {
+        let char_count = self.rope.char_count();
+        let mut rope = self.rope.clone();
+        rope.ensure_trailing_newline(line_ending);
+        if rope.char_count() == char_count {
+            return None;
+        }
+        return Some(self.edit_between_char_indices(char_count, char_count, |r| *r = rope));
+    }
+
+
+    /// Converts the indentation of every line from tabs to spaces, as
+    /// with Rope::indentation_tabs_to_spaces().  The observers are
+    /// notified of a single edit from the first change to the last, if
+    /// there were any.
+    pub fn indentation_tabs_to_spaces(&mut self, tab_width: usize) -> Option<Edit> {
+        return self.edit_and_diff(|rope| rope.indentation_tabs_to_spaces(tab_width));
+    }
+
+
+    /// Same as indentation_tabs_to_spaces(), but from spaces to tabs.
+    pub fn indentation_spaces_to_tabs(&mut self, tab_width: usize) -> Option<Edit> {
+        return self.edit_and_diff(|rope| rope.indentation_spaces_to_tabs(tab_width));
+    }
+
+
+    /// Removes trailing whitespace, as with
+    /// Rope::trim_trailing_whitespace().  The observers are notified of a
+    /// single edit from the first change to the last, if there were any.
+    pub fn trim_trailing_whitespace(&mut self) -> Option<Edit> {
+        return self.edit_and_diff(|rope| rope.trim_trailing_whitespace());
+    }
+
+
+    /// Collapses runs of blank lines, as with Rope::collapse_blank_lines().
+    /// The observers are notified of a single edit from the first change
+    /// to the last, if there were any.
+    pub fn collapse_blank_lines(&mut self, max: usize) -> Option<Edit> {
+        return self.edit_and_diff(|rope| rope.collapse_blank_lines(max));
+    }
+
+
+    /// Edits the text at every selection, as with SelectionSet::edit().
+    /// The observers are notified of a single edit from the first change
+    /// to the last, if there were any, after the selections have been
+    /// updated.
+    pub fn edit_selections<F>(&mut self, selections: &mut SelectionSet, f: F) -> Option<Edit>
+        where F: FnMut(&Rope, Selection) -> (Range<usize>, String)
+    {
+        return self.edit_and_diff(|rope| selections.edit(rope, f));
+    }
+
+
+    /// Makes an edit to the rope that only changes the text between two
+    /// char indices, and notifies the observers of it.
+    fn edit_between_char_indices<F: FnOnce(&mut Rope)>(&mut self, pos_a: usize, pos_b: usize, f: F) -> Edit {
+        let start = TextPosition::of_char_index(&self.rope, pos_a);
+        let old_end = TextPosition::of_char_index(&self.rope, pos_b);
+        let old_char_count = self.rope.char_count();
+        f(&mut self.rope);
+        let new_end = TextPosition::of_char_index(&self.rope, pos_b + self.rope.char_count() - old_char_count);
+
+        return self.notify(Edit {
+            start,
+            old_end,
+            new_end,
+        });
+    }
+
+
+    /// Makes an edit to the rope that may change text anywhere in it, and
+    /// notifies the observers of the range between the first and last
+    /// changed chars.  Finding that range takes time linear to the
+    /// unchanged text between the shared subtrees of the old and new
+    /// ropes.
+    fn edit_and_diff<F: FnOnce(&mut Rope)>(&mut self, f: F) -> Option<Edit> {
+        let old = self.rope.clone();
+        f(&mut self.rope);
+
+        let old_len = old.char_count();
+        let new_len = self.rope.char_count();
+        let prefix = common_prefix(&old, &self.rope);
+        if prefix == old_len && prefix == new_len {
+            return None;
+        }
+        let suffix = min(common_suffix(&old, &self.rope), min(old_len, new_len) - prefix);
+
+        let edit = Edit {
+            start: TextPosition::of_char_index(&old, prefix),
+            old_end: TextPosition::of_char_index(&old, old_len - suffix),
+            new_end: TextPosition::of_char_index(&self.rope, new_len - suffix),
+        };
+        return Some(self.notify(edit));
+    }
+
+
+    /// Returns the char range of a range of lines, including the line
+    /// ending of the last one.
+    fn line_span(&self, lines: &Range<usize>) -> (usize, usize) {
+        let line_to_char = |li: usize| {
+            if li > self.rope.line_ending_count() { self.rope.char_count() } else { self.rope.line_index_to_char_index(li) }
+        };
+        return (line_to_char(lines.start), line_to_char(lines.end));
+    }
+
+
+    fn notify(&mut self, edit: Edit) -> Edit {
+        for &mut (_, ref mut observer) in self.observers.iter_mut() {
+            observer(&edit, &self.rope);
+        }
+        return edit;
+    }
+}
+
+
+impl fmt::Debug for Document {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Document")
+            .field("rope", &self.rope)
+            .field("observer_count", &self.observers.len())
+            .finish()
+    }
+}
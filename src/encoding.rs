@@ -0,0 +1,521 @@
+//! Reading and writing ropes in text encodings other than UTF-8, and
+//! from bytes that aren't valid UTF-8.
+
+use std::char;
+use std::io;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::str;
+use string_utils::char_count;
+use super::{Rope, RopeConfig, READ_BLOCK_SIZE};
+
+
+/// The char that the byte 0x80 is escaped as by InvalidUtf8::Preserve.
+/// The bytes 0x80 to 0xFF are escaped as the private use chars from here
+/// to U+10FFFF.
+const ESCAPE_BASE: u32 = 0x10FF00;
+
+
+/// A text encoding that ropes can be read from and written in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+
+    /// UTF-16 with a byte order mark.  When reading, the byte order is
+    /// detected from the byte order mark, and is big endian without one.
+    /// When writing, a byte order mark is written followed by big endian
+    /// text.
+    Utf16,
+
+    /// UTF-16 in little endian byte order.  When reading, a little endian
+    /// byte order mark at the start is skipped.  When writing, no byte
+    /// order mark is written.
+    Utf16Le,
+
+    /// UTF-16 in big endian byte order.  When reading, a big endian byte
+    /// order mark at the start is skipped.  When writing, no byte order
+    /// mark is written.
+    Utf16Be,
+
+    /// ISO-8859-1, which maps each byte to the code point of the same
+    /// value.
+    Latin1,
+
+    /// Windows code page 1252.  The five bytes that it leaves undefined,
+    /// 0x81, 0x8D, 0x8F, 0x90 and 0x9D, are invalid, and the C1 control
+    /// chars of the same values can't be represented.
+    Windows1252,
+}
+
+
+/// What to do with invalid byte sequences when decoding text, and with
+/// chars that the encoding can't represent when encoding it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Replacement {
+    /// Substitute the given char.  When encoding, a replacement char that
+    /// can't be represented either is substituted with '?'.
+    Char(char),
+
+    /// Leave the invalid sequence or char out.
+    Skip,
+
+    /// Fail with an io::ErrorKind::InvalidData error.
+    Error,
+}
+
+
+/// How Rope::from_bytes() handles bytes that aren't valid UTF-8.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum InvalidUtf8 {
+    /// Replace each invalid sequence with U+FFFD.
+    #[default]
+    Replace,
+
+    /// Escape each invalid byte as one of the private use chars U+10FF80
+    /// to U+10FFFF, so that Rope::write_bytes_to() can write it back
+    /// as it was.  Any of those chars that are in the bytes as valid
+    /// UTF-8 are escaped byte by byte as well, so the escapes are never
+    /// ambiguous.
+    Preserve,
+}
+
+
+/// A sequence of bytes that wasn't valid UTF-8, as reported by
+/// Rope::from_bytes().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSequence {
+    /// The position of the sequence in the bytes.
+    pub byte_range: Range<usize>,
+    /// The char index in the rope where the sequence was replaced or
+    /// escaped.
+    pub char_index: usize,
+}
+
+
+impl Default for Replacement {
+    fn default() -> Replacement {
+        Replacement::Char('\u{FFFD}')
+    }
+}
+
+
+/// Windows-1252 chars for the bytes 0x80 to 0x9F, with None for the
+/// undefined bytes.  The rest of the bytes are the same as in Latin-1.
+const WINDOWS_1252_HIGH: [Option<char>; 32] = [
+    Some('\u{20AC}'), None, Some('\u{201A}'), Some('\u{0192}'), Some('\u{201E}'), Some('\u{2026}'), Some('\u{2020}'), Some('\u{2021}'),
+    Some('\u{02C6}'), Some('\u{2030}'), Some('\u{0160}'), Some('\u{2039}'), Some('\u{0152}'), None, Some('\u{017D}'), None,
+    None, Some('\u{2018}'), Some('\u{2019}'), Some('\u{201C}'), Some('\u{201D}'), Some('\u{2022}'), Some('\u{2013}'), Some('\u{2014}'),
+    Some('\u{02DC}'), Some('\u{2122}'), Some('\u{0161}'), Some('\u{203A}'), Some('\u{0153}'), None, Some('\u{017E}'), Some('\u{0178}'),
+];
+
+
+impl Rope {
+    /// Creates a new rope from a reader of text in the given encoding.
+    /// The text is decoded and built into the rope in blocks, like with
+    /// Rope::from_reader().
+    pub fn from_reader_with_encoding<R: Read>(reader: R, encoding: Encoding, replacement: Replacement) -> io::Result<Rope> {
+        Rope::from_reader_with_encoding_and_config(reader, encoding, replacement, RopeConfig::default())
+    }
+
+
+    /// Same as from_reader_with_encoding(), with the given config.
+    pub fn from_reader_with_encoding_and_config<R: Read>(reader: R, encoding: Encoding, replacement: Replacement, config: RopeConfig) -> io::Result<Rope> {
+        let mut reader = reader;
+        let mut rope = Rope::with_config(config);
+        let mut decoder = Decoder {
+            encoding,
+            replacement,
+            is_start: true,
+        };
+        let mut buffer: Vec<u8> = Vec::with_capacity(READ_BLOCK_SIZE);
+        let mut text = String::new();
+
+        loop {
+            let read_count = (&mut reader).take(READ_BLOCK_SIZE as u64).read_to_end(&mut buffer)?;
+            let is_end = read_count < READ_BLOCK_SIZE;
+
+            // A block may end in the middle of an encoded char, which is
+            // carried over to the next block
+            let decoded_len = decoder.decode(&buffer[..], is_end, &mut text)?;
+            buffer.drain(..decoded_len);
+
+            let end = rope.append_complete_graphemes(&text[..], is_end);
+            text.drain(..end);
+
+            if is_end {
+                return Ok(rope);
+            }
+        }
+    }
+
+
+    /// Creates a new rope from bytes that may not be valid UTF-8, along
+    /// with the positions of the invalid sequences in them.
+    pub fn from_bytes(bytes: &[u8], invalid_utf8: InvalidUtf8) -> (Rope, Vec<InvalidSequence>) {
+        Rope::from_bytes_with_config(bytes, invalid_utf8, RopeConfig::default())
+    }
+
+
+    /// Same as from_bytes(), with the given config.
+    pub fn from_bytes_with_config(bytes: &[u8], invalid_utf8: InvalidUtf8, config: RopeConfig) -> (Rope, Vec<InvalidSequence>) {
+        let mut text = String::with_capacity(bytes.len());
+        let mut invalid = Vec::new();
+        let mut char_i = 0;
+        let mut byte_i = 0;
+
+        while byte_i < bytes.len() {
+            let rest = &bytes[byte_i..];
+            let (valid_len, invalid_len) = match str::from_utf8(rest) {
+                Ok(_) => (rest.len(), 0),
+                Err(e) => (e.valid_up_to(), e.error_len().unwrap_or(rest.len() - e.valid_up_to())),
+            };
+            let valid = str::from_utf8(&rest[..valid_len]).unwrap();
+
+            // Valid chars in the escape range are escaped too, without
+            // being reported
+            if invalid_utf8 == InvalidUtf8::Preserve && valid.as_bytes().contains(&0xF4) {
+                for c in valid.chars() {
+                    if escaped_byte(c).is_some() {
+                        let mut buf = [0u8; 4];
+                        text.extend(c.encode_utf8(&mut buf).bytes().map(escape_byte));
+                        char_i += 4;
+                    }
+                    else {
+                        text.push(c);
+                        char_i += 1;
+                    }
+                }
+            }
+            else {
+                text.push_str(valid);
+                char_i += char_count(valid);
+            }
+            byte_i += valid_len;
+
+            if invalid_len > 0 {
+                invalid.push(InvalidSequence {
+                    byte_range: byte_i..(byte_i + invalid_len),
+                    char_index: char_i,
+                });
+                match invalid_utf8 {
+                    InvalidUtf8::Replace => {
+                        text.push('\u{FFFD}');
+                        char_i += 1;
+                    },
+                    InvalidUtf8::Preserve => {
+                        text.extend(bytes[byte_i..(byte_i + invalid_len)].iter().map(|&byte| escape_byte(byte)));
+                        char_i += invalid_len;
+                    },
+                }
+                byte_i += invalid_len;
+            }
+        }
+
+        return (Rope::from_str_with_config(&text[..], config), invalid);
+    }
+
+
+    /// Writes the rope's text to a writer as UTF-8, with the bytes that
+    /// were escaped by InvalidUtf8::Preserve written back as they were.
+    pub fn write_bytes_to<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = writer;
+        let mut buffer: Vec<u8> = Vec::new();
+
+        for chunk in self.chunk_iter() {
+            // Escapes are four bytes long, starting with 0xF4
+            if !chunk.as_bytes().contains(&0xF4) {
+                writer.write_all(chunk.as_bytes())?;
+                continue;
+            }
+
+            buffer.clear();
+            unescape_bytes(chunk, &mut buffer);
+            writer.write_all(&buffer[..])?;
+        }
+
+        return Ok(());
+    }
+
+
+    /// Makes a copy of the rope as bytes, with the bytes that were
+    /// escaped by InvalidUtf8::Preserve as they were.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_bytes_to(&mut bytes).unwrap();
+        return bytes;
+    }
+
+
+    /// Writes the rope's text to a writer in the given encoding.
+    pub fn write_to_with_encoding<W: Write>(&self, writer: W, encoding: Encoding, replacement: Replacement) -> io::Result<()> {
+        let mut writer = writer;
+        let mut buffer: Vec<u8> = Vec::new();
+
+        if encoding == Encoding::Utf16 {
+            writer.write_all(&[0xFE, 0xFF])?;
+        }
+
+        for chunk in self.chunk_iter() {
+            buffer.clear();
+            encode(chunk, encoding, replacement, &mut buffer)?;
+            writer.write_all(&buffer[..])?;
+        }
+
+        return Ok(());
+    }
+}
+
+
+/// Decodes blocks of text, keeping track of the byte order mark.
+struct Decoder {
+    encoding: Encoding,
+    replacement: Replacement,
+    is_start: bool,
+}
+
+
+impl Decoder {
+    /// Decodes as much of the given bytes as possible onto the end of the
+    /// given string.  Returns the number of bytes decoded, which is less
+    /// than all of them if the bytes end in the middle of an encoded char
+    /// and there are more to come.
+    fn decode(&mut self, bytes: &[u8], is_end: bool, text: &mut String) -> io::Result<usize> {
+        let mut start = 0;
+        let is_utf16 = matches!(self.encoding, Encoding::Utf16 | Encoding::Utf16Le | Encoding::Utf16Be);
+        if self.is_start && is_utf16 {
+            if bytes.len() < 2 && !is_end {
+                return Ok(0);
+            }
+            let bom = match bytes {
+                [0xFF, 0xFE, ..] => Some(Encoding::Utf16Le),
+                [0xFE, 0xFF, ..] => Some(Encoding::Utf16Be),
+                _ => None,
+            };
+
+            // A byte order mark is skipped if it's detected, or if it
+            // matches the explicit byte order
+            match (self.encoding, bom) {
+                (Encoding::Utf16, Some(encoding)) => {
+                    self.encoding = encoding;
+                    start = 2;
+                },
+                (Encoding::Utf16, None) => self.encoding = Encoding::Utf16Be,
+                (encoding, Some(bom)) if encoding == bom => start = 2,
+                _ => {},
+            }
+        }
+        self.is_start = false;
+
+        let decoded_len = match self.encoding {
+            Encoding::Utf8 => self.decode_utf8(&bytes[start..], is_end, text)?,
+            Encoding::Utf16 | Encoding::Utf16Be => self.decode_utf16(&bytes[start..], is_end, u16::from_be_bytes, text)?,
+            Encoding::Utf16Le => self.decode_utf16(&bytes[start..], is_end, u16::from_le_bytes, text)?,
+            Encoding::Latin1 => {
+                text.extend(bytes[start..].iter().map(|&byte| byte as char));
+                bytes.len() - start
+            },
+            Encoding::Windows1252 => {
+                for &byte in bytes[start..].iter() {
+                    match windows_1252_char(byte) {
+                        Some(c) => text.push(c),
+                        None => self.replace_invalid(text)?,
+                    }
+                }
+                bytes.len() - start
+            },
+        };
+
+        return Ok(start + decoded_len);
+    }
+
+
+    fn decode_utf8(&self, bytes: &[u8], is_end: bool, text: &mut String) -> io::Result<usize> {
+        let mut rest = bytes;
+        loop {
+            match str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    return Ok(bytes.len());
+                },
+
+                Err(e) => {
+                    let valid_len = e.valid_up_to();
+                    text.push_str(str::from_utf8(&rest[..valid_len]).unwrap());
+                    match e.error_len() {
+                        Some(invalid_len) => {
+                            self.replace_invalid(text)?;
+                            rest = &rest[(valid_len + invalid_len)..];
+                        },
+
+                        None if !is_end => {
+                            return Ok(bytes.len() - rest.len() + valid_len);
+                        },
+
+                        None => {
+                            self.replace_invalid(text)?;
+                            return Ok(bytes.len());
+                        },
+                    }
+                },
+            }
+        }
+    }
+
+
+    fn decode_utf16(&self, bytes: &[u8], is_end: bool, to_unit: fn([u8; 2]) -> u16, text: &mut String) -> io::Result<usize> {
+        let mut i = 0;
+        while (i + 2) <= bytes.len() {
+            let unit = to_unit([bytes[i], bytes[i + 1]]);
+
+            if !(0xD800..0xE000).contains(&unit) {
+                text.push(char::from_u32(unit as u32).unwrap());
+                i += 2;
+            }
+            else if unit < 0xDC00 {
+                // A high surrogate, which must be followed by a low one
+                if (i + 4) > bytes.len() {
+                    if !is_end {
+                        return Ok(i);
+                    }
+                    self.replace_invalid(text)?;
+                    i += 2;
+                    continue;
+                }
+                let unit2 = to_unit([bytes[i + 2], bytes[i + 3]]);
+                if (0xDC00..0xE000).contains(&unit2) {
+                    let code = 0x10000 + (((unit as u32) - 0xD800) << 10) + ((unit2 as u32) - 0xDC00);
+                    text.push(char::from_u32(code).unwrap());
+                    i += 4;
+                }
+                else {
+                    self.replace_invalid(text)?;
+                    i += 2;
+                }
+            }
+            else {
+                // A lone low surrogate
+                self.replace_invalid(text)?;
+                i += 2;
+            }
+        }
+
+        // An odd byte left over at the end
+        if i < bytes.len() {
+            if !is_end {
+                return Ok(i);
+            }
+            self.replace_invalid(text)?;
+        }
+
+        return Ok(bytes.len());
+    }
+
+
+    fn replace_invalid(&self, text: &mut String) -> io::Result<()> {
+        match self.replacement {
+            Replacement::Char(c) => text.push(c),
+            Replacement::Skip => {},
+            Replacement::Error => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Rope::from_reader_with_encoding(): stream contained an invalid byte sequence."));
+            },
+        }
+        return Ok(());
+    }
+}
+
+
+fn escape_byte(byte: u8) -> char {
+    char::from_u32(ESCAPE_BASE + (byte as u32)).unwrap()
+}
+
+
+/// Appends the UTF-8 bytes of some text to a buffer, with the escaped
+/// bytes of InvalidUtf8::Preserve written as they were.
+pub fn unescape_bytes(text: &str, buffer: &mut Vec<u8>) {
+    for c in text.chars() {
+        match escaped_byte(c) {
+            Some(byte) => buffer.push(byte),
+            None => {
+                let mut buf = [0u8; 4];
+                buffer.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            },
+        }
+    }
+}
+
+
+/// Returns the byte that the given char is an escape of, if any.
+fn escaped_byte(c: char) -> Option<u8> {
+    let code = c as u32;
+    if code >= (ESCAPE_BASE + 0x80) {
+        Some((code - ESCAPE_BASE) as u8)
+    }
+    else {
+        None
+    }
+}
+
+
+/// Returns the char that a byte decodes to in Windows-1252, or None if
+/// it's undefined.
+fn windows_1252_char(byte: u8) -> Option<char> {
+    if (0x80..0xA0).contains(&byte) {
+        WINDOWS_1252_HIGH[(byte - 0x80) as usize]
+    }
+    else {
+        Some(byte as char)
+    }
+}
+
+
+/// Returns the byte that encodes the given char in a single-byte
+/// encoding, if any.
+fn single_byte(c: char, encoding: Encoding) -> Option<u8> {
+    let code = c as u32;
+    match encoding {
+        Encoding::Latin1 if code < 0x100 => Some(code as u8),
+        Encoding::Windows1252 if !(0x80..0xA0).contains(&code) && code < 0x100 => Some(code as u8),
+        Encoding::Windows1252 => {
+            WINDOWS_1252_HIGH.iter().position(|&c2| c2 == Some(c)).map(|i| 0x80 + (i as u8))
+        },
+        _ => None,
+    }
+}
+
+
+/// Encodes text onto the end of the given buffer.
+fn encode(text: &str, encoding: Encoding, replacement: Replacement, buffer: &mut Vec<u8>) -> io::Result<()> {
+    match encoding {
+        Encoding::Utf8 => buffer.extend_from_slice(text.as_bytes()),
+
+        Encoding::Utf16 | Encoding::Utf16Be => {
+            for unit in text.encode_utf16() {
+                buffer.extend_from_slice(&unit.to_be_bytes());
+            }
+        },
+
+        Encoding::Utf16Le => {
+            for unit in text.encode_utf16() {
+                buffer.extend_from_slice(&unit.to_le_bytes());
+            }
+        },
+
+        Encoding::Latin1 | Encoding::Windows1252 => {
+            for c in text.chars() {
+                if let Some(byte) = single_byte(c, encoding) {
+                    buffer.push(byte);
+                    continue;
+                }
+                match replacement {
+                    Replacement::Char(c2) => buffer.push(single_byte(c2, encoding).unwrap_or(b'?')),
+                    Replacement::Skip => {},
+                    Replacement::Error => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Rope::write_to_with_encoding(): text contained a char that the encoding can't represent."));
+                    },
+                }
+            }
+        },
+    }
+
+    return Ok(());
+}
@@ -0,0 +1,139 @@
+//! Content hashes of text, for detecting changes to it.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+
+/// The modulus of the hash, the Mersenne prime 2^61 - 1.
+const MODULUS: u64 = (1 << 61) - 1;
+
+/// The base of the hash polynomial.
+const BASE: u64 = 0x1F3D_5B79_A2C4_E681 % MODULUS;
+
+
+/// A hash of a sequence of bytes.
+///
+/// The hash is a polynomial in the bytes, so the hash of two sequences
+/// joined together can be computed from their hashes alone, without
+/// looking at the bytes again.  It's meant for detecting changes, and
+/// isn't cryptographically secure.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ContentHash {
+    value: u64,
+    len: u64,
+}
+
+
+impl ContentHash {
+    /// The hash of no bytes.
+    pub fn new() -> ContentHash {
+        ContentHash {
+            value: 0,
+            len: 0,
+        }
+    }
+
+
+    pub fn of_bytes(bytes: &[u8]) -> ContentHash {
+        let mut hash = ContentHash::new();
+        hash.update(bytes);
+        return hash;
+    }
+
+
+    /// Hashes everything that can be read from a reader.
+    pub fn from_reader<R: Read>(reader: R) -> io::Result<ContentHash> {
+        let mut reader = reader;
+        let mut hash = ContentHash::new();
+        let mut buffer = vec![0u8; 1 << 16];
+
+        loop {
+            let read_count = match reader.read(&mut buffer[..]) {
+                Ok(0) => return Ok(hash),
+                Ok(read_count) => read_count,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            hash.update(&buffer[..read_count]);
+        }
+    }
+
+
+    /// Hashes the contents of a file.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<ContentHash> {
+        ContentHash::from_reader(File::open(path)?)
+    }
+
+
+    /// Returns the hash as a single number.
+    pub fn value(&self) -> u64 {
+        self.value ^ self.len.rotate_left(61)
+    }
+
+
+    /// Returns the number of bytes that were hashed.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+
+    /// Appends bytes to the hashed sequence.
+    pub fn update(&mut self, bytes: &[u8]) {
+        let mut value = self.value;
+        for &byte in bytes.iter() {
+            value = mul_mod(value, BASE) + (byte as u64) + 1;
+            if value >= MODULUS {
+                value -= MODULUS;
+            }
+        }
+        self.value = value;
+        self.len += bytes.len() as u64;
+    }
+
+
+    /// Returns the hash of the sequence followed by the one of another
+    /// hash.
+    pub fn concat(&self, other: &ContentHash) -> ContentHash {
+        let value = mul_mod(self.value, pow_mod(BASE, other.len)) + other.value;
+        ContentHash {
+            value: if value >= MODULUS { value - MODULUS } else { value },
+            len: self.len + other.len,
+        }
+    }
+}
+
+
+impl Default for ContentHash {
+    fn default() -> ContentHash {
+        ContentHash::new()
+    }
+}
+
+
+fn mul_mod(a: u64, b: u64) -> u64 {
+    let product = (a as u128) * (b as u128);
+    let value = ((product >> 61) as u64) + ((product as u64) & MODULUS);
+    if value >= MODULUS { value - MODULUS } else { value }
+}
+
+
+fn pow_mod(base: u64, exponent: u64) -> u64 {
+    let mut result = 1;
+    let mut base = base;
+    let mut exponent = exponent;
+    while exponent > 0 {
+        if (exponent & 1) == 1 {
+            result = mul_mod(result, base);
+        }
+        base = mul_mod(base, base);
+        exponent >>= 1;
+    }
+    return result;
+}
@@ -0,0 +1,215 @@
+//! Indentation analysis and reindenting.
+
+use std::ops::Range;
+use string_utils::is_line_ending;
+use super::{Rope, RopeSlice};
+
+
+/// How many lines from the start of the text detect_indent_style()
+/// looks at.
+const DETECT_SAMPLE_LINES: usize = 1000;
+
+
+/// The whitespace that one level of indentation is made of.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum IndentStyle {
+    Tabs,
+    Spaces(usize),
+}
+
+
+impl IndentStyle {
+    /// Returns the text of one level of indentation.
+    pub fn unit(&self) -> String {
+        match *self {
+            IndentStyle::Tabs => "\t".to_string(),
+            IndentStyle::Spaces(width) => " ".repeat(width),
+        }
+    }
+}
+
+
+impl Rope {
+    /// Detects the indentation style of the text from its first lines.
+    /// Tabs win if more lines start with a tab than with a space, and
+    /// otherwise the width is the most common change in the number of
+    /// leading spaces between indented lines.  Returns None if none of
+    /// the lines are indented.
+    pub fn detect_indent_style(&self) -> Option<IndentStyle> {
+        let mut tab_lines = 0;
+        let mut space_lines = 0;
+        let mut width_counts = [0usize; 9];
+        let mut prev_spaces = 0;
+
+        for line in self.line_iter().take(DETECT_SAMPLE_LINES) {
+            let indent: String = line.char_iter().take_while(|&c| c == ' ' || c == '\t').collect();
+            let is_blank = match line.char_iter().nth(indent.len()) {
+                Some(c) => is_line_ending(c.encode_utf8(&mut [0; 4])),
+                None => true,
+            };
+            if is_blank {
+                continue;
+            }
+
+            if indent.starts_with('\t') {
+                tab_lines += 1;
+                continue;
+            }
+            let spaces = indent.bytes().take_while(|&b| b == b' ').count();
+            if spaces > 0 {
+                space_lines += 1;
+            }
+            let change = spaces.abs_diff(prev_spaces);
+            if change < width_counts.len() {
+                width_counts[change] += 1;
+            }
+            prev_spaces = spaces;
+        }
+
+        if tab_lines == 0 && space_lines == 0 {
+            return None;
+        }
+        if tab_lines > space_lines {
+            return Some(IndentStyle::Tabs);
+        }
+
+        // Ties go to the narrower width
+        let mut width = 4;
+        let mut best = 0;
+        for (w, &count) in width_counts.iter().enumerate().skip(1) {
+            if count > best {
+                width = w;
+                best = count;
+            }
+        }
+        return Some(IndentStyle::Spaces(width));
+    }
+
+
+    /// Returns the indentation of a line: the spaces and tabs at its
+    /// start.
+    pub fn line_indentation<'a>(&'a self, li: usize) -> RopeSlice<'a> {
+        assert!(li <= self.line_ending_count(), "Rope::line_indentation(): attempted to index a line beyond the end of the text.");
+
+        let start = self.line_index_to_char_index(li);
+        let len = self.char_iter_at_index(start).take_while(|&c| c == ' ' || c == '\t').count();
+        return self.slice(start, start + len);
+    }
+
+
+    /// Adds a level of indentation to the start of each of a range of
+    /// lines.  Lines that are empty are left alone.
+    pub fn indent_lines(&mut self, lines: Range<usize>, style: IndentStyle) {
+        let unit = style.unit();
+        self.reindent_lines(lines, "Rope::indent_lines()", |indent, is_empty| {
+            if is_empty {
+                indent.to_string()
+            }
+            else {
+                unit.clone() + indent
+            }
+        });
+    }
+
+
+    /// Removes a level of indentation from the start of each of a range
+    /// of lines.  That's a leading tab, or with space indentation up to
+    /// its width of leading spaces.
+    pub fn dedent_lines(&mut self, lines: Range<usize>, style: IndentStyle) {
+        self.reindent_lines(lines, "Rope::dedent_lines()", |indent, _| {
+            if let Some(rest) = indent.strip_prefix('\t') {
+                return rest.to_string();
+            }
+            let width = match style {
+                IndentStyle::Tabs => 0,
+                IndentStyle::Spaces(width) => width,
+            };
+            let spaces = indent.bytes().take(width).take_while(|&b| b == b' ').count();
+            return indent[spaces..].to_string();
+        });
+    }
+
+
+    /// Converts the tabs in the indentation of every line to spaces, each
+    /// tab advancing to the next multiple of the tab width.  Tabs after
+    /// the indentation are left alone.
+    pub fn indentation_tabs_to_spaces(&mut self, tab_width: usize) {
+        assert!(tab_width > 0, "Rope::indentation_tabs_to_spaces(): tab_width must be greater than zero.");
+
+        let line_count = self.line_ending_count() + 1;
+        self.reindent_lines(0..line_count, "Rope::indentation_tabs_to_spaces()", |indent, _| {
+            " ".repeat(indentation_width(indent, tab_width))
+        });
+    }
+
+
+    /// Converts the indentation of every line to as many tabs as fit in
+    /// its width, followed by spaces for the rest.
+    pub fn indentation_spaces_to_tabs(&mut self, tab_width: usize) {
+        assert!(tab_width > 0, "Rope::indentation_spaces_to_tabs(): tab_width must be greater than zero.");
+
+        let line_count = self.line_ending_count() + 1;
+        self.reindent_lines(0..line_count, "Rope::indentation_spaces_to_tabs()", |indent, _| {
+            let width = indentation_width(indent, tab_width);
+            "\t".repeat(width / tab_width) + &" ".repeat(width % tab_width)
+        });
+    }
+
+
+    /// Replaces the indentation of each of a range of lines with what the
+    /// function returns for it.  The function is also told whether the
+    /// line is otherwise empty.  All of the lines are rebuilt in a single
+    /// pass and a single edit.
+    fn reindent_lines<F>(&mut self, lines: Range<usize>, function: &str, f: F)
+        where F: FnMut(&str, bool) -> String
+    {
+        assert!(lines.start <= lines.end, "{}: the line range must not be reversed.", function);
+        assert!(lines.end <= self.line_ending_count() + 1, "{}: attempted to reindent lines beyond the end of the text.", function);
+
+        if lines.start == lines.end {
+            return;
+        }
+        let mut f = f;
+        let start = self.line_index_to_char_index(lines.start);
+        let end = if lines.end > self.line_ending_count() { self.char_count() } else { self.line_index_to_char_index(lines.end) };
+
+        let mut text = String::new();
+        let mut indent = String::new();
+        let mut in_indent = true;
+        let mut changed = false;
+        for g in self.grapheme_iter_at_char_index(start).take(self.grapheme_count_in_char_range(start, end)) {
+            if in_indent && (g == " " || g == "\t") {
+                indent.push_str(g);
+                continue;
+            }
+            if in_indent {
+                let new_indent = f(&indent[..], is_line_ending(g));
+                changed |= new_indent != indent;
+                text.push_str(&new_indent[..]);
+                indent.clear();
+            }
+            text.push_str(g);
+            in_indent = is_line_ending(g);
+        }
+        // The last line of the text has no line ending to end it
+        if in_indent && lines.end > self.line_ending_count() {
+            let new_indent = f(&indent[..], true);
+            changed |= new_indent != indent;
+            text.push_str(&new_indent[..]);
+        }
+
+        if changed {
+            self.rebuild_between_char_indices(start, end, &text[..]);
+        }
+    }
+}
+
+
+/// Returns the width of some indentation in columns.
+fn indentation_width(indent: &str, tab_width: usize) -> usize {
+    let mut width = 0;
+    for c in indent.chars() {
+        width = if c == '\t' { (width / tab_width + 1) * tab_width } else { width + 1 };
+    }
+    return width;
+}
@@ -0,0 +1,402 @@
+//! The text storage of leaf nodes.
+//!
+//! Leaves either own their text in a String, or reference a span of a
+//! shared append-only buffer, as in a piece table.  Spans are cheap to
+//! clone and split, and appending to a span that ends where its buffer
+//! does only copies the new text.  Buffers are never written to below
+//! their current length, so the text a span references never changes,
+//! and a buffer is freed once no span references it anymore.
+
+use std::cell::RefCell;
+use std::cmp::max;
+use std::mem;
+use std::ops::Deref;
+use std::ptr;
+use std::slice;
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use string_utils::{
+    char_pos_to_byte_pos,
+    insert_text_at_char_index,
+    remove_text_between_char_indices,
+    split_string_at_char_index,
+};
+use super::{LeafStorage, RopeConfig};
+
+
+/// The capacity of the append buffers that spans are written to, in
+/// bytes.
+const BUFFER_SIZE: usize = 1 << 16;
+
+
+thread_local! {
+    /// The buffer that new text is appended to when a span can't be
+    /// written to its own buffer.  Each thread has its own, so that
+    /// unrelated edits don't contend for it.
+    static CURRENT_BUFFER: RefCell<Arc<AppendBuffer>> = RefCell::new(AppendBuffer::with_capacity(0));
+}
+
+
+#[derive(Debug)]
+pub enum LeafText {
+    Owned(String),
+    Span(Span),
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Span {
+    buffer: Arc<AppendBuffer>,
+    start: usize,
+    end: usize,
+}
+
+
+impl LeafText {
+    /// Creates empty leaf text with the given config's storage.
+    pub fn new(config: RopeConfig) -> LeafText {
+        match config.leaf_storage() {
+            LeafStorage::Owned => LeafText::Owned(String::new()),
+            LeafStorage::PieceTable => LeafText::from_str("", config),
+        }
+    }
+
+
+    /// Creates leaf text from a string slice, with the given config's
+    /// storage.  Owned text gets a buffer with the full leaf capacity.
+    pub fn from_str(text: &str, config: RopeConfig) -> LeafText {
+        match config.leaf_storage() {
+            LeafStorage::Owned => {
+                let mut s = String::with_capacity(max(config.max_leaf_size(), text.len()));
+                s.push_str(text);
+                LeafText::Owned(s)
+            },
+
+            LeafStorage::PieceTable => LeafText::Span(Span::from_parts(&[text], None)),
+        }
+    }
+
+
+    /// Returns whether the text is stored in a shared buffer.
+    pub fn is_span(&self) -> bool {
+        matches!(*self, LeafText::Span(_))
+    }
+
+
+    /// Returns the size of the heap allocation that is owned by this
+    /// leaf, in bytes.  Spans don't own their buffers, and count as just
+    /// the text they reference.
+    pub fn capacity(&self) -> usize {
+        match *self {
+            LeafText::Owned(ref s) => s.capacity(),
+            LeafText::Span(ref span) => span.end - span.start,
+        }
+    }
+
+
+    /// Returns an id of the shared buffer that a span references, along
+    /// with the buffer's capacity in bytes, or None for owned text.
+    pub fn span_buffer(&self) -> Option<(usize, usize)> {
+        match *self {
+            LeafText::Owned(_) => None,
+            LeafText::Span(ref span) => Some((Arc::as_ptr(&span.buffer) as usize, span.buffer.capacity)),
+        }
+    }
+
+
+    /// Makes sure that owned text has at least the given capacity.
+    pub fn reserve_capacity(&mut self, capacity: usize) {
+        if let LeafText::Owned(ref mut s) = *self {
+            if s.capacity() < capacity {
+                let mut buffer = String::with_capacity(capacity);
+                buffer.push_str(&s[..]);
+                *s = buffer;
+            }
+        }
+    }
+
+
+    /// Returns the text between the given byte indices as new leaf text.
+    /// This doesn't copy spans.
+    pub fn slice(&self, byte_a: usize, byte_b: usize) -> LeafText {
+        assert!(byte_a <= byte_b && byte_b <= self.len() && self.is_char_boundary(byte_a) && self.is_char_boundary(byte_b), "LeafText::slice(): invalid byte range.");
+
+        match *self {
+            LeafText::Owned(ref s) => LeafText::Owned(s[byte_a..byte_b].to_string()),
+            LeafText::Span(ref span) => {
+                LeafText::Span(Span {
+                    buffer: span.buffer.clone(),
+                    start: span.start + byte_a,
+                    end: span.start + byte_b,
+                })
+            },
+        }
+    }
+
+
+    /// Replaces the text with the given string slice.
+    pub fn set(&mut self, text: &str) {
+        match *self {
+            LeafText::Owned(ref mut s) => {
+                s.clear();
+                s.push_str(text);
+            },
+
+            LeafText::Span(ref mut span) => span.rewrite(&[text]),
+        }
+    }
+
+
+    pub fn push_str(&mut self, text: &str) {
+        match *self {
+            LeafText::Owned(ref mut s) => s.push_str(text),
+            LeafText::Span(ref mut span) => {
+                if !span.extend(text) {
+                    let old = span.clone();
+                    span.rewrite(&[old.as_str(), text]);
+                }
+            },
+        }
+    }
+
+
+    /// Appends other leaf text.  A span that directly follows this one
+    /// in the same buffer is joined without copying.
+    pub fn append(&mut self, other: &LeafText) {
+        if let (&mut LeafText::Span(ref mut span), LeafText::Span(other_span)) = (&mut *self, other) {
+            if Arc::ptr_eq(&span.buffer, &other_span.buffer) && span.end == other_span.start {
+                span.end = other_span.end;
+                return;
+            }
+        }
+
+        self.push_str(other);
+    }
+
+
+    pub fn insert_at_char_index(&mut self, text: &str, pos: usize) {
+        match *self {
+            LeafText::Owned(ref mut s) => insert_text_at_char_index(s, text, pos),
+            LeafText::Span(ref mut span) => {
+                let byte_pos = char_pos_to_byte_pos(span.as_str(), pos);
+                if byte_pos == (span.end - span.start) && span.extend(text) {
+                    return;
+                }
+                let old = span.clone();
+                let old_text = old.as_str();
+                span.rewrite(&[&old_text[..byte_pos], text, &old_text[byte_pos..]]);
+            },
+        }
+    }
+
+
+    pub fn remove_between_char_indices(&mut self, pos_a: usize, pos_b: usize) {
+        match *self {
+            LeafText::Owned(ref mut s) => remove_text_between_char_indices(s, pos_a, pos_b),
+            LeafText::Span(ref mut span) => {
+                assert!(pos_a <= pos_b, "LeafText::remove_between_char_indices(): pos_a must be less than or equal to pos_b.");
+                let byte_a = char_pos_to_byte_pos(span.as_str(), pos_a);
+                let byte_b = char_pos_to_byte_pos(span.as_str(), pos_b);
+
+                // Removing from either end just narrows the span
+                if byte_b == (span.end - span.start) {
+                    span.end = span.start + byte_a;
+                }
+                else if byte_a == 0 {
+                    span.start += byte_b;
+                }
+                else {
+                    let old = span.clone();
+                    let old_text = old.as_str();
+                    span.rewrite(&[&old_text[..byte_a], &old_text[byte_b..]]);
+                }
+            },
+        }
+    }
+
+
+    /// Splits the text at the given char index, keeping the first part
+    /// and returning the second.  Spans are split without copying.
+    pub fn split_off_at_char_index(&mut self, pos: usize) -> LeafText {
+        match *self {
+            LeafText::Owned(ref mut s) => LeafText::Owned(split_string_at_char_index(s, pos)),
+            LeafText::Span(ref mut span) => {
+                let split = span.start + char_pos_to_byte_pos(span.as_str(), pos);
+                let right = Span {
+                    buffer: span.buffer.clone(),
+                    start: split,
+                    end: span.end,
+                };
+                span.end = split;
+                LeafText::Span(right)
+            },
+        }
+    }
+}
+
+
+impl Clone for LeafText {
+    fn clone(&self) -> LeafText {
+        match *self {
+            // Keep the full capacity of the text buffer, so that the copy
+            // can still be edited without reallocating
+            LeafText::Owned(ref s) => {
+                let mut new_s = String::with_capacity(s.capacity());
+                new_s.push_str(s);
+                LeafText::Owned(new_s)
+            },
+
+            LeafText::Span(ref span) => LeafText::Span(span.clone()),
+        }
+    }
+}
+
+
+impl Deref for LeafText {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match *self {
+            LeafText::Owned(ref s) => &s[..],
+            LeafText::Span(ref span) => span.as_str(),
+        }
+    }
+}
+
+
+impl Span {
+    /// Writes the concatenation of the given string slices to a buffer,
+    /// and returns a span of it.  The given buffer is tried first, then
+    /// the current buffer of the thread.  Text too large to share a
+    /// buffer with much else gets a buffer of its own.
+    fn from_parts(parts: &[&str], buffer: Option<&Arc<AppendBuffer>>) -> Span {
+        let len = parts.iter().map(|part| part.len()).sum();
+
+        if let Some(buffer) = buffer {
+            if let Some(start) = buffer.push(parts) {
+                return Span { buffer: buffer.clone(), start, end: start + len };
+            }
+        }
+
+        if len > (BUFFER_SIZE / 4) {
+            let buffer = AppendBuffer::with_capacity(len);
+            let start = buffer.push(parts).unwrap();
+            return Span { buffer, start, end: start + len };
+        }
+
+        CURRENT_BUFFER.with(|current| {
+            let mut current = current.borrow_mut();
+            let start = match current.push(parts) {
+                Some(start) => start,
+                None => {
+                    *current = AppendBuffer::with_capacity(BUFFER_SIZE);
+                    current.push(parts).unwrap()
+                },
+            };
+            Span { buffer: current.clone(), start, end: start + len }
+        })
+    }
+
+
+    fn as_str(&self) -> &str {
+        // Safe because the span was written before it was created, and
+        // buffers are never written to below their length
+        unsafe { self.buffer.slice(self.start, self.end) }
+    }
+
+
+    /// Appends text in place, if nothing has been written to the buffer
+    /// after this span yet.  Returns whether it succeeded.
+    fn extend(&mut self, text: &str) -> bool {
+        if self.buffer.push_at(self.end, text) {
+            self.end += text.len();
+            return true;
+        }
+        return false;
+    }
+
+
+    /// Replaces the span with a new one of the concatenation of the given
+    /// string slices.
+    fn rewrite(&mut self, parts: &[&str]) {
+        let new_span = Span::from_parts(parts, Some(&self.buffer));
+        *self = new_span;
+    }
+}
+
+
+/// A fixed-capacity byte buffer that can only be appended to.  Appends
+/// reserve their region of the buffer atomically, so a buffer can be
+/// shared between threads, and nothing ever writes to a region that has
+/// already been reserved.
+#[derive(Debug)]
+struct AppendBuffer {
+    ptr: *mut u8,
+    capacity: usize,
+    len: AtomicUsize,
+}
+
+unsafe impl Send for AppendBuffer {}
+unsafe impl Sync for AppendBuffer {}
+
+
+impl AppendBuffer {
+    fn with_capacity(capacity: usize) -> Arc<AppendBuffer> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(capacity);
+        let buffer = AppendBuffer {
+            ptr: bytes.as_mut_ptr(),
+            capacity: bytes.capacity(),
+            len: AtomicUsize::new(0),
+        };
+        mem::forget(bytes);
+        return Arc::new(buffer);
+    }
+
+
+    /// Appends the concatenation of the given string slices if it fits,
+    /// returning the byte index it was written at.
+    fn push(&self, parts: &[&str]) -> Option<usize> {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        let start = self.len.fetch_update(Ordering::AcqRel, Ordering::Acquire, |old_len| {
+            if len <= (self.capacity - old_len) { Some(old_len + len) } else { None }
+        }).ok()?;
+
+        let mut i = start;
+        for part in parts.iter() {
+            unsafe { ptr::copy_nonoverlapping(part.as_ptr(), self.ptr.add(i), part.len()) };
+            i += part.len();
+        }
+        return Some(start);
+    }
+
+
+    /// Appends text at the given byte index if that's where the buffer
+    /// currently ends and it fits.  Returns whether it succeeded.
+    fn push_at(&self, pos: usize, text: &str) -> bool {
+        if text.len() > (self.capacity - pos) {
+            return false;
+        }
+        if self.len.compare_exchange(pos, pos + text.len(), Ordering::AcqRel, Ordering::Acquire).is_err() {
+            return false;
+        }
+
+        unsafe { ptr::copy_nonoverlapping(text.as_ptr(), self.ptr.add(pos), text.len()) };
+        return true;
+    }
+
+
+    /// Returns the text between the given byte indices.  The range must
+    /// have been written as valid UTF-8 by this thread, or before the
+    /// span referencing it was passed to this thread.
+    unsafe fn slice(&self, start: usize, end: usize) -> &str {
+        str::from_utf8_unchecked(slice::from_raw_parts(self.ptr.add(start), end - start))
+    }
+}
+
+
+impl Drop for AppendBuffer {
+    fn drop(&mut self) {
+        unsafe { drop(Vec::from_raw_parts(self.ptr, 0, self.capacity)) };
+    }
+}
@@ -1,169 +1,503 @@
 #![allow(dead_code)]
+#![allow(clippy::needless_return)]
 //#![feature(test)]
 //#![feature(unicode)]
 
 //extern crate test;
 extern crate unicode_segmentation;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "memmap2")]
+extern crate memmap2;
+#[cfg(feature = "tree-sitter")]
+extern crate tree_sitter;
+#[cfg(feature = "unicode-normalization")]
+extern crate unicode_normalization;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
 mod string_utils;
+mod node_children;
+mod leaf_text;
+#[cfg(feature = "rayon")]
+mod parallel;
+#[cfg(feature = "serde")]
+mod serde_impls;
+#[cfg(feature = "memmap2")]
+mod mapped;
+mod encoding;
+mod hash;
+mod save;
+mod diff;
+mod merge;
+mod crdt;
+mod document;
+mod selection;
+mod block;
+mod brackets;
+mod indent;
+mod whitespace;
+mod transform;
+#[cfg(feature = "tree-sitter")]
+mod tree_sitter_input;
 mod tests;
 mod benches;
 
 use std::cmp::{min, max};
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::Read;
 use std::mem;
+use std::str;
+use std::ops::Deref;
+use std::sync::{Arc, OnceLock};
 use std::str::Chars;
 use unicode_segmentation::{UnicodeSegmentation, Graphemes};
+use node_children::NodeChildren;
+use leaf_text::LeafText;
+use brackets::BracketSummary;
+pub use encoding::{Encoding, Replacement, InvalidUtf8, InvalidSequence};
+pub use hash::ContentHash;
+pub use save::{SaveOptions, LineEnding};
+pub use diff::{RopeDiff, DiffOp, DiffKind};
+pub use merge::{MergeOptions, RopeMerge};
+pub use crdt::{CrdtText, CrdtOp, OpId};
+pub use document::{Document, Edit, TextPosition, ObserverId};
+pub use selection::{Selection, SelectionSet};
+pub use block::BlockSelection;
+pub use brackets::DEFAULT_BRACKET_PAIRS;
+pub use indent::IndentStyle;
+#[cfg(feature = "serde")]
+pub use serde_impls::chunks as serde_chunks;
+#[cfg(feature = "memmap2")]
+pub use mapped::{MappedRope, MappedChunkIter};
 use string_utils::{
     char_count,
     char_grapheme_line_ending_count,
-    grapheme_count_is_less_than,
     graphemes_are_mergeable,
     char_pos_to_byte_pos,
     char_pos_to_grapheme_pos,
     grapheme_pos_to_char_pos,
     insert_text_at_char_index,
-    remove_text_between_char_indices,
-    split_string_at_char_index,
-    split_string_at_grapheme_index,
+    nearest_grapheme_boundary,
+    prev_grapheme_boundary,
+    next_grapheme_boundary,
     is_line_ending,
 };
 
 
-pub const MIN_NODE_SIZE: usize = 64;
+/// Default minimum size of a leaf node's text, in bytes.
+pub const MIN_NODE_SIZE: usize = 512;
+/// Default maximum size of a leaf node's text, in bytes.
 pub const MAX_NODE_SIZE: usize = MIN_NODE_SIZE * 2;
+/// The largest maximum leaf size a RopeConfig may specify, in bytes.
+pub const MAX_NODE_SIZE_LIMIT: usize = 1 << 16;
+
+/// Size of the blocks that Rope::from_reader() reads its input in, in
+/// bytes.
+const READ_BLOCK_SIZE: usize = 1 << 22;
+
+pub const MAX_CHILDREN: usize = 16;
+pub const MIN_CHILDREN: usize = MAX_CHILDREN / 2;
+
+
+/// Configures the size limits and text storage of a rope's leaf nodes.
+///
+/// Sizes are measured in bytes of UTF-8 text.  A leaf never grows beyond
+/// max_leaf_size, unless it holds a single grapheme that is itself larger
+/// than that.  Owned leaf text buffers are allocated with max_leaf_size
+/// capacity up front, so editing a leaf never reallocates it.
+///
+/// With hash caching, every node keeps the content hash of its text once
+/// it has been computed, so that Rope::content_hash() only has to rehash
+/// the nodes that were edited since.  Bracket caching does the same for
+/// the depths of the brackets in each node's text, which lets
+/// Rope::matching_bracket() skip over whole nodes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RopeConfig {
+    min_leaf_size: usize,
+    max_leaf_size: usize,
+    leaf_storage: LeafStorage,
+    cache_hashes: bool,
+    cache_brackets: bool,
+}
+
+
+/// How the text of a rope's leaf nodes is stored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum LeafStorage {
+    /// Every leaf owns its text in a buffer of its own.
+    #[default]
+    Owned,
+
+    /// Leaves reference spans of shared, append-only text buffers, as in
+    /// a piece table.  Splitting a leaf doesn't allocate, and typing at
+    /// the end of a leaf only copies the new text.  Other edits write the
+    /// leaf's new text to the end of a buffer.  Buffers hold up to 64 KB
+    /// of text, and are freed once no leaf references them.  A leaf keeps
+    /// its whole buffer alive, though, so after heavy editing the buffers
+    /// can hold much more than the rope's text, up to a buffer per leaf.
+    /// RopeStats::span_buffer_bytes tells how much, and Rope::compact()
+    /// copies the text into new buffers.
+    PieceTable,
+}
+
+
+impl RopeConfig {
+    /// Creates a new config with the given minimum and maximum leaf
+    /// sizes, in bytes.  max_leaf_size must be at least twice
+    /// min_leaf_size, and no larger than MAX_NODE_SIZE_LIMIT.
+    pub fn new(min_leaf_size: usize, max_leaf_size: usize) -> RopeConfig {
+        assert!(min_leaf_size > 0, "RopeConfig::new(): min_leaf_size must be greater than zero.");
+        assert!(max_leaf_size >= (min_leaf_size * 2), "RopeConfig::new(): max_leaf_size must be at least twice min_leaf_size.");
+        assert!(max_leaf_size <= MAX_NODE_SIZE_LIMIT, "RopeConfig::new(): max_leaf_size must not be larger than MAX_NODE_SIZE_LIMIT.");
+
+        RopeConfig {
+            min_leaf_size,
+            max_leaf_size,
+            leaf_storage: LeafStorage::Owned,
+            cache_hashes: false,
+            cache_brackets: false,
+        }
+    }
+
+    /// Returns a copy of the config with the given leaf storage.
+    pub fn with_leaf_storage(self, leaf_storage: LeafStorage) -> RopeConfig {
+        RopeConfig {
+            leaf_storage,
+            ..self
+        }
+    }
+
+    pub fn min_leaf_size(&self) -> usize {
+        self.min_leaf_size
+    }
+
+    pub fn max_leaf_size(&self) -> usize {
+        self.max_leaf_size
+    }
+
+    pub fn leaf_storage(&self) -> LeafStorage {
+        self.leaf_storage
+    }
+
+    /// Returns a copy of the config with hash caching turned on or off.
+    pub fn with_hash_caching(self, cache_hashes: bool) -> RopeConfig {
+        RopeConfig {
+            cache_hashes,
+            ..self
+        }
+    }
+
+    pub fn hash_caching(&self) -> bool {
+        self.cache_hashes
+    }
+
+    /// Returns a copy of the config with bracket caching turned on or off.
+    pub fn with_bracket_caching(self, cache_brackets: bool) -> RopeConfig {
+        RopeConfig {
+            cache_brackets,
+            ..self
+        }
+    }
+
+    pub fn bracket_caching(&self) -> bool {
+        self.cache_brackets
+    }
+}
+
+
+impl Default for RopeConfig {
+    fn default() -> RopeConfig {
+        RopeConfig::new(MIN_NODE_SIZE, MAX_NODE_SIZE)
+    }
+}
+
+
+/// Statistics about a rope's tree structure and memory usage, as
+/// returned by Rope::stats().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RopeStats {
+    /// Number of leaf nodes.
+    pub leaf_count: usize,
+    /// Number of branch nodes.
+    pub branch_count: usize,
+    /// Height of the tree, counting the leaves as height 1.
+    pub tree_height: u32,
+    /// Size of the text, in bytes.
+    pub text_bytes: usize,
+    /// Total heap memory used by the rope, in bytes: the nodes below the
+    /// root plus the leaves' text buffers.  Nodes that are shared with
+    /// clones or snapshots of the rope are counted in full.  Leaves with
+    /// piece table storage count just the text they reference.
+    pub heap_bytes: usize,
+    /// Unused capacity in the leaves' text buffers, in bytes.
+    pub wasted_leaf_bytes: usize,
+    /// Total capacity of the shared buffers that leaves with piece table
+    /// storage reference, in bytes.  Each buffer is counted once.
+    pub span_buffer_bytes: usize,
+    /// Number of leaves by text size.  Index 0 counts empty leaves, and
+    /// index i counts leaves of at least 2^(i-1) and less than 2^i bytes.
+    pub leaf_size_histogram: Vec<usize>,
+}
 
 
 /// A rope data structure for storing text in a format that is efficient
 /// for insertion and removal even for extremely large strings.
-#[derive(Debug)]
+///
+/// Internally this is a B-tree: branch nodes hold between MIN_CHILDREN
+/// and MAX_CHILDREN children, and all leaf nodes are at the same depth.
+/// Child nodes are shared between clones of a rope and copied on write,
+/// so cloning a rope is cheap.
+#[derive(Debug, Clone)]
 pub struct Rope {
     data: RopeData,
     char_count_: usize,
     grapheme_count_: usize,
     line_ending_count_: usize,
+    byte_count_: usize,
+    leaf_count_: usize,
     tree_height: u32,
+    content_hash_: OnceLock<ContentHash>,
+    bracket_summary_: OnceLock<Box<BracketSummary>>,
+    lf_count_: OnceLock<usize>,
+    config: RopeConfig,
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum RopeData {
-    Leaf(String),
-    Branch(Box<Rope>, Box<Rope>),
+    Leaf(LeafText),
+    Branch(NodeChildren),
+}
+
+
+impl Default for Rope {
+    fn default() -> Rope {
+        Rope::new()
+    }
+}
+
+
+/// Ropes are equal if their text is, regardless of their configs and
+/// tree structures.
+impl PartialEq for Rope {
+    fn eq(&self, other: &Rope) -> bool {
+        if self.byte_count_ != other.byte_count_ {
+            return false;
+        }
+        if self.config.cache_hashes && other.config.cache_hashes && self.content_hash() != other.content_hash() {
+            return false;
+        }
+
+        // Compare the chunks of the two ropes, which are usually split
+        // at different places
+        let mut other_iter = other.chunk_iter();
+        let mut other_chunk: &[u8] = &[];
+        for chunk in self.chunk_iter() {
+            let mut chunk = chunk.as_bytes();
+            while !chunk.is_empty() {
+                if other_chunk.is_empty() {
+                    other_chunk = other_iter.next().unwrap().as_bytes();
+                    continue;
+                }
+                let len = min(chunk.len(), other_chunk.len());
+                if chunk[..len] != other_chunk[..len] {
+                    return false;
+                }
+                chunk = &chunk[len..];
+                other_chunk = &other_chunk[len..];
+            }
+        }
+
+        return true;
+    }
+}
+
+
+impl Eq for Rope {}
+
+
+impl Hash for Rope {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.content_hash().hash(state);
+    }
 }
 
 
 impl Rope {
     /// Creates a new empty rope
     pub fn new() -> Rope {
+        Rope::with_config(RopeConfig::default())
+    }
+
+
+    /// Creates a new empty rope with the given leaf size configuration
+    pub fn with_config(config: RopeConfig) -> Rope {
         Rope {
-            data: RopeData::Leaf(String::new()),
+            data: RopeData::Leaf(LeafText::new(config)),
             char_count_: 0,
             grapheme_count_: 0,
             line_ending_count_: 0,
+            byte_count_: 0,
+            leaf_count_: 1,
             tree_height: 1,
+            content_hash_: OnceLock::new(),
+            bracket_summary_: OnceLock::new(),
+            lf_count_: OnceLock::new(),
+            config,
         }
     }
-    
 
-    /// Creates a new rope from a string slice    
+
+    /// Creates a new rope from a string slice
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Rope {
-        let mut rope_stack: Vec<Rope> = Vec::new();
-        
-        let mut s1 = s;
+        Rope::from_str_with_config(s, RopeConfig::default())
+    }
+
+
+    /// Creates a new rope from a string slice, with the given leaf size
+    /// configuration
+    pub fn from_str_with_config(s: &str, config: RopeConfig) -> Rope {
+        // Large texts are built in parallel pieces
+        #[cfg(feature = "rayon")]
+        {
+            if s.len() >= parallel::MIN_PARALLEL_SIZE {
+                return parallel::from_str(s, config);
+            }
+        }
+
+        return Rope::build_from_str(s, config);
+    }
+
+    /// Creates a new rope from a string, consuming the string
+    pub fn from_string(s: String) -> Rope {
+        // TODO: special case short strings?
+        Rope::from_str(&s[..])
+    }
+
+
+    /// Creates a new rope from a reader of UTF-8 text.  The text is read
+    /// and built into the rope in blocks, so it never has to be held in
+    /// memory in full a second time.
+    pub fn from_reader<R: Read>(reader: R) -> io::Result<Rope> {
+        Rope::from_reader_with_config(reader, RopeConfig::default())
+    }
+
+
+    /// Creates a new rope from a reader of UTF-8 text, with the given leaf
+    /// size configuration.
+    pub fn from_reader_with_config<R: Read>(reader: R, config: RopeConfig) -> io::Result<Rope> {
+        let mut reader = reader;
+        let mut rope = Rope::with_config(config);
+        let mut buffer: Vec<u8> = Vec::with_capacity(READ_BLOCK_SIZE);
+
         loop {
-            // Get the next chunk of the string to add
-            let mut byte_i = 0;
-            let mut le_count = 0;
-            let mut c_count = 0;
-            let mut g_count = 0;
-            for (bi, g) in UnicodeSegmentation::grapheme_indices(s1, true) {
-                byte_i = bi + g.len();
-                g_count += 1;
-                c_count += char_count(g);
-                if is_line_ending(g) {
-                    le_count += 1;
-                }
-                if g_count >= MAX_NODE_SIZE {
-                    break;
-                }
+            let read_count = (&mut reader).take(READ_BLOCK_SIZE as u64).read_to_end(&mut buffer)?;
+            let is_end = read_count < READ_BLOCK_SIZE;
+
+            // A block may end in the middle of a char, which is carried
+            // over to the next block
+            let valid_len = match str::from_utf8(&buffer[..]) {
+                Ok(_) => buffer.len(),
+                Err(ref e) if e.error_len().is_none() && !is_end => e.valid_up_to(),
+                Err(_) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Rope::from_reader(): stream did not contain valid UTF-8."));
+                },
+            };
+            let text = str::from_utf8(&buffer[..valid_len]).unwrap();
+
+            // Likewise for the last grapheme
+            let end = rope.append_complete_graphemes(text, is_end);
+            buffer.drain(..end);
+
+            if is_end {
+                return Ok(rope);
             }
-            if g_count == 0 {
-                break;
+        }
+    }
+
+
+    /// Appends a block of text that has been read from a stream, except
+    /// for its last grapheme, which may continue in the next block.  All
+    /// of the grapheme boundaries before it are final.  Returns the
+    /// length of the appended text, in bytes.
+    fn append_complete_graphemes(&mut self, text: &str, is_end: bool) -> usize {
+        let end = if is_end { text.len() } else { prev_grapheme_boundary(text, text.len().saturating_sub(1)) };
+        if end > 0 {
+            let config = self.config;
+            self.append(Rope::from_str_with_config(&text[..end], config));
+        }
+        return end;
+    }
+
+
+    /// Builds a rope from a string slice on the current thread.
+    fn build_from_str(s: &str, config: RopeConfig) -> Rope {
+        let mut nodes: Vec<Rope> = Vec::new();
+
+        let mut s1 = s;
+        while !s1.is_empty() {
+            // Get the next chunk of the string to add, ending on a
+            // grapheme boundary.  A single grapheme that's too large for
+            // a leaf gets one to itself.
+            let mut byte_i = prev_grapheme_boundary(s1, config.max_leaf_size);
+            if byte_i == 0 {
+                byte_i = next_grapheme_boundary(s1, 0);
             }
             let chunk = &s1[..byte_i];
-            
-            // Add chunk
-            rope_stack.push(Rope {
-                data: RopeData::Leaf(chunk.to_string()),
+            let (c_count, g_count, le_count) = char_grapheme_line_ending_count(chunk);
+
+            // Add chunk.  With piece table storage, consecutive chunks
+            // are copied into the same bounded buffer until it's full, so
+            // that a leaf that outlives the rest of the text doesn't keep
+            // all of it alive.
+            nodes.push(Rope {
+                data: RopeData::Leaf(LeafText::from_str(chunk, config)),
                 char_count_: c_count,
                 grapheme_count_: g_count,
                 line_ending_count_: le_count,
+                byte_count_: byte_i,
+                leaf_count_: 1,
                 tree_height: 1,
+                content_hash_: OnceLock::new(),
+                bracket_summary_: OnceLock::new(),
+                lf_count_: OnceLock::new(),
+                config,
             });
-            
-            // Do merges
-            loop {
-                let rsl = rope_stack.len();
-                if rsl > 1 && rope_stack[rsl-2].tree_height <= rope_stack[rsl-1].tree_height {
-                    let right = Box::new(rope_stack.pop().unwrap());
-                    let left = Box::new(rope_stack.pop().unwrap());
-                    let h = max(left.tree_height, right.tree_height) + 1;
-                    let lc = left.line_ending_count_ + right.line_ending_count_;
-                    let gc = left.grapheme_count_ + right.grapheme_count_;
-                    let cc = left.char_count_ + right.char_count_;
-                    rope_stack.push(Rope {
-                        data: RopeData::Branch(left, right),
-                        char_count_: cc,
-                        grapheme_count_: gc,
-                        line_ending_count_: lc,
-                        tree_height: h,
-                    });
-                }
-                else {
-                    break;
-                }
-            }
-            
+
             s1 = &s1[byte_i..];
         }
-        
-        
-        // Handle possible final unmerged case
-        let rope = if rope_stack.len() == 0 {
-            Rope::new()
-        }
-        else {
-            while rope_stack.len() > 1 {
-                let right = rope_stack.pop().unwrap();
-                let mut left = rope_stack.pop().unwrap();
-                left.append_right(right);
-                rope_stack.push(left);
-            }
-            rope_stack.pop().unwrap()
-        };
-        
-        return rope;
+
+        return Rope::from_leaves(nodes, config);
     }
-    
-    /// Creates a new rope from a string, consuming the string
-    pub fn from_string(s: String) -> Rope {
-        // TODO: special case short strings?
-        Rope::from_str(&s[..])
+
+    /// Returns the leaf size configuration of the rope.
+    pub fn config(&self) -> RopeConfig {
+        return self.config;
     }
-    
+
     pub fn char_count(&self) -> usize {
         return self.char_count_;
     }
-    
+
     pub fn grapheme_count(&self) -> usize {
         return self.grapheme_count_;
     }
-    
+
     pub fn line_ending_count(&self) -> usize {
         return self.line_ending_count_;
     }
-    
-    
+
+    pub fn byte_count(&self) -> usize {
+        return self.byte_count_;
+    }
+
+
     /// Returns the number of graphemes between char indices pos_a and pos_b.
     /// This is not as simple as a subtraction of char_index_to_grapheme_index()
     /// calls, because the char indices may split graphemes.
@@ -171,11 +505,11 @@ impl Rope {
     pub fn grapheme_count_in_char_range(&self, pos_a: usize, pos_b: usize) -> usize {
         assert!(pos_a <= pos_b, "Rope::grapheme_count_in_char_range(): pos_a must be less than or equal to pos_b.");
         assert!(pos_b <= self.char_count(), "Rope::grapheme_count_in_char_range(): attempted to get grapheme count beyond the end of the text.");
-        
+
         let ga = self.char_index_to_grapheme_index(pos_a);
         let gb = self.char_index_to_grapheme_index(pos_b);
         let cb = self.grapheme_index_to_char_index(gb);
-        
+
         if pos_b == cb {
             return gb - ga;
         }
@@ -183,59 +517,94 @@ impl Rope {
             return 1 + gb - ga;
         }
     }
-    
-    
+
+
     /// Returns the index of the grapheme that the given char index is a
     /// part of.
     pub fn char_index_to_grapheme_index(&self, pos: usize) -> usize {
         assert!(pos <= self.char_count(), "Rope::char_index_to_grapheme_index(): attempted to index beyond the end of the text.");
-        
+
         match self.data {
             RopeData::Leaf(ref text) => {
                 return char_pos_to_grapheme_pos(text, pos);
             },
-            
-            RopeData::Branch(ref left, ref right) => {
-                if pos < left.char_count_ {
-                    return left.char_index_to_grapheme_index(pos);
-                }
-                else {
-                    return left.grapheme_count_ + right.char_index_to_grapheme_index(pos - left.char_count_);
-                }
+
+            RopeData::Branch(ref children) => {
+                let (i, cc, gc, _) = children.search_char_index(pos);
+                return gc + children.get(i).char_index_to_grapheme_index(pos - cc);
             },
         }
-        
-        unreachable!()
     }
-    
-    
+
+
     /// Returns the beginning char index of the given grapheme index.
     pub fn grapheme_index_to_char_index(&self, pos: usize) -> usize {
         assert!(pos <= self.grapheme_count(), "Rope::grapheme_index_to_char_index(): attempted to index beyond the end of the text.");
-        
+
         match self.data {
             RopeData::Leaf(ref text) => {
                 return grapheme_pos_to_char_pos(text, pos);
             },
-            
-            RopeData::Branch(ref left, ref right) => {
-                if pos < left.grapheme_count_ {
-                    return left.grapheme_index_to_char_index(pos);
+
+            RopeData::Branch(ref children) => {
+                let (i, cc, gc) = children.search_grapheme_index(pos);
+                return cc + children.get(i).grapheme_index_to_char_index(pos - gc);
+            },
+        }
+    }
+
+
+    /// Returns the byte index of the given char index in the UTF-8 text.
+    pub fn char_index_to_byte_index(&self, pos: usize) -> usize {
+        assert!(pos <= self.char_count(), "Rope::char_index_to_byte_index(): attempted to index beyond the end of the text.");
+
+        match self.data {
+            RopeData::Leaf(ref text) => {
+                return char_pos_to_byte_pos(text, pos);
+            },
+
+            RopeData::Branch(ref children) => {
+                let (i, cc, _, _) = children.search_char_index(pos);
+                let bc: usize = children.iter().take(i).map(|node| node.byte_count_).sum();
+                return children.get(i).char_index_to_byte_index(pos - cc) + bc;
+            },
+        }
+    }
+
+
+    /// Returns the char index of the char that the given byte index is
+    /// in, in the UTF-8 text.
+    pub fn byte_index_to_char_index(&self, pos: usize) -> usize {
+        assert!(pos <= self.byte_count(), "Rope::byte_index_to_char_index(): attempted to index beyond the end of the text.");
+
+        match self.data {
+            RopeData::Leaf(ref text) => {
+                if pos == text.len() {
+                    return self.char_count_;
                 }
-                else {
-                    return left.char_count_ + right.grapheme_index_to_char_index(pos - left.grapheme_count_);
+                return text.as_bytes()[..=pos].iter().filter(|&&byte| (byte & 0xC0) != 0x80).count() - 1;
+            },
+
+            RopeData::Branch(ref children) => {
+                let mut bc = 0;
+                let mut cc = 0;
+                for (i, node) in children.iter().enumerate() {
+                    if pos < bc + node.byte_count_ || i + 1 == children.len() {
+                        return node.byte_index_to_char_index(pos - bc) + cc;
+                    }
+                    bc += node.byte_count_;
+                    cc += node.char_count_;
                 }
+                unreachable!()
             },
         }
-        
-        unreachable!()
     }
-    
-    
+
+
     /// Returns the index of the line that the given char index is on.
     pub fn char_index_to_line_index(&self, pos: usize) -> usize {
         assert!(pos <= self.char_count(), "Rope::char_index_to_line_index(): attempted to index beyond the end of the text.");
-    
+
         match self.data {
             RopeData::Leaf(ref text) => {
                 let mut ci = 0;
@@ -254,28 +623,46 @@ impl Rope {
                 }
                 return lei;
             },
-            
-            RopeData::Branch(ref left, ref right) => {
-                if pos < left.char_count_ {
-                    return left.char_index_to_line_index(pos);
-                }
-                else {
-                    return right.char_index_to_line_index(pos - left.char_count_) + left.line_ending_count_;
-                }
+
+            RopeData::Branch(ref children) => {
+                let (i, cc, _, lec) = children.search_char_index(pos);
+                return children.get(i).char_index_to_line_index(pos - cc) + lec;
             },
         }
     }
-    
-    
+
+
+    /// Returns the number of LFs before the given char index.  Unlike
+    /// char_index_to_line_index(), this only counts LF as a line break,
+    /// the way tools like tree-sitter count rows.  The LF counts of nodes
+    /// are cached as they're needed, so this runs in O(log N) time except
+    /// for nodes that were edited since the last call.
+    pub fn lf_count_before_char_index(&self, pos: usize) -> usize {
+        assert!(pos <= self.char_count(), "Rope::lf_count_before_char_index(): attempted to index beyond the end of the text.");
+
+        match self.data {
+            RopeData::Leaf(ref text) => {
+                return text.chars().take(pos).filter(|&c| c == '\n').count();
+            },
+
+            RopeData::Branch(ref children) => {
+                let (i, cc, _, _) = children.search_char_index(pos);
+                let lfc: usize = children.iter().take(i).map(|child| child.lf_count()).sum();
+                return children.get(i).lf_count_before_char_index(pos - cc) + lfc;
+            },
+        }
+    }
+
+
     /// Returns the char index at the start of the given line index.
     pub fn line_index_to_char_index(&self, li: usize) -> usize {
         assert!(li <= self.line_ending_count(), "Rope::line_index_to_char_index(): attempted to index beyond the end of the text.");
-        
+
         // Special case for the beginning of the rope
         if li == 0 {
             return 0;
         }
-        
+
         // General cases
         match self.data {
             RopeData::Leaf(ref text) => {
@@ -292,160 +679,181 @@ impl Rope {
                 }
                 return ci;
             },
-            
-            RopeData::Branch(ref left, ref right) => {
-                if li <= left.line_ending_count_ {
-                    return left.line_index_to_char_index(li);
-                }
-                else {
-                    return right.line_index_to_char_index(li - left.line_ending_count_) + left.char_count_;
-                }
+
+            RopeData::Branch(ref children) => {
+                let (i, cc, lec) = children.search_line_index(li);
+                return children.get(i).line_index_to_char_index(li - lec) + cc;
             },
         }
     }
-    
-    
+
+
     pub fn char_at_index(&self, index: usize) -> char {
         assert!(index < self.char_count(), "Rope::char_at_index(): attempted to fetch char that is outside the bounds of the text.");
-        
+
         match self.data {
             RopeData::Leaf(ref text) => {
-                let mut i: usize = 0;
-                for c in text.chars() {
-                    if i == index {
-                        return c;
-                    }
-                    i += 1;
+                if let Some(c) = text.chars().nth(index) {
+                    return c;
                 }
                 unreachable!();
             },
-            
-            RopeData::Branch(ref left, ref right) => {
-                if index < left.char_count() {
-                    return left.char_at_index(index);
-                }
-                else {
-                    return right.char_at_index(index - left.char_count());
-                }
+
+            RopeData::Branch(ref children) => {
+                let (i, cc, _, _) = children.search_char_index(index);
+                return children.get(i).char_at_index(index - cc);
             },
         }
     }
-    
-    
-    pub fn grapheme_at_index<'a>(&'a self, index: usize) -> &'a str {
+
+
+    pub fn grapheme_at_index(&self, index: usize) -> &str {
         assert!(index < self.grapheme_count(), "Rope::grapheme_at_index(): attempted to fetch grapheme that is outside the bounds of the text.");
-        
+
         match self.data {
             RopeData::Leaf(ref text) => {
-                let mut i: usize = 0;
-                for g in UnicodeSegmentation::graphemes(&text[..], true) {
-                    if i == index {
-                        return g;
-                    }
-                    i += 1;
+                if let Some(g) = UnicodeSegmentation::graphemes(&text[..], true).nth(index) {
+                    return g;
                 }
                 unreachable!();
             },
-            
-            RopeData::Branch(ref left, ref right) => {
-                if index < left.grapheme_count() {
-                    return left.grapheme_at_index(index);
-                }
-                else {
-                    return right.grapheme_at_index(index - left.grapheme_count());
-                }
+
+            RopeData::Branch(ref children) => {
+                let (i, _, gc) = children.search_grapheme_index(index);
+                return children.get(i).grapheme_at_index(index - gc);
             },
         }
     }
-    
-    
+
+
     /// Inserts the given text at the given char index.
     /// For small lengths of 'text' runs in O(log N) time.
-    /// For large lengths of 'text', dunno.  But it seems to perform
-    /// sub-linearly, at least.
+    /// For large lengths of 'text' the rope is split at the insertion
+    /// point and re-joined, which is also O(log N) aside from building
+    /// the inserted text itself.
     pub fn insert_text_at_char_index(&mut self, text: &str, pos: usize) {
         assert!(pos <= self.char_count(), "Rope::insert_text_at_char_index(): attempted to insert text at a position beyond the end of the text.");
-    
-        // Insert text    
+
+        // Insert text
         let cc = self.char_count_;
         self.insert_text_at_char_index_without_seam_check(text, pos);
         let cc2 = self.char_count_;
-        
+
         // Repair possible grapheme seams
         self.repair_grapheme_seam(pos);
         self.repair_grapheme_seam(pos + cc2 - cc);
     }
-    
-    
+
+
     /// Removes the text between the given char indices.
-    /// For small distances between pos_a and pos_b runs in O(log N) time.
-    /// For large distances, dunno.  If it becomes a performance bottleneck,
-    /// can special-case that to two splits and an append, which are all
-    /// sublinear.
+    /// Runs in O(log N) time plus time linear to the amount of text
+    /// that lives in partially removed leaf nodes.
     pub fn remove_text_between_char_indices(&mut self, pos_a: usize, pos_b: usize) {
         assert!(pos_a <= pos_b, "Rope::remove_text_between_char_indices(): pos_a must be less than or equal to pos_b.");
         assert!(pos_b <= self.char_count(), "Rope::remove_text_between_char_indices(): attempted to remove text beyond the end of the text.");
-        
+
         self.remove_text_between_char_indices_without_seam_check(pos_a, pos_b);
         self.repair_grapheme_seam(pos_a);
+        self.compact_if_fragmented();
     }
-    
-    
+
+
     /// Splits a rope into two pieces from the given char index.
     /// The first piece remains in this rope, the second piece is returned
     /// as a new rope.
-    /// I _think_ this runs in O(log N) time, but this needs more analysis to
-    /// be sure.  It is at least sublinear.
+    /// Runs in O(log N) time.
     pub fn split_at_char_index(&mut self, pos: usize) -> Rope {
         assert!(pos <= self.char_count(), "Rope::split_at_char_index(): attempted to split text at a position beyond the end of the text.");
-    
-        let mut left = Rope::new();
-        let mut right = Rope::new();
-        
-        self.split_recursive(pos, &mut left, &mut right);
-        
-        mem::swap(self, &mut left);
+
+        let mut right = self.split_recursive(pos);
+
+        self.shrink_root();
+        right.shrink_root();
+        self.compact_if_fragmented();
+        right.compact_if_fragmented();
         return right;
     }
-    
+
+
+    /// Merges undersized adjacent leaf nodes, and rebuilds the rope as a
+    /// balanced tree of full leaf nodes.
+    /// Runs in O(N) time.
+    pub fn compact(&mut self) {
+        let config = self.config;
+        let mut nodes: Vec<Rope> = Vec::new();
+        let mut buffer = LeafText::from_str("", config);
+
+        for chunk in self.chunk_iter() {
+            let mut rest = chunk;
+            while !rest.is_empty() {
+                let room = config.max_leaf_size - min(buffer.len(), config.max_leaf_size);
+                if rest.len() <= room {
+                    buffer.push_str(rest);
+                    break;
+                }
+
+                // Fill the buffer up to the last grapheme boundary that
+                // fits, and start a new leaf.  A lone grapheme that's too
+                // large for any leaf gets one to itself.
+                let mut split_pos = prev_grapheme_boundary(rest, room);
+                if split_pos == 0 && buffer.is_empty() {
+                    split_pos = next_grapheme_boundary(rest, 0);
+                }
+                buffer.push_str(&rest[..split_pos]);
+                rest = &rest[split_pos..];
+
+                let text = mem::replace(&mut buffer, LeafText::from_str("", config));
+                nodes.push(Rope::new_leaf(text, config));
+            }
+        }
+        if !buffer.is_empty() {
+            nodes.push(Rope::new_leaf(buffer, config));
+        }
+
+        *self = Rope::from_leaves(nodes, config);
+    }
+
 
     /// Appends another rope to the end of this one, consuming the other rope.
-    /// Runs in O(log N) time.
+    /// Runs in O(log N) time.  A rope with a different config is rebuilt
+    /// with this rope's config first, though, which takes time linear to
+    /// its length.
     pub fn append(&mut self, rope: Rope) {
         let cc = self.char_count_;
         self.append_without_seam_check(rope);
         self.repair_grapheme_seam(cc);
-    }    
-    
-    
+    }
+
+
     /// Makes a copy of the rope as a string.
     /// Runs in O(N) time.
+    #[allow(clippy::inherent_to_string)]
     pub fn to_string(&self) -> String {
         let mut s = String::new();
 
         for chunk in self.chunk_iter() {
             s.push_str(chunk);
         }
-        
+
         return s;
     }
-    
-    
+
+
     /// Creates a chunk iterator for the rope
     pub fn chunk_iter<'a>(&'a self) -> RopeChunkIter<'a> {
         self.chunk_iter_at_char_index(0).1
     }
-    
-    
+
+
     /// Creates a chunk iter starting at the chunk containing the given
     /// char index.  Returns the chunk iter and its starting char index.
     pub fn chunk_iter_at_char_index<'a>(&'a self, index: usize) -> (usize, RopeChunkIter<'a>) {
         assert!(index <= self.char_count(), "Rope::chunk_iter_at_char_index(): attempted to create an iterator starting beyond the end of the text.");
-        
+
         let mut node_stack: Vec<&'a Rope> = Vec::new();
         let mut cur_node = self;
         let mut char_i = index;
-        
+
         // Find the right rope node, and populate the stack at the same time
         loop {
             match cur_node.data {
@@ -453,97 +861,95 @@ impl Rope {
                     node_stack.push(cur_node);
                     break;
                 },
-                
-                RopeData::Branch(ref left, ref right) => {
-                    if char_i < left.char_count_ {
-                        node_stack.push(&(**right));
-                        cur_node = &(**left);
-                    }
-                    else {
-                        cur_node = &(**right);
-                        char_i -= left.char_count_;
+
+                RopeData::Branch(ref children) => {
+                    let (i, cc, _, _) = children.search_char_index(char_i);
+                    for node in children.iter().skip(i + 1).rev() {
+                        node_stack.push(node);
                     }
+                    cur_node = children.get(i);
+                    char_i -= cc;
                 }
             }
         }
-        
-        (index - char_i, RopeChunkIter {node_stack: node_stack})
+
+        (index - char_i, RopeChunkIter {node_stack})
     }
-    
-    
+
+
     /// Creates an iterator at the first char of the rope
     pub fn char_iter<'a>(&'a self) -> RopeCharIter<'a> {
         self.char_iter_at_index(0)
     }
-    
-    
+
+
     /// Creates an iterator starting at the given char index
     pub fn char_iter_at_index<'a>(&'a self, index: usize) -> RopeCharIter<'a> {
         assert!(index <= self.char_count(), "Rope::char_iter_at_index(): attempted to create an iterator starting beyond the end of the text.");
-        
+
         let (char_i, mut chunk_iter) = self.chunk_iter_at_char_index(index);
-        
+
         // Create the char iter for the current node
         let mut citer = if let Some(text) = chunk_iter.next() {
-            (&text[..]).chars()
+            text.chars()
         }
         else {
             unreachable!()
         };
-        
+
         // Get to the right spot in the iter
         for _ in char_i..index {
             citer.next();
         }
-        
+
         // Create the rope grapheme iter
         return RopeCharIter {
-            chunk_iter: chunk_iter,
+            chunk_iter,
             cur_chunk: citer,
             length: None,
         };
     }
-    
-    
+
+
     /// Creates an iterator that starts at pos_a and stops just before pos_b.
     pub fn char_iter_between_indices<'a>(&'a self, pos_a: usize, pos_b: usize) -> RopeCharIter<'a> {
         assert!(pos_a <= pos_b, "Rope::char_iter_between_indices(): pos_a must be less than or equal to pos_b.");
         assert!(pos_b <= self.char_count(), "Rope::char_iter_between_indices(): attempted to create an iterator starting beyond the end of the text.");
-    
+
         let mut iter = self.char_iter_at_index(pos_a);
         iter.length = Some(pos_b - pos_a);
         return iter;
     }
-    
-    
+
+
     /// Creates an iterator at the first grapheme of the rope
     pub fn grapheme_iter<'a>(&'a self) -> RopeGraphemeIter<'a> {
         self.grapheme_iter_at_index(0)
     }
-    
-    
+
+
     /// Creates an iterator at the given grapheme index
     pub fn grapheme_iter_at_index<'a>(&'a self, index: usize) -> RopeGraphemeIter<'a> {
         assert!(index <= self.grapheme_count(), "Rope::grapheme_iter_at_index(): attempted to create an iterator starting beyond the end of the text.");
-        
+
         let cindex = self.grapheme_index_to_char_index(index);
         return self.grapheme_iter_at_char_index(cindex);
     }
-    
-    
+
+
     /// Creates an iterator that starts a pos_a and stops just before pos_b.
     pub fn grapheme_iter_between_indices<'a>(&'a self, pos_a: usize, pos_b: usize) -> RopeGraphemeIter<'a> {
         assert!(pos_a <= pos_b, "Rope::grapheme_iter_between_indices(): pos_a must be less than or equal to pos_b.");
         assert!(pos_b <= self.grapheme_count(), "Rope::grapheme_iter_between_indices(): attempted to create an iterator starting beyond the end of the text.");
-    
+
         let mut iter = self.grapheme_iter_at_index(pos_a);
         let cpos_a = self.grapheme_index_to_char_index(pos_a);
         let cpos_b = self.grapheme_index_to_char_index(pos_b);
         iter.length = Some(cpos_b - cpos_a);
         return iter;
     }
-    
-    
+
+
     /// Creates an iterator over the lines in the rope.
     pub fn line_iter<'a>(&'a self) -> RopeLineIter<'a> {
         RopeLineIter {
@@ -551,36 +957,36 @@ impl Rope {
             li: 0,
         }
     }
-    
-    
+
+
     /// Creates an iterator over the lines in the rope, starting at the given
     /// line index.
     pub fn line_iter_at_index<'a>(&'a self, index: usize) -> RopeLineIter<'a> {
         assert!(index <= (self.line_ending_count()+1), "Rope::line_iter_at_index(): attempted to create an iterator starting beyond the end of the text.");
-        
+
         RopeLineIter {
             rope: self,
             li: index,
         }
     }
-    
-    
+
+
     // Creates a slice into the Rope, between char indices pos_a and pos_b.
     pub fn slice<'a>(&'a self, pos_a: usize, pos_b: usize) -> RopeSlice<'a> {
         assert!(pos_a <= pos_b, "Rope::slice(): pos_a must be less than or equal to pos_b.");
         assert!(pos_b <= self.char_count(), "Rope::slice(): attempted to create a slice extending beyond the end of the text.");
-        
+
         let a = pos_a;
         let b = min(self.char_count_, pos_b);
-        
+
         RopeSlice {
             rope: self,
             start: a,
             end: b,
         }
     }
-    
-    
+
+
     // Creates a graphviz document of the Rope's structure, and returns
     // it as a string.  For debugging purposes.
     pub fn to_graphviz(&self) -> String {
@@ -589,497 +995,711 @@ impl Rope {
         text.push_str("}\n");
         return text;
     }
-    
-    
+
+
+    /// Creates a read-only snapshot of the rope in its current state.
+    /// Runs in O(1) time.
+    pub fn snapshot(&self) -> RopeSnapshot {
+        RopeSnapshot {
+            rope: Arc::new(self.clone()),
+        }
+    }
+
+
+    /// Gathers statistics about the rope's tree structure and memory
+    /// usage.
+    /// Runs in O(N) time in the number of nodes.
+    pub fn stats(&self) -> RopeStats {
+        let mut stats = RopeStats {
+            leaf_count: 0,
+            branch_count: 0,
+            tree_height: self.tree_height,
+            text_bytes: self.byte_count_,
+            heap_bytes: 0,
+            wasted_leaf_bytes: 0,
+            span_buffer_bytes: 0,
+            leaf_size_histogram: Vec::new(),
+        };
+        let mut span_buffers = HashSet::new();
+
+        self.visit_nodes("s".to_string(), &mut |node, _| {
+            match node.data {
+                RopeData::Leaf(ref text) => {
+                    stats.leaf_count += 1;
+                    stats.heap_bytes += text.capacity();
+                    stats.wasted_leaf_bytes += text.capacity() - text.len();
+                    if let Some((buffer_id, capacity)) = text.span_buffer() {
+                        if span_buffers.insert(buffer_id) {
+                            stats.span_buffer_bytes += capacity;
+                        }
+                    }
+
+                    let bucket = (usize::BITS - text.len().leading_zeros()) as usize;
+                    if stats.leaf_size_histogram.len() <= bucket {
+                        stats.leaf_size_histogram.resize(bucket + 1, 0);
+                    }
+                    stats.leaf_size_histogram[bucket] += 1;
+                },
+
+                RopeData::Branch(_) => {
+                    stats.branch_count += 1;
+                },
+            }
+        });
+
+        // Every node except the root is in a reference counted allocation
+        // of its parent
+        let node_bytes = mem::size_of::<Rope>() + (2 * mem::size_of::<usize>());
+        stats.heap_bytes += (stats.leaf_count + stats.branch_count - 1) * node_bytes;
+
+        return stats;
+    }
+
+
+    /// Returns a hash of the rope's text, which only depends on the text
+    /// and not on how it's split between leaf nodes.  It's the same as the
+    /// ContentHash of the text's UTF-8 bytes.
+    /// Runs in O(N) time, or in O(log N) time plus time linear to the
+    /// size of the edits since the last call with hash caching.
+    pub fn content_hash(&self) -> ContentHash {
+        if self.config.cache_hashes {
+            return self.cached_content_hash();
+        }
+
+        let mut hash = ContentHash::new();
+        for chunk in self.chunk_iter() {
+            hash.update(chunk.as_bytes());
+        }
+        return hash;
+    }
+
+
     //================================================================
     // Private utility functions
     //================================================================
-    
-    
+
+
+    fn cached_content_hash(&self) -> ContentHash {
+        *self.content_hash_.get_or_init(|| {
+            match self.data {
+                RopeData::Leaf(ref text) => ContentHash::of_bytes(text.as_bytes()),
+                RopeData::Branch(ref children) => {
+                    children.iter().fold(ContentHash::new(), |hash, child| hash.concat(&child.cached_content_hash()))
+                },
+            }
+        })
+    }
+
+
+    fn lf_count(&self) -> usize {
+        *self.lf_count_.get_or_init(|| {
+            match self.data {
+                RopeData::Leaf(ref text) => text.as_bytes().iter().filter(|&&b| b == b'\n').count(),
+                RopeData::Branch(ref children) => children.iter().map(|child| child.lf_count()).sum(),
+            }
+        })
+    }
+
+
     fn to_graphviz_recursive(&self, text: &mut String, name: String) {
-        match self.data {
-            RopeData::Leaf(_) => {
-                text.push_str(&(format!("{} [label=\"cc={}\\ngc={}\\nlec={}\"];\n", name, self.char_count_, self.grapheme_count_, self.line_ending_count_))[..]);
-            },
-            
-            RopeData::Branch(ref left, ref right) => {
-                let mut lname = name.clone();
-                let mut rname = name.clone();
-                lname.push('l');
-                rname.push('r');
-                text.push_str(&(format!("{} [shape=box, label=\"h={}\\ncc={}\\ngc={}\\nlec={}\"];\n", name, self.tree_height, self.char_count_, self.grapheme_count_, self.line_ending_count_))[..]);
-                text.push_str(&(format!("{} -> {{ {} {} }};\n", name, lname, rname))[..]);
-                left.to_graphviz_recursive(text, lname);
-                right.to_graphviz_recursive(text, rname);
+        self.visit_nodes(name, &mut |node, name| {
+            match node.data {
+                RopeData::Leaf(_) => {
+                    text.push_str(&(format!("{} [label=\"cc={}\\ngc={}\\nlec={}\"];\n", name, node.char_count_, node.grapheme_count_, node.line_ending_count_))[..]);
+                },
+
+                RopeData::Branch(ref children) => {
+                    let names: Vec<String> = (0..children.len()).map(|i| format!("{}_{}", name, i)).collect();
+                    text.push_str(&(format!("{} [shape=box, label=\"h={}\\ncc={}\\ngc={}\\nlec={}\"];\n", name, node.tree_height, node.char_count_, node.grapheme_count_, node.line_ending_count_))[..]);
+                    text.push_str(&(format!("{} -> {{ {} }};\n", name, names.join(" ")))[..]);
+                }
+            }
+        });
+    }
+
+
+    /// Calls the given function on every node of the tree in pre-order,
+    /// along with a name for the node.  Child nodes are named after their
+    /// parent and their index in it.
+    fn visit_nodes<F: FnMut(&Rope, &str)>(&self, name: String, f: &mut F) {
+        f(self, &name[..]);
+
+        if let RopeData::Branch(ref children) = self.data {
+            for (i, child) in children.iter().enumerate() {
+                child.visit_nodes(format!("{}_{}", name, i), f);
             }
         }
     }
-    
-    
-    fn is_leaf(&self) -> bool {
-        if let RopeData::Leaf(_) = self.data {
-            true
+
+
+    /// Builds a balanced tree from a list of leaf nodes, bottom-up, one
+    /// level at a time.  Only the last leaf may be underfull, and it's
+    /// evened out with the one before it.
+    fn from_leaves(nodes: Vec<Rope>, config: RopeConfig) -> Rope {
+        let mut nodes = nodes;
+
+        if nodes.len() > 1 && nodes[nodes.len() - 1].is_underfull() {
+            let right = nodes.pop().unwrap();
+            let last = nodes.len() - 1;
+            if let Some(node) = nodes[last].merge_with(right) {
+                nodes.push(node);
+            }
         }
-        else {
-            false
+
+        if nodes.is_empty() {
+            return Rope::with_config(config);
+        }
+        while nodes.len() > 1 {
+            nodes = Rope::group_into_branches(nodes, config);
+        }
+
+        return nodes.pop().unwrap();
+    }
+
+
+    /// Compacts the rope if its leaf nodes are badly fragmented, i.e. if
+    /// it has more than twice as many leaves as it would with leaves of
+    /// the minimum size.  Since the leaf count can only grow a little with
+    /// each edit, the cost of compaction is amortized over the many edits
+    /// that it takes to get there.  Should only be called on the root node.
+    fn compact_if_fragmented(&mut self) {
+        if self.leaf_count_ > ((self.byte_count_ / self.config.min_leaf_size) * 2) + 1 {
+            self.compact();
+        }
+    }
+
+
+    /// Creates a new leaf node from the given text.  Owned text is moved
+    /// into a buffer with the full leaf capacity if it doesn't have one
+    /// already.
+    fn new_leaf(text: LeafText, config: RopeConfig) -> Rope {
+        let mut text = text;
+        text.reserve_capacity(config.max_leaf_size);
+
+        let mut rope = Rope::with_config(config);
+        rope.data = RopeData::Leaf(text);
+        rope.update_stats();
+        return rope;
+    }
+
+
+    fn new_branch(children: NodeChildren, config: RopeConfig) -> Rope {
+        let mut rope = Rope::with_config(config);
+        rope.data = RopeData::Branch(children);
+        rope.update_stats();
+        return rope;
+    }
+
+
+    /// Groups a list of nodes of equal height under as few new branch
+    /// nodes as possible.  The children are spread evenly, so that every
+    /// new node ends up with at least MIN_CHILDREN children unless there
+    /// is only one new node.
+    fn group_into_branches(nodes: Vec<Rope>, config: RopeConfig) -> Vec<Rope> {
+        let node_count = nodes.len();
+        let branch_count = node_count.div_ceil(MAX_CHILDREN);
+        let mut branches = Vec::with_capacity(branch_count);
+        let mut node_iter = nodes.into_iter();
+
+        for i in 0..branch_count {
+            let mut children = NodeChildren::new();
+            let child_count = (node_count / branch_count) + if i < (node_count % branch_count) {1} else {0};
+            for node in node_iter.by_ref().take(child_count) {
+                children.push(node);
+            }
+            branches.push(Rope::new_branch(children, config));
+        }
+
+        return branches;
+    }
+
+
+    fn is_leaf(&self) -> bool {
+        matches!(self.data, RopeData::Leaf(_))
+    }
+
+
+    /// Returns whether this node is smaller than the B-tree invariants
+    /// allow for non-root nodes.
+    fn is_underfull(&self) -> bool {
+        match self.data {
+            RopeData::Leaf(ref text) => text.len() < self.config.min_leaf_size,
+            RopeData::Branch(ref children) => children.len() < MIN_CHILDREN,
         }
     }
-    
 
-    /// Non-recursively updates the stats of a node    
+
+    /// Non-recursively updates the stats of a node
     fn update_stats(&mut self) {
+        self.content_hash_ = OnceLock::new();
+        self.bracket_summary_ = OnceLock::new();
+        self.lf_count_ = OnceLock::new();
+
         match self.data {
             RopeData::Leaf(ref text) => {
                 let (cc, gc, lec) = char_grapheme_line_ending_count(text);
                 self.char_count_ = cc;
                 self.grapheme_count_ = gc;
                 self.line_ending_count_ = lec;
+                self.byte_count_ = text.len();
+                self.leaf_count_ = 1;
                 self.tree_height = 1;
             },
-            
-            RopeData::Branch(ref left, ref right) => {
-                self.char_count_ = left.char_count_ + right.char_count_;
-                self.grapheme_count_ = left.grapheme_count_ + right.grapheme_count_;
-                self.line_ending_count_ = left.line_ending_count_ + right.line_ending_count_;
-                self.tree_height = max(left.tree_height, right.tree_height) + 1;
+
+            RopeData::Branch(ref children) => {
+                self.char_count_ = 0;
+                self.grapheme_count_ = 0;
+                self.line_ending_count_ = 0;
+                self.byte_count_ = 0;
+                self.leaf_count_ = 0;
+                self.tree_height = 1;
+                for child in children.iter() {
+                    self.char_count_ += child.char_count_;
+                    self.grapheme_count_ += child.grapheme_count_;
+                    self.line_ending_count_ += child.line_ending_count_;
+                    self.byte_count_ += child.byte_count_;
+                    self.leaf_count_ += child.leaf_count_;
+                    self.tree_height = max(self.tree_height, child.tree_height + 1);
+                }
             }
         }
     }
-    
-    
-    fn split_recursive(&mut self, pos: usize, left: &mut Rope, right: &mut Rope) {
-        match self.data {
-            RopeData::Leaf(ref text) => {
-                // Split the text into two new nodes
-                let mut l_text = text.clone();
-                let r_text = split_string_at_char_index(&mut l_text, pos);
-                let new_rope_l = Rope::from_string(l_text);
-                let mut new_rope_r = Rope::from_string(r_text);
-                
-                // Append the nodes to their respective sides
-                left.append_without_seam_check(new_rope_l);
-                mem::swap(right, &mut new_rope_r);
-                right.append_without_seam_check(new_rope_r);
+
+
+    /// Splits the node at the given char index, returning the right side
+    /// as a new node of the same height.  Either side may end up
+    /// underfull or even empty, which the parent node takes care of.
+    fn split_recursive(&mut self, pos: usize) -> Rope {
+        let mut right = match self.data {
+            RopeData::Leaf(ref mut text) => {
+                Rope::new_leaf(text.split_off_at_char_index(pos), self.config)
             },
-            
-            RopeData::Branch(ref mut left_b, ref mut right_b) => {
-                let mut l = Rope::new();
-                let mut r = Rope::new();
-                mem::swap(&mut **left_b, &mut l);
-                mem::swap(&mut **right_b, &mut r);
-                
-                // Split is on left side
-                if pos < l.char_count_ {
-                    // Append the right split to the right side
-                    mem::swap(right, &mut r);
-                    right.append_without_seam_check(r);
-                    
-                    // Recurse
-                    if let RopeData::Branch(_, ref mut new_left) = left.data {
-                        if let RopeData::Branch(ref mut new_right, _) = right.data {
-                            l.split_recursive(pos, new_left, new_right);
-                        }
-                        else {
-                            l.split_recursive(pos, new_left, right);
-                        }
-                    }
-                    else {
-                        if let RopeData::Branch(ref mut new_right, _) = right.data {
-                            l.split_recursive(pos, left, new_right);
-                        }
-                        else {
-                            l.split_recursive(pos, left, right);
-                        }
-                    }
-                }
-                // Split is on right side
-                else {
-                    // Append the left split to the left side
-                    let new_pos = pos - l.char_count_;
-                    left.append_without_seam_check(l);
-                    
-                    // Recurse
-                    if let RopeData::Branch(_, ref mut new_left) = left.data {
-                        if let RopeData::Branch(ref mut new_right, _) = right.data {
-                            r.split_recursive(new_pos, new_left, new_right);
-                        }
-                        else {
-                            r.split_recursive(new_pos, new_left, right);
-                        }
-                    }
-                    else {
-                        if let RopeData::Branch(ref mut new_right, _) = right.data {
-                            r.split_recursive(new_pos, left, new_right);
-                        }
-                        else {
-                            r.split_recursive(new_pos, left, right);
-                        }
-                    }
-                }
+
+            RopeData::Branch(ref mut children) => {
+                let (i, cc, _, _) = children.search_char_index(pos);
+                let split_node = children.get_mut(i).split_recursive(pos - cc);
+                let mut right_children = children.split_off(i + 1);
+                right_children.insert(0, split_node);
+                Rope::new_branch(right_children, self.config)
             },
-            
-        }
-        
-        left.rebalance();
-        right.rebalance();
+        };
+
+        self.update_stats();
+        self.fix_underfull_children();
+        right.fix_underfull_children();
+
+        return right;
     }
-    
-    
+
+
     fn append_without_seam_check(&mut self, rope: Rope) {
-        if self.grapheme_count_ == 0 {
-            let mut r = rope;
-            mem::swap(self, &mut r);
+        // A rope with different leaf sizes has to be rebuilt to match
+        let mut rope = rope;
+        if rope.config != self.config {
+            rope = Rope::from_str_with_config(&rope.to_string()[..], self.config);
         }
-        else if rope.grapheme_count_ == 0 {
+
+        if rope.char_count_ == 0 {
             return;
         }
-        else if self.tree_height > rope.tree_height {
-            self.append_right(rope);
+        else if self.char_count_ == 0 {
+            *self = rope;
+            return;
         }
-        else {
-            let mut rope = rope;
+
+        let new_node = if self.tree_height > rope.tree_height {
+            self.append_node(rope)
+        }
+        else if self.tree_height < rope.tree_height {
             mem::swap(self, &mut rope);
-            self.append_left(rope);
+            self.prepend_node(rope)
         }
-    }  
-    
-    
-    fn append_right(&mut self, rope: Rope) {
-        if self.tree_height <= rope.tree_height || self.is_leaf() {
-            let mut temp_rope = Box::new(Rope::new());
-            mem::swap(self, &mut (*temp_rope));
-            self.data = RopeData::Branch(temp_rope, Box::new(rope));
+        else {
+            Some(rope)
+        };
+
+        // Grow the tree by a level if the root had to be split
+        if let Some(node) = new_node {
+            self.grow_root(node);
+            self.fix_underfull_children();
         }
-        else if let RopeData::Branch(_, ref mut right) = self.data {
-            right.append_right(rope);
+
+        self.shrink_root();
+    }
+
+
+    /// Attaches a node of smaller height to the right edge of this
+    /// subtree.  Returns a new right sibling for this node if it had to
+    /// be split to make room.
+    fn append_node(&mut self, rope: Rope) -> Option<Rope> {
+        if let RopeData::Branch(ref mut children) = self.data {
+            if self.tree_height == rope.tree_height + 1 {
+                children.push(rope);
+            }
+            else {
+                let last = children.len() - 1;
+                if let Some(node) = children.get_mut(last).append_node(rope) {
+                    children.push(node);
+                }
+            }
         }
-        
-        self.update_stats();
-        self.rebalance();
+        else {
+            panic!("Rope::append_node(): attempted to append a node to a leaf node.");
+        }
+
+        self.fix_underfull_children();
+        return self.split_if_overfull();
     }
-    
-    
-    fn append_left(&mut self, rope: Rope) {
-        if self.tree_height <= rope.tree_height || self.is_leaf() {
-            let mut temp_rope = Box::new(Rope::new());
-            mem::swap(self, &mut (*temp_rope));
-            self.data = RopeData::Branch(Box::new(rope), temp_rope);
+
+
+    /// Attaches a node of smaller height to the left edge of this
+    /// subtree.  Returns a new right sibling for this node if it had to
+    /// be split to make room.
+    fn prepend_node(&mut self, rope: Rope) -> Option<Rope> {
+        if let RopeData::Branch(ref mut children) = self.data {
+            if self.tree_height == rope.tree_height + 1 {
+                children.insert(0, rope);
+            }
+            else if let Some(node) = children.get_mut(0).prepend_node(rope) {
+                children.insert(1, node);
+            }
         }
-        else if let RopeData::Branch(ref mut left, _) = self.data {
-            left.append_left(rope);
+        else {
+            panic!("Rope::prepend_node(): attempted to prepend a node to a leaf node.");
         }
-        
+
+        self.fix_underfull_children();
+        return self.split_if_overfull();
+    }
+
+
+    /// Splits a branch node in half if it has more than MAX_CHILDREN
+    /// children.  The left half stays in this node, and the right half
+    /// is returned as a new node.
+    fn split_if_overfull(&mut self) -> Option<Rope> {
+        let mut new_node = None;
+
+        if let RopeData::Branch(ref mut children) = self.data {
+            if children.len() > MAX_CHILDREN {
+                let half = children.len() / 2;
+                new_node = Some(Rope::new_branch(children.split_off(half), self.config));
+            }
+        }
+
         self.update_stats();
-        self.rebalance();
+        return new_node;
     }
-    
-    
-    /// Inserts the given text at the given char index.
-    /// This is done without a seam check because it is recursive and
-    /// would otherwise do a seam check at every recursive function call.
-    /// Rope::insert_text_at_char_index() calls this, and then does the seam
-    /// checks afterwards.
-    fn insert_text_at_char_index_without_seam_check(&mut self, text: &str, pos: usize) {
-        let mut leaf_insert = false;
-        
-        match self.data {
-            // Find node for text to be inserted into
-            RopeData::Branch(ref mut left, ref mut right) => {
-                if pos < left.char_count_ {
-                    left.insert_text_at_char_index(text, pos);
+
+
+    /// Removes empty children of a branch node, and merges underfull
+    /// children with their siblings, so that all children adhere to the
+    /// B-tree invariants again.  This can leave the node itself underfull,
+    /// which is its parent's job to fix.
+    fn fix_underfull_children(&mut self) {
+        if let RopeData::Branch(ref mut children) = self.data {
+            // Remove empty children
+            let mut i = 0;
+            while i < children.len() {
+                if children.get(i).char_count_ == 0 {
+                    children.remove(i);
                 }
                 else {
-                    right.insert_text_at_char_index(text, pos - left.char_count_);
+                    i += 1;
                 }
-            },
-            
-            // Insert the text
-            RopeData::Leaf(ref mut s_text) => {
-                if grapheme_count_is_less_than(text, MAX_NODE_SIZE - self.grapheme_count_) {
-                    // Simple case
-                    insert_text_at_char_index(s_text, text, pos);
+            }
+
+            // Merge underfull children with a neighbor
+            let mut i = 0;
+            while i < children.len() && children.len() > 1 {
+                if children.get(i).is_underfull() {
+                    let j = if (i + 1) < children.len() { i } else { i - 1 };
+                    let right = children.remove(j + 1);
+                    if let Some(node) = children.get_mut(j).merge_with(right) {
+                        // The two were evened out rather than merged.
+                        // Branches can become underfull again when fixing
+                        // up their own children afterwards, so they are
+                        // retried.  Leaves can't be improved any further.
+                        children.insert(j + 1, node);
+                        let retry = !children.get(j).is_leaf()
+                            && (children.get(j).is_underfull() || children.get(j + 1).is_underfull());
+                        i = if retry { j } else { j + 2 };
+                    }
+                    else {
+                        i = j;
+                    }
                 }
                 else {
-                    // Special cases
-                    leaf_insert = true;
+                    i += 1;
                 }
-            },
-        }
-        
-        // The special cases of inserting at a leaf node.
-        // These have to be done outside of the match statement because
-        // of the borrow checker, but logically they take place in the
-        // RopeData::Leaf branch of the match statement above.
-        if leaf_insert {
-            // TODO: these special cases are currently prone to causing leaf
-            // fragmentation.  Find ways to reduce that.
-            if pos == 0 {
-                let mut new_rope = Rope::new();
-                mem::swap(self, &mut new_rope);
-                self.data = RopeData::Branch(Box::new(Rope::from_str(text)), Box::new(new_rope));
-            }
-            else if pos == self.char_count_ {
-                let mut new_rope = Rope::new();
-                mem::swap(self, &mut new_rope);
-                self.data = RopeData::Branch(Box::new(new_rope), Box::new(Rope::from_str(text)));
-            }
-            else {
-                // Split the leaf node at the insertion point
-                let mut node_l = Rope::new();
-                let node_r = self.split_at_char_index(pos);
-                mem::swap(self, &mut node_l);
-                
-                // Set the inserted text as the main node
-                *self = Rope::from_str(text);
-                
-                // Append the left and right split nodes to either side of
-                // the main node.
-                self.append_left(node_l);
-                self.append_right(node_r);
             }
         }
-        
+
         self.update_stats();
-        self.rebalance();
     }
-    
-    
-    /// Removes the text between the given char indices.
-    /// This is done without a seam check so that it can be used inside
-    /// repair_grapheme_seam() without risk of unintended recursion.
-    fn remove_text_between_char_indices_without_seam_check(&mut self, pos_a: usize, pos_b: usize) {
-        // Bounds checks
-        if pos_a > pos_b {
-            panic!("Rope::remove_text_between_char_indices(): pos_a must be less than or equal to pos_b.");
-        }
-        if pos_b > self.char_count_ {
-            panic!("Rope::remove_text_between_char_indices(): attempt to remove text after end of node text.");
-        }
-        
-        match self.data {
-            RopeData::Leaf(ref mut text) => {
-                remove_text_between_char_indices(text, pos_a, pos_b);
+
+
+    /// Merges the given node, which must be the right sibling of this
+    /// node, into this node.  If the result is too large it is split
+    /// evenly again, and the right half is returned.
+    fn merge_with(&mut self, rope: Rope) -> Option<Rope> {
+        assert!(self.tree_height == rope.tree_height, "Rope::merge_with(): attempted to merge nodes of different heights.");
+
+        let mut rope = rope;
+        let mut merged = true;
+
+        // Fix up the children of branches first, so that the child
+        // counts used below are final
+        self.fix_underfull_children();
+        rope.fix_underfull_children();
+
+        match (&mut self.data, &mut rope.data) {
+            (&mut RopeData::Leaf(ref mut l_text), &mut RopeData::Leaf(ref mut r_text)) => {
+                if (l_text.len() + r_text.len()) <= self.config.max_leaf_size {
+                    l_text.append(r_text);
+                }
+                else {
+                    // Redistribute the text evenly between the two leaves,
+                    // reusing their existing buffers.  Splitting in the
+                    // middle keeps both at or above the minimum leaf size.
+                    let mut combined = String::with_capacity(l_text.len() + r_text.len());
+                    combined.push_str(l_text);
+                    combined.push_str(r_text);
+                    let split_pos = nearest_grapheme_boundary(&combined, combined.len() / 2);
+                    l_text.set(&combined[..split_pos]);
+                    r_text.set(&combined[split_pos..]);
+                    merged = false;
+                }
             },
-            
-            RopeData::Branch(ref mut left, ref mut right) => {
-                let lcc = left.char_count_;
-                
-                if pos_a < lcc {
-                    left.remove_text_between_char_indices(pos_a, min(pos_b, lcc));
+
+            (&mut RopeData::Branch(ref mut l_children), &mut RopeData::Branch(ref mut r_children)) => {
+                if (l_children.len() + r_children.len()) <= MAX_CHILDREN {
+                    l_children.append(r_children);
                 }
-                
-                if pos_b > lcc {
-                    right.remove_text_between_char_indices(pos_a - min(pos_a, lcc), pos_b - lcc);
+                else {
+                    l_children.balance_with(r_children);
+                    merged = false;
                 }
-            }
+            },
+
+            _ => {
+                unreachable!();
+            },
+        }
+
+        // Branches may have underfull children where they were joined, or
+        // that were moved between them while evening them out
+        self.fix_underfull_children();
+        if merged {
+            return None;
+        }
+        else {
+            rope.fix_underfull_children();
+            return Some(rope);
         }
-        
-        self.update_stats();
-        self.merge_if_too_small();
-        self.rebalance();
     }
 
 
-    /// Splits a leaf node into pieces if it's too large
-    // TODO: find a way to do this that's more algorithmically efficient
-    // if lots of splits need to happen.  This version ends up re-scanning
-    // the text quite a lot, as well as doing quite a few unnecessary
-    // allocations.
-    fn split_if_too_large(&mut self) {
-        if self.grapheme_count_ > MAX_NODE_SIZE && self.is_leaf() {
-            
-            // Calculate split position and how large the left and right
-            // sides are going to be
-            let split_pos = self.grapheme_count_ / 2;
-            let new_gc_l = split_pos;
-            let new_gc_r = self.grapheme_count_ - split_pos;
-
-            // Do the split
-            let mut nl = Box::new(Rope::new());
-            let mut nr = Box::new(Rope::new());
-            mem::swap(self, &mut (*nl));
-            if let RopeData::Leaf(ref mut text) = nl.data {
-                nr.data = RopeData::Leaf(split_string_at_grapheme_index(text, split_pos));
-                text.shrink_to_fit();
-            }
-            
-            // Recursively split
-            nl.grapheme_count_ = new_gc_l;
-            nr.grapheme_count_ = new_gc_r;
-            nl.split_if_too_large();
-            nr.split_if_too_large();
-            
-            // Update the new left and right node's stats
-            nl.update_stats();
-            nr.update_stats();
-            
-            // Create the new branch node with the new left and right nodes
-            self.data = RopeData::Branch(nl, nr);
-            self.update_stats();
-        }
+    /// Adds a level to the top of the tree, with the current root and
+    /// the given node (its new right sibling) as the new root's
+    /// children.  Should only be called on the root node.
+    fn grow_root(&mut self, node: Rope) {
+        let config = self.config;
+        let mut children = NodeChildren::new();
+        children.push(mem::take(self));
+        children.push(node);
+        *self = Rope::new_branch(children, config);
     }
-    
-    
-    /// Merges a non-leaf node into a leaf node if it's too small
-    fn merge_if_too_small(&mut self) {
-        if self.grapheme_count_ < MIN_NODE_SIZE && !self.is_leaf() {
-            let mut merged_text = String::new();
-            
-            if let RopeData::Branch(ref mut left, ref mut right) = self.data {
-                // First, recursively merge the children
-                left.merge_if_too_small();
-                right.merge_if_too_small();
-                
-                // Then put their text into merged_text
-                if let RopeData::Leaf(ref mut text) = left.data {
-                    mem::swap(&mut merged_text, text);
-                }        
-                if let RopeData::Leaf(ref mut text) = right.data {
-                    merged_text.push_str(&text[..]);
+
+
+    /// Removes redundant levels from the top of the tree, i.e. branch
+    /// nodes with only a single child.  Should only be called on the
+    /// root node.
+    fn shrink_root(&mut self) {
+        loop {
+            let mut child = None;
+
+            if let RopeData::Branch(ref mut children) = self.data {
+                if children.len() == 1 {
+                    child = Some(children.remove(0));
+                }
+                else if children.is_empty() {
+                    child = Some(Rope::with_config(self.config));
                 }
             }
-            
-            // Make this a leaf node with merged_text as its data
-            self.data = RopeData::Leaf(merged_text);
-            self.tree_height = 1;
-            // Don't need to update grapheme count, because it should be the
-            // same as before.
-        }
-    }
-    
-    
-    /// Rotates the tree under the node left
-    fn rotate_left(&mut self) {
-        let mut temp = Rope::new();
-        
-        if let RopeData::Branch(_, ref mut right) = self.data {
-            mem::swap(&mut temp, &mut (**right));
-            
-            if let RopeData::Branch(ref mut left, _) = temp.data {   
-                mem::swap(&mut (**left), &mut (**right));
+
+            if let Some(child) = child {
+                *self = child;
             }
             else {
-                panic!("Rope::rotate_left(): attempting to rotate node without branching right child.");
+                break;
             }
         }
-        else {
-            panic!("Rope::rotate_left(): attempting to rotate leaf node.");
-        }
-        
-        if let RopeData::Branch(ref mut left, _) = temp.data {
-            mem::swap(&mut (**left), self);
-            left.update_stats();
-        }
-        
-        mem::swap(&mut temp, self);
-        self.update_stats();
     }
-    
-    
-    /// Rotates the tree under the node right
-    fn rotate_right(&mut self) {
-        let mut temp = Rope::new();
-        
-        if let RopeData::Branch(ref mut left, _) = self.data {
-            mem::swap(&mut temp, &mut (**left));
-            
-            if let RopeData::Branch(_, ref mut right) = temp.data {   
-                mem::swap(&mut (**right), &mut (**left));
-            }
-            else {
-                panic!("Rope::rotate_right(): attempting to rotate node without branching left child.");
+
+
+    /// Inserts the given text at the given char index.
+    /// This is done without a seam check because it is recursive and
+    /// would otherwise do a seam check at every recursive function call.
+    /// Rope::insert_text_at_char_index() calls this, and then does the seam
+    /// checks afterwards.
+    fn insert_text_at_char_index_without_seam_check(&mut self, text: &str, pos: usize) {
+        if text.len() <= (self.config.max_leaf_size / 2) {
+            // Small text is inserted directly into a leaf node
+            if let Some(node) = self.insert_recursive(text, pos) {
+                self.grow_root(node);
             }
         }
         else {
-            panic!("Rope::rotate_right(): attempting to rotate leaf node.");
-        }
-        
-        if let RopeData::Branch(_, ref mut right) = temp.data {
-            mem::swap(&mut (**right), self);
-            right.update_stats();
+            // Large text is built into its own rope, and then joined
+            // with the two halves of this one.
+            let right = self.split_at_char_index(pos);
+            let middle = Rope::from_str_with_config(text, self.config);
+            self.append_without_seam_check(middle);
+            self.append_without_seam_check(right);
         }
-        
-        mem::swap(&mut temp, self);
-        self.update_stats();
     }
-    
-    
-    /// Balances the tree under this node.  Assumes that both the left and
-    /// right sub-trees are themselves aleady balanced.
-    /// Runs in time linear to the difference in height between the two
-    /// sub-trees.  Thus worst-case is O(log N) time, and best-case is O(1)
-    /// time.
-    fn rebalance(&mut self) {
-        let mut rot: isize = 0;
-        
-        if let RopeData::Branch(ref mut left, ref mut right) = self.data {
-            let height_diff = (left.tree_height as isize) - (right.tree_height as isize);
-
-            // Left side higher than right side
-            if height_diff > 1 {
-                let mut child_rot = false;
-                if let RopeData::Branch(ref lc, ref rc) = left.data {
-                    if lc.tree_height < rc.tree_height {
-                        child_rot = true;
+
+
+    /// Inserts a small piece of text (no larger than half the maximum
+    /// leaf size) into the leaf node at the given char index.  Returns a new
+    /// right sibling for this node if it had to be split to make room.
+    fn insert_recursive(&mut self, text: &str, pos: usize) -> Option<Rope> {
+        match self.data {
+            RopeData::Leaf(ref mut s_text) => {
+                if (s_text.len() + text.len()) > self.config.max_leaf_size {
+                    // Split the combined text evenly between this leaf and
+                    // a new one, reusing this leaf's buffer so that it
+                    // never has to grow beyond its capacity
+                    let mut combined = String::with_capacity(s_text.len() + text.len());
+                    combined.push_str(s_text);
+                    insert_text_at_char_index(&mut combined, text, pos);
+                    let split_pos = nearest_grapheme_boundary(&combined, combined.len() / 2);
+                    s_text.set(&combined[..split_pos]);
+
+                    // A single grapheme can't be split, so it gets an
+                    // oversized leaf all to itself
+                    if split_pos == combined.len() {
+                        self.update_stats();
+                        return None;
                     }
+
+                    let right = Rope::new_leaf(LeafText::from_str(&combined[split_pos..], self.config), self.config);
+                    self.update_stats();
+                    return Some(right);
                 }
-                
-                if child_rot {
-                    left.rotate_left();
+
+                s_text.insert_at_char_index(text, pos);
+            },
+
+            RopeData::Branch(ref mut children) => {
+                let (i, cc, _, _) = children.search_char_index(pos);
+                if let Some(node) = children.get_mut(i).insert_recursive(text, pos - cc) {
+                    children.insert(i + 1, node);
                 }
-                
-                rot = 1;
-            }
-            // Right side higher then left side
-            else if height_diff < -1 {
-                let mut child_rot = false;
-                if let RopeData::Branch(ref lc, ref rc) = right.data {
-                    if lc.tree_height > rc.tree_height {
-                        child_rot = true;
+            },
+        }
+
+        return self.split_if_overfull();
+    }
+
+
+    /// Removes the text between the given char indices.
+    /// This is done without a seam check so that it can be used inside
+    /// repair_grapheme_seam() without risk of unintended recursion.
+    fn remove_text_between_char_indices_without_seam_check(&mut self, pos_a: usize, pos_b: usize) {
+        // Bounds checks
+        if pos_a > pos_b {
+            panic!("Rope::remove_text_between_char_indices(): pos_a must be less than or equal to pos_b.");
+        }
+        if pos_b > self.char_count_ {
+            panic!("Rope::remove_text_between_char_indices(): attempt to remove text after end of node text.");
+        }
+
+        if pos_a == pos_b {
+            return;
+        }
+
+        self.remove_recursive(pos_a, pos_b);
+        self.shrink_root();
+    }
+
+
+    fn remove_recursive(&mut self, pos_a: usize, pos_b: usize) {
+        match self.data {
+            RopeData::Leaf(ref mut text) => {
+                text.remove_between_char_indices(pos_a, pos_b);
+                self.update_stats();
+            },
+
+            RopeData::Branch(ref mut children) => {
+                let mut i = 0;
+                let mut cc = 0;
+                while i < children.len() && cc < pos_b {
+                    let child_cc = children.get(i).char_count_;
+
+                    if pos_a <= cc && (cc + child_cc) <= pos_b {
+                        // Child is entirely within the removed range
+                        children.remove(i);
                     }
+                    else {
+                        if pos_a < (cc + child_cc) {
+                            // Child is partially within the removed range
+                            let a = max(pos_a, cc) - cc;
+                            let b = min(pos_b, cc + child_cc) - cc;
+                            children.get_mut(i).remove_recursive(a, b);
+                        }
+                        i += 1;
+                    }
+
+                    cc += child_cc;
                 }
-                
-                if child_rot {
-                    right.rotate_right();
-                }
-                
-                rot = -1;
-            }
+
+                self.fix_underfull_children();
+            },
         }
-        
-        if rot == 1 {
-            self.rotate_right();
-            if let RopeData::Branch(_, ref mut right) = self.data {
-                right.rebalance();
-            }
+    }
+
+
+    /// Takes a branch node whose children don't meet the B-tree
+    /// invariants (for example because they are of different heights),
+    /// and rebuilds it by joining its children back together one by one.
+    /// Runs in O(log N) time per child.
+    fn rebalance(&mut self) {
+        if self.is_balanced() {
+            return;
         }
-        else if rot == -1 {
-            self.rotate_left();
-            if let RopeData::Branch(ref mut left, _) = self.data {
-                left.rebalance();
+
+        let config = self.config;
+        let mut nodes = Vec::new();
+        if let RopeData::Branch(ref mut children) = self.data {
+            while !children.is_empty() {
+                nodes.push(children.remove(0));
             }
         }
-        
-        self.update_stats();
+
+        *self = Rope::with_config(config);
+        for node in nodes {
+            self.append_without_seam_check(node);
+        }
     }
-    
-    
+
+
     /// Creates a grapheme iterator startin at the given char index.
     /// If the given char index starts in the middle of a grapheme,
     /// the grapheme is split and the part of the grapheme after the
     /// the char index is returned as the first grapheme.
     fn grapheme_iter_at_char_index<'a>(&'a self, index: usize) -> RopeGraphemeIter<'a> {
         let (char_i, mut chunk_iter) = self.chunk_iter_at_char_index(index);
-        
+
         // Get the chunk string
         if let Some(text) = chunk_iter.next() {
             // Create the grapheme iter for the current node
             let byte_i = char_pos_to_byte_pos(text, index - char_i);
             let giter = UnicodeSegmentation::graphemes(&text[byte_i..], true);
-            
+
             // Create the rope grapheme iter
             return RopeGraphemeIter {
-                chunk_iter: chunk_iter,
+                chunk_iter,
                 cur_chunk: giter,
                 length: None,
             };
@@ -1087,14 +1707,14 @@ impl Rope {
         else {
             // No chunks, which means no text
             return RopeGraphemeIter {
-                chunk_iter: chunk_iter,
+                chunk_iter,
                 cur_chunk: UnicodeSegmentation::graphemes("", true),
                 length: None,
             };
         };
     }
-    
-    
+
+
     /// Returns whether the given char index lies on a leaf node boundary.
     fn is_leaf_boundary(&self, index: usize) -> bool {
         if index == 0 || index == self.char_count_ {
@@ -1105,40 +1725,43 @@ impl Rope {
                 RopeData::Leaf(_) => {
                     return false;
                 },
-                
-                RopeData::Branch(ref left, ref right) => {
-                    if index < left.char_count_ {
-                        return left.is_leaf_boundary(index);
-                    }
-                    else {
-                        return right.is_leaf_boundary(index - left.char_count_);
-                    }
+
+                RopeData::Branch(ref children) => {
+                    let (i, cc, _, _) = children.search_char_index(index);
+                    return children.get(i).is_leaf_boundary(index - cc);
                 }
             }
         }
     }
-    
-    
-    fn append_to_leaf(&mut self, text: &str, index: usize) {
+
+
+    /// Appends text to the end of the leaf node that ends at (or
+    /// contains) the given char index.  Returns a new right sibling for
+    /// this node if it had to be split to make room.
+    fn append_to_leaf(&mut self, text: &str, index: usize) -> Option<Rope> {
         match self.data {
-            RopeData::Leaf(ref mut l_text) => {
-                l_text.push_str(text);
+            RopeData::Leaf(_) => {
+                let cc = self.char_count_;
+                return self.insert_recursive(text, cc);
             },
-            
-            RopeData::Branch(ref mut left, ref mut right) => {
-                if index <= left.char_count_ {
-                    left.append_to_leaf(text, index);
+
+            RopeData::Branch(ref mut children) => {
+                let mut i = 0;
+                let mut cc = 0;
+                while (i + 1) < children.len() && index > (cc + children.get(i).char_count_) {
+                    cc += children.get(i).char_count_;
+                    i += 1;
                 }
-                else {
-                    right.append_to_leaf(text, index - left.char_count_);
+                if let Some(node) = children.get_mut(i).append_to_leaf(text, index - cc) {
+                    children.insert(i + 1, node);
                 }
             }
         }
-        
-        self.update_stats();
+
+        return self.split_if_overfull();
     }
-    
-    
+
+
     /// Repairs an erroneous grapheme separation that can occur at
     /// leaf node boundaries.  The index given is the char index of the
     /// possible seam.
@@ -1146,37 +1769,67 @@ impl Rope {
         if index == 0 || index == self.char_count_ {
             return;
         }
-        
+
         let gi = self.char_index_to_grapheme_index(index);
-        
+
         if self.is_leaf_boundary(index) && graphemes_are_mergeable(self.grapheme_at_index(gi-1), self.grapheme_at_index(gi)) {
             let c1 = self.grapheme_index_to_char_index(gi);
             let c2 = self.grapheme_index_to_char_index(gi + 1);
-            
+
             // Get the grapheme on the right
             let mut s = String::new();
             s.push_str(self.grapheme_at_index(gi));
-            
+
             // Append it to the left
-            self.append_to_leaf(&s[..], index);
-            
+            if let Some(node) = self.append_to_leaf(&s[..], index) {
+                self.grow_root(node);
+            }
+
             // Remove the duplicate
             self.remove_text_between_char_indices_without_seam_check(c2, c2 + (c2 - c1));
         }
     }
-    
-    
-    /// Tests if the rope adheres to the AVL balancing invariants.
+
+
+    /// Replaces the text between the given char indices as a single edit:
+    /// the rope is split around the range, the new text is built into a
+    /// balanced tree of its own, and the pieces are joined again.  Only
+    /// the leaves in the range are rebuilt, and grapheme seams are only
+    /// repaired at the two joins.
+    /// Runs in O(log N) time aside from building the new text.
+    fn rebuild_between_char_indices(&mut self, pos_a: usize, pos_b: usize, text: &str) {
+        let config = self.config;
+        let mut middle = self.split_at_char_index(pos_a);
+        let right = middle.split_at_char_index(pos_b - pos_a);
+        self.append(Rope::from_str_with_config(text, config));
+        self.append(right);
+    }
+
+
+    /// Tests if the rope adheres to the B-tree invariants: all leaves
+    /// are at the same depth, no leaf is larger than the maximum leaf
+    /// size (unless it's a single grapheme), and all branch nodes other
+    /// than the root have between MIN_CHILDREN and MAX_CHILDREN children.
     fn is_balanced(&self) -> bool {
+        return self.is_balanced_recursive(true);
+    }
+
+
+    fn is_balanced_recursive(&self, is_root: bool) -> bool {
         match self.data {
             RopeData::Leaf(_) => {
-                return true;
+                let size_ok = self.byte_count_ <= self.config.max_leaf_size || self.grapheme_count_ == 1;
+                return size_ok && (is_root || self.char_count_ > 0);
             },
-            
-            RopeData::Branch(ref left, ref right) => {
-                let mut diff = left.tree_height as isize - right.tree_height as isize;
-                diff = if diff < 0 {-diff} else {diff};
-                return (diff < 2) && left.is_balanced() && right.is_balanced();
+
+            RopeData::Branch(ref children) => {
+                let min_children = if is_root { 2 } else { MIN_CHILDREN };
+                if children.len() < min_children || children.len() > MAX_CHILDREN {
+                    return false;
+                }
+                return children.iter().all(|child| {
+                    (child.tree_height + 1) == self.tree_height && child.is_balanced_recursive(false)
+                });
             }
         }
     }
@@ -1185,6 +1838,43 @@ impl Rope {
 
 
 
+//=============================================================
+// Rope snapshots
+//=============================================================
+
+/// A read-only snapshot of a rope, which can be shared with other
+/// threads while the original rope continues to be edited.
+///
+/// Creating a snapshot is cheap, since it shares the nodes of the rope
+/// rather than copying them.  Nodes are only copied when the rope edits
+/// them later on.  The full read API of Rope is available on a snapshot
+/// through Deref.
+#[derive(Debug, Clone)]
+pub struct RopeSnapshot {
+    rope: Arc<Rope>,
+}
+
+
+impl RopeSnapshot {
+    /// Makes an editable rope from the snapshot.  This is cheap in the
+    /// same way that creating the snapshot is.
+    pub fn to_rope(&self) -> Rope {
+        return (*self.rope).clone();
+    }
+}
+
+
+impl Deref for RopeSnapshot {
+    type Target = Rope;
+
+    fn deref(&self) -> &Rope {
+        &self.rope
+    }
+}
+
+
+
+
 //=============================================================
 // Rope iterators
 //=============================================================
@@ -1199,24 +1889,20 @@ impl<'a> Iterator for RopeChunkIter<'a> {
     
     fn next(&mut self) -> Option<&'a str> {
         if let Some(next_chunk) = self.node_stack.pop() {
-            loop {
-                if let Option::Some(node) = self.node_stack.pop() {
-                    match node.data {
-                        RopeData::Leaf(_) => {
-                            self.node_stack.push(node);
-                            break;
-                        },
-                      
-                        RopeData::Branch(ref left, ref right) => {
-                            self.node_stack.push(&(**right));
-                            self.node_stack.push(&(**left));
-                            continue;
+            while let Some(node) = self.node_stack.pop() {
+                match node.data {
+                    RopeData::Leaf(_) => {
+                        self.node_stack.push(node);
+                        break;
+                    },
+                  
+                    RopeData::Branch(ref children) => {
+                        for child in children.iter().rev() {
+                            self.node_stack.push(child);
                         }
+                        continue;
                     }
                 }
-                else {
-                    break;
-                }
             }
             
             if let RopeData::Leaf(ref text) = next_chunk.data {
@@ -1457,8 +2143,8 @@ impl<'a> RopeSlice<'a> {
         }
         else {
             // Hard case: partial graphemes
-            let shave_a = if cs < self.start { self.start - cs} else { 0 };
-            let shave_b = if ce > self.end { ce - self.end } else { 0 };
+            let shave_a = self.start.saturating_sub(cs);
+            let shave_b = ce.saturating_sub(self.end);
             
             let cc = char_count(g);
             
@@ -0,0 +1,776 @@
+//! Read-mostly ropes backed by memory-mapped files.
+//!
+//! A MappedRope splits its file into blocks of bytes, which are only
+//! looked at when they are needed.  Opening even a huge file is
+//! instant: the char, grapheme, and line ending counts of the blocks are
+//! computed lazily on first use, and meanwhile by a background scan.
+//! Finding a position in the text, e.g. to edit there, only needs the
+//! counts of the blocks before it.
+//! Edited blocks are copied into ordinary ropes ("overlays"), and the
+//! file itself is never written to.
+
+use std::borrow::Cow;
+use std::cmp::min;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::str;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use memmap2::Mmap;
+use unicode_segmentation::UnicodeSegmentation;
+use string_utils::{
+    char_grapheme_line_ending_count,
+    graphemes_are_mergeable,
+    nearest_grapheme_boundary,
+};
+use super::{Rope, RopeChunkIter, RopeConfig};
+
+
+/// The size of the blocks that a file is split into, in bytes.
+const BLOCK_SIZE: usize = 1 << 18;
+
+/// How far from the target block boundary to look for a grapheme
+/// boundary, in bytes.
+const SEAM_SEARCH_SIZE: usize = 64;
+
+
+/// A read-mostly rope whose text lives in a memory-mapped file.
+///
+/// The file must not be modified by anyone else while it's mapped.
+/// Bytes that aren't valid UTF-8 read as U+FFFD, one block at a time.
+pub struct MappedRope {
+    map: Arc<Mmap>,
+    blocks: Vec<Block>,
+    counts: Arc<Vec<OnceLock<BlockCounts>>>,
+    stop_scan: Arc<AtomicBool>,
+    config: RopeConfig,
+
+    /// The running totals of the counts of the blocks, which only reach
+    /// as far into the text as queries have needed so far.
+    index: Mutex<CountIndex>,
+
+    /// The rope of the mapped block that was last queried, keyed by the
+    /// index of its counts.
+    block_rope_cache: Mutex<Option<(usize, Arc<Rope>)>>,
+}
+
+
+enum Block {
+    /// A range of bytes of the file, along with the index of its lazily
+    /// computed counts.
+    Mapped { start: usize, end: usize, counts_index: usize },
+
+    /// A block that has been edited, held in memory.
+    Overlay(Box<Rope>),
+}
+
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BlockCounts {
+    pub char_count: usize,
+    pub grapheme_count: usize,
+    pub line_ending_count: usize,
+}
+
+
+impl BlockCounts {
+    fn plus(self, other: BlockCounts) -> BlockCounts {
+        BlockCounts {
+            char_count: self.char_count + other.char_count,
+            grapheme_count: self.grapheme_count + other.grapheme_count,
+            line_ending_count: self.line_ending_count + other.line_ending_count,
+        }
+    }
+
+    fn minus(self, other: BlockCounts) -> BlockCounts {
+        BlockCounts {
+            char_count: self.char_count - other.char_count,
+            grapheme_count: self.grapheme_count - other.grapheme_count,
+            line_ending_count: self.line_ending_count - other.line_ending_count,
+        }
+    }
+}
+
+
+/// The running totals of the counts of the first blocks of a mapped
+/// rope, as a Fenwick tree.  It's extended a block at a time as queries
+/// reach further into the text, so that finding a char or line only
+/// ever counts the blocks before it, and takes O(log N) time in the
+/// number of blocks once they're counted.
+#[derive(Default)]
+pub struct CountIndex {
+    tree: Vec<BlockCounts>,
+}
+
+
+impl CountIndex {
+    /// Returns the number of blocks in the index.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+
+    /// Returns the total counts of the first k blocks.
+    pub fn prefix(&self, k: usize) -> BlockCounts {
+        let mut total = BlockCounts::default();
+        let mut k = k;
+        while k > 0 {
+            total = total.plus(self.tree[k - 1]);
+            k &= k - 1;
+        }
+        return total;
+    }
+
+
+    /// Adds the counts of the next block.
+    pub fn push(&mut self, counts: BlockCounts) {
+        let k = self.tree.len() + 1;
+        let covered = self.prefix(k - 1).minus(self.prefix(k - (k & k.wrapping_neg())));
+        self.tree.push(covered.plus(counts));
+    }
+
+
+    /// Changes the counts of a block in the index.
+    pub fn update(&mut self, i: usize, old: BlockCounts, new: BlockCounts) {
+        let mut k = i + 1;
+        while k <= self.tree.len() {
+            self.tree[k - 1] = self.tree[k - 1].minus(old).plus(new);
+            k += k & k.wrapping_neg();
+        }
+    }
+
+
+    /// Drops the blocks from the given one on from the index, e.g. when
+    /// blocks are removed or merged.
+    pub fn truncate(&mut self, len: usize) {
+        self.tree.truncate(len);
+    }
+
+
+    /// Returns the largest number of first blocks whose total counts pass
+    /// the given test, along with those totals.  The test must pass for
+    /// all totals up to some point and then fail.
+    pub fn search<F: Fn(&BlockCounts) -> bool>(&self, f: F) -> (usize, BlockCounts) {
+        let mut k = 0;
+        let mut total = BlockCounts::default();
+        let mut step = if self.tree.is_empty() { 0 } else { 1 << (usize::BITS - 1 - self.tree.len().leading_zeros()) };
+        while step > 0 {
+            if k + step <= self.tree.len() {
+                let next = total.plus(self.tree[k + step - 1]);
+                if f(&next) {
+                    k += step;
+                    total = next;
+                }
+            }
+            step >>= 1;
+        }
+        return (k, total);
+    }
+}
+
+
+impl MappedRope {
+    /// Opens a file as a rope.  Runs in O(1) time in the size of the
+    /// file, apart from splitting it into blocks.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MappedRope> {
+        MappedRope::open_with_config(path, RopeConfig::default())
+    }
+
+
+    /// Opens a file as a rope, with the given leaf size configuration
+    /// for the ropes of edited blocks.
+    pub fn open_with_config<P: AsRef<Path>>(path: P, config: RopeConfig) -> io::Result<MappedRope> {
+        MappedRope::open_with_block_size(path.as_ref(), config, BLOCK_SIZE)
+    }
+
+
+    fn open_with_block_size(path: &Path, config: RopeConfig, block_size: usize) -> io::Result<MappedRope> {
+        let file = File::open(path)?;
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+
+        let ranges = split_into_blocks(&map[..], block_size);
+        let counts: Arc<Vec<OnceLock<BlockCounts>>> = Arc::new(ranges.iter().map(|_| OnceLock::new()).collect());
+        let stop_scan = Arc::new(AtomicBool::new(false));
+
+        // Count the blocks in the background, so that they're usually
+        // ready by the time they're needed
+        {
+            let map = map.clone();
+            let counts = counts.clone();
+            let stop_scan = stop_scan.clone();
+            let ranges = ranges.clone();
+            thread::spawn(move || {
+                for (i, &(start, end)) in ranges.iter().enumerate() {
+                    if stop_scan.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    counts[i].get_or_init(|| count_block(&map[start..end]));
+                }
+            });
+        }
+
+        let blocks = ranges.iter().enumerate().map(|(i, &(start, end))| {
+            Block::Mapped { start, end, counts_index: i }
+        }).collect();
+
+        Ok(MappedRope {
+            map,
+            blocks,
+            counts,
+            stop_scan,
+            config,
+            index: Mutex::new(CountIndex::default()),
+            block_rope_cache: Mutex::new(None),
+        })
+    }
+
+
+    /// Returns whether the counts of all of the file's blocks have been
+    /// computed yet.
+    pub fn is_fully_counted(&self) -> bool {
+        self.counts.iter().all(|counts| counts.get().is_some())
+    }
+
+
+    /// Returns the number of chars in the rope.  This needs the counts of
+    /// all of the blocks.
+    pub fn char_count(&self) -> usize {
+        self.total_counts().char_count
+    }
+
+    pub fn grapheme_count(&self) -> usize {
+        self.total_counts().grapheme_count
+    }
+
+    pub fn line_ending_count(&self) -> usize {
+        self.total_counts().line_ending_count
+    }
+
+
+    /// Returns the index of the line that the given char index is on.
+    pub fn char_index_to_line_index(&self, pos: usize) -> usize {
+        if self.blocks.is_empty() {
+            assert!(pos == 0, "MappedRope::char_index_to_line_index(): attempted to index beyond the end of the text.");
+            return 0;
+        }
+
+        let found = self.search_char_index(pos);
+        assert!(found.is_some(), "MappedRope::char_index_to_line_index(): attempted to index beyond the end of the text.");
+        let (i, cc, lec) = found.unwrap();
+        return lec + self.with_block_rope(i, |rope| rope.char_index_to_line_index(pos - cc));
+    }
+
+
+    /// Returns the char index at the start of the given line index.
+    pub fn line_index_to_char_index(&self, li: usize) -> usize {
+        if li == 0 {
+            return 0;
+        }
+
+        let found = self.search_line_index(li);
+        assert!(found.is_some(), "MappedRope::line_index_to_char_index(): attempted to index beyond the end of the text.");
+        let (i, cc, lec) = found.unwrap();
+        return cc + self.with_block_rope(i, |rope| rope.line_index_to_char_index(li - lec));
+    }
+
+
+    /// Copies the text between the given char indices into a string.
+    pub fn slice_to_string(&self, pos_a: usize, pos_b: usize) -> String {
+        assert!(pos_a <= pos_b, "MappedRope::slice_to_string(): pos_a must be less than or equal to pos_b.");
+
+        let mut text = String::new();
+        if self.blocks.is_empty() {
+            assert!(pos_b == 0, "MappedRope::slice_to_string(): attempted to slice beyond the end of the text.");
+            return text;
+        }
+        let found = self.search_char_index(pos_b);
+        assert!(found.is_some(), "MappedRope::slice_to_string(): attempted to slice beyond the end of the text.");
+        let (ib, _, _) = found.unwrap();
+        let (ia, mut cc, _) = self.search_char_index(pos_a).unwrap();
+
+        for i in ia..(ib + 1) {
+            let block_cc = self.block_counts(i).char_count;
+            let a = pos_a.saturating_sub(cc);
+            let b = min(pos_b - cc, block_cc);
+            if a == 0 && b == block_cc {
+                match self.blocks[i] {
+                    Block::Mapped { start, end, .. } => text.push_str(&self.mapped_text(start, end)[..]),
+                    Block::Overlay(ref rope) => text.extend(rope.chunk_iter()),
+                }
+            }
+            else if a < b {
+                self.with_block_rope(i, |rope| text.extend(rope.slice(a, b).char_iter()));
+            }
+            cc += block_cc;
+        }
+
+        return text;
+    }
+
+
+    /// Creates an iterator over the rope's text, in chunks.
+    pub fn chunk_iter(&self) -> MappedChunkIter<'_> {
+        MappedChunkIter {
+            rope: self,
+            block_index: 0,
+            overlay_iter: None,
+        }
+    }
+
+
+    /// Writes the rope's text to a writer.
+    pub fn write_to<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = writer;
+        for chunk in self.chunk_iter() {
+            writer.write_all(chunk.as_bytes())?;
+        }
+        return Ok(());
+    }
+
+
+    /// Copies the whole text into an ordinary rope.
+    pub fn to_rope(&self) -> Rope {
+        let mut rope = Rope::with_config(self.config);
+        for i in 0..self.blocks.len() {
+            rope.append(self.block_rope(i));
+        }
+        return rope;
+    }
+
+
+    /// Inserts the given text at the given char index.  The block that
+    /// it's inserted into is copied into memory.  Only the blocks before
+    /// it have to be counted.
+    pub fn insert_text_at_char_index(&mut self, text: &str, pos: usize) {
+        if self.blocks.is_empty() {
+            assert!(pos == 0, "MappedRope::insert_text_at_char_index(): attempted to insert text at a position beyond the end of the text.");
+            self.blocks.push(Block::Overlay(Box::new(Rope::from_str_with_config(text, self.config))));
+            return;
+        }
+
+        let found = self.search_char_index(pos);
+        assert!(found.is_some(), "MappedRope::insert_text_at_char_index(): attempted to insert text at a position beyond the end of the text.");
+        let (i, cc, _) = found.unwrap();
+        let old = self.block_counts(i);
+        self.overlay(i).insert_text_at_char_index(text, pos - cc);
+        self.update_index(i, old);
+
+        self.repair_seam(i + 1);
+        self.repair_seam(i);
+    }
+
+
+    /// Removes the text between the given char indices.  The blocks that
+    /// the ends of the range are in are copied into memory, and those in
+    /// between are dropped.  Only the blocks up to the end of the range
+    /// have to be counted.
+    pub fn remove_text_between_char_indices(&mut self, pos_a: usize, pos_b: usize) {
+        assert!(pos_a <= pos_b, "MappedRope::remove_text_between_char_indices(): pos_a must be less than or equal to pos_b.");
+
+        if self.blocks.is_empty() {
+            assert!(pos_b == 0, "MappedRope::remove_text_between_char_indices(): attempted to remove text beyond the end of the text.");
+            return;
+        }
+        let found = self.search_char_index(pos_b);
+        assert!(found.is_some(), "MappedRope::remove_text_between_char_indices(): attempted to remove text beyond the end of the text.");
+
+        if pos_a == pos_b {
+            return;
+        }
+
+        let (ia, cca, _) = self.search_char_index(pos_a).unwrap();
+        let (ib, ccb, _) = found.unwrap();
+
+        if ia == ib {
+            let old = self.block_counts(ia);
+            self.overlay(ia).remove_text_between_char_indices(pos_a - cca, pos_b - cca);
+            self.update_index(ia, old);
+        }
+        else {
+            let end = self.block_counts(ia).char_count;
+            self.overlay(ia).remove_text_between_char_indices(pos_a - cca, end);
+            self.overlay(ib).remove_text_between_char_indices(0, pos_b - ccb);
+            self.blocks.drain((ia + 1)..ib);
+            self.index.get_mut().unwrap().truncate(ia);
+        }
+
+        // Drop blocks that are now empty, and repair the seam where the
+        // text was removed
+        let mut i = ia;
+        while i < self.blocks.len() && i <= (ia + 1) {
+            if self.block_counts(i).char_count == 0 {
+                self.blocks.remove(i);
+                self.index.get_mut().unwrap().truncate(i);
+            }
+            else {
+                i += 1;
+            }
+        }
+        self.repair_seam(ia + 1);
+        self.repair_seam(ia);
+    }
+
+
+    //================================================================
+    // Private utility functions
+    //================================================================
+
+    fn block_counts(&self, i: usize) -> BlockCounts {
+        match self.blocks[i] {
+            Block::Mapped { start, end, counts_index } => {
+                *self.counts[counts_index].get_or_init(|| count_block(&self.map[start..end]))
+            },
+
+            Block::Overlay(ref rope) => {
+                BlockCounts {
+                    char_count: rope.char_count(),
+                    grapheme_count: rope.grapheme_count(),
+                    line_ending_count: rope.line_ending_count(),
+                }
+            },
+        }
+    }
+
+
+    /// Returns the total counts of all of the blocks.
+    fn total_counts(&self) -> BlockCounts {
+        let mut index = self.index.lock().unwrap();
+        self.extend_index(&mut index, |_| false);
+        return index.prefix(index.len());
+    }
+
+
+    /// Counts more blocks into the index, until the total counts of the
+    /// blocks in it pass the given test or all of the blocks are in it.
+    fn extend_index<F: Fn(&BlockCounts) -> bool>(&self, index: &mut CountIndex, done: F) {
+        let mut total = index.prefix(index.len());
+        while index.len() < self.blocks.len() && !done(&total) {
+            let counts = self.block_counts(index.len());
+            index.push(counts);
+            total = total.plus(counts);
+        }
+    }
+
+
+    /// Updates the index for a block whose text was edited, given its
+    /// counts from before the edit.
+    fn update_index(&mut self, i: usize, old: BlockCounts) {
+        let new = self.block_counts(i);
+        let index = self.index.get_mut().unwrap();
+        if i < index.len() {
+            index.update(i, old, new);
+        }
+    }
+
+
+    /// Returns the text of a mapped block.
+    fn mapped_text(&self, start: usize, end: usize) -> Cow<'_, str> {
+        block_text(&self.map[start..end])
+    }
+
+
+    /// Copies a block into a new rope.
+    fn block_rope(&self, i: usize) -> Rope {
+        match self.blocks[i] {
+            Block::Mapped { start, end, .. } => Rope::from_str_with_config(&self.mapped_text(start, end)[..], self.config),
+            Block::Overlay(ref rope) => (**rope).clone(),
+        }
+    }
+
+
+    /// Calls the given function with a rope of a block's text.  For mapped
+    /// blocks that's a rope that's kept around until another mapped block
+    /// is queried, so that queries near each other don't each rebuild it.
+    fn with_block_rope<T, F: FnOnce(&Rope) -> T>(&self, i: usize, f: F) -> T {
+        match self.blocks[i] {
+            Block::Mapped { counts_index, .. } => {
+                let rope = {
+                    let mut cache = self.block_rope_cache.lock().unwrap();
+                    match *cache {
+                        Some((cached_index, ref rope)) if cached_index == counts_index => rope.clone(),
+                        _ => {
+                            let rope = Arc::new(self.block_rope(i));
+                            *cache = Some((counts_index, rope.clone()));
+                            rope
+                        },
+                    }
+                };
+                f(&rope)
+            },
+            Block::Overlay(ref rope) => f(rope),
+        }
+    }
+
+
+    /// Returns the rope of a block, copying the block into memory first
+    /// if it's still mapped.
+    fn overlay(&mut self, i: usize) -> &mut Rope {
+        if let Block::Mapped { .. } = self.blocks[i] {
+            self.blocks[i] = Block::Overlay(Box::new(self.block_rope(i)));
+        }
+
+        match self.blocks[i] {
+            Block::Overlay(ref mut rope) => rope,
+            Block::Mapped { .. } => unreachable!(),
+        }
+    }
+
+
+    /// Finds the block that contains the given char index.  Returns the
+    /// index of the block along with the total char and line ending
+    /// counts of the blocks before it, or None if the char index is past
+    /// the end of the text.  A char index on the boundary between two
+    /// blocks belongs to the block on the right, except at the very end
+    /// where it belongs to the last block.
+    fn search_char_index(&self, pos: usize) -> Option<(usize, usize, usize)> {
+        let mut index = self.index.lock().unwrap();
+        self.extend_index(&mut index, |total| total.char_count > pos);
+
+        let (i, before) = index.search(|total| total.char_count <= pos);
+        if i < index.len() {
+            return Some((i, before.char_count, before.line_ending_count));
+        }
+        else if pos == before.char_count && i > 0 {
+            let before = index.prefix(i - 1);
+            return Some((i - 1, before.char_count, before.line_ending_count));
+        }
+        return None;
+    }
+
+
+    /// Finds the block that contains the line ending before the given
+    /// line index, which must be greater than zero.  Returns the same as
+    /// search_char_index().
+    fn search_line_index(&self, li: usize) -> Option<(usize, usize, usize)> {
+        let mut index = self.index.lock().unwrap();
+        self.extend_index(&mut index, |total| total.line_ending_count >= li);
+
+        let (i, before) = index.search(|total| total.line_ending_count < li);
+        if i < index.len() {
+            return Some((i, before.char_count, before.line_ending_count));
+        }
+        return None;
+    }
+
+
+    /// Repairs an erroneous grapheme separation between the block with
+    /// the given index and the one before it, by merging the two.
+    fn repair_seam(&mut self, i: usize) {
+        if i == 0 || i >= self.blocks.len() {
+            return;
+        }
+
+        let mergeable = {
+            let left = self.edge_grapheme(i - 1, false);
+            let right = self.edge_grapheme(i, true);
+            graphemes_are_mergeable(&left[..], &right[..])
+        };
+
+        if mergeable {
+            let right = self.block_rope(i);
+            self.blocks.remove(i);
+            self.overlay(i - 1).append(right);
+            self.index.get_mut().unwrap().truncate(i - 1);
+        }
+    }
+
+
+    /// Returns the first or last grapheme of a block.
+    fn edge_grapheme(&self, i: usize, first: bool) -> String {
+        match self.blocks[i] {
+            Block::Mapped { start, end, .. } => edge_grapheme_of_bytes(&self.map[start..end], first),
+            Block::Overlay(ref rope) => {
+                let gc = rope.grapheme_count();
+                if gc == 0 {
+                    return String::new();
+                }
+                rope.grapheme_at_index(if first { 0 } else { gc - 1 }).to_string()
+            },
+        }
+    }
+}
+
+
+/// Internals for the tests in tests.rs.
+#[cfg(test)]
+impl MappedRope {
+    pub fn open_with_small_blocks(path: &Path, block_size: usize) -> io::Result<MappedRope> {
+        MappedRope::open_with_block_size(path, RopeConfig::default(), block_size)
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn indexed_block_count(&self) -> usize {
+        self.index.lock().unwrap().len()
+    }
+
+    /// Returns whether the block that contains the given char index is a
+    /// mapped block whose rope is the cached one.
+    pub fn is_block_rope_cached_at(&self, pos: usize) -> bool {
+        let (i, _, _) = self.search_char_index(pos).unwrap();
+        match self.blocks[i] {
+            Block::Mapped { counts_index, .. } => {
+                self.block_rope_cache.lock().unwrap().as_ref().is_some_and(|&(cached, _)| cached == counts_index)
+            },
+            Block::Overlay(_) => false,
+        }
+    }
+}
+
+
+impl Drop for MappedRope {
+    fn drop(&mut self) {
+        self.stop_scan.store(true, Ordering::Relaxed);
+    }
+}
+
+
+/// An iterator over a mapped rope's text, in chunks
+pub struct MappedChunkIter<'a> {
+    rope: &'a MappedRope,
+    block_index: usize,
+    overlay_iter: Option<RopeChunkIter<'a>>,
+}
+
+impl<'a> Iterator for MappedChunkIter<'a> {
+    type Item = Cow<'a, str>;
+
+    fn next(&mut self) -> Option<Cow<'a, str>> {
+        loop {
+            if let Some(ref mut iter) = self.overlay_iter {
+                if let Some(chunk) = iter.next() {
+                    return Some(Cow::Borrowed(chunk));
+                }
+            }
+            self.overlay_iter = None;
+
+            if self.block_index >= self.rope.blocks.len() {
+                return None;
+            }
+            let i = self.block_index;
+            self.block_index += 1;
+
+            match self.rope.blocks[i] {
+                Block::Mapped { start, end, .. } => {
+                    return Some(self.rope.mapped_text(start, end));
+                },
+                Block::Overlay(ref rope) => {
+                    self.overlay_iter = Some(rope.chunk_iter());
+                },
+            }
+        }
+    }
+}
+
+
+/// Decodes the text of a block, replacing invalid UTF-8 with U+FFFD.
+fn block_text(bytes: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(bytes)
+}
+
+
+fn count_block(bytes: &[u8]) -> BlockCounts {
+    let (char_count, grapheme_count, line_ending_count) = char_grapheme_line_ending_count(&block_text(bytes)[..]);
+    BlockCounts {
+        char_count,
+        grapheme_count,
+        line_ending_count,
+    }
+}
+
+
+/// Returns the first or last grapheme of a block's text, only decoding
+/// as much of the block as it takes to find it.
+pub fn edge_grapheme_of_bytes(bytes: &[u8], first: bool) -> String {
+    let mut size = SEAM_SEARCH_SIZE;
+    loop {
+        // Look at a window at the edge, ending at a char boundary
+        let window = if first {
+            let mut end = min(size, bytes.len());
+            while end < bytes.len() && (bytes[end] & 0xC0) == 0x80 {
+                end += 1;
+            }
+            &bytes[..end]
+        }
+        else {
+            let mut start = bytes.len() - min(size, bytes.len());
+            while start > 0 && (bytes[start] & 0xC0) == 0x80 {
+                start -= 1;
+            }
+            &bytes[start..]
+        };
+
+        // A grapheme that fills the window may continue past it
+        let text = block_text(window);
+        let mut graphemes = UnicodeSegmentation::graphemes(&text[..], true);
+        let grapheme = if first { graphemes.next() } else { graphemes.next_back() }.unwrap_or("");
+        if grapheme.len() < text.len() || window.len() == bytes.len() {
+            return grapheme.to_string();
+        }
+        size *= 2;
+    }
+}
+
+
+/// Splits the bytes of a file into blocks of roughly the given size,
+/// returned as byte ranges.  This only looks at the bytes near the
+/// boundaries between blocks, which are placed at grapheme boundaries as
+/// well as can be determined from there.
+pub fn split_into_blocks(bytes: &[u8], block_size: usize) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+
+    let mut start = 0;
+    while (bytes.len() - start) > block_size {
+        let end = find_seam(bytes, start + block_size, start);
+        ranges.push((start, end));
+        start = end;
+    }
+    if start < bytes.len() {
+        ranges.push((start, bytes.len()));
+    }
+
+    return ranges;
+}
+
+
+/// Finds a place near the given byte index to split a file between two
+/// blocks, after min_pos.
+fn find_seam(bytes: &[u8], pos: usize, min_pos: usize) -> usize {
+    // There is always a grapheme boundary between two ASCII chars, other
+    // than in CRLF
+    let mut i = pos;
+    while i > (min_pos + 1) && (pos - i) < SEAM_SEARCH_SIZE {
+        if bytes[i - 1] < 0x80 && bytes[i] < 0x80 && !(bytes[i - 1] == 0x0D && bytes[i] == 0x0A) {
+            return i;
+        }
+        i -= 1;
+    }
+
+    // Otherwise segment the text around the target position
+    let mut window_start = pos.saturating_sub(SEAM_SEARCH_SIZE).max(min_pos);
+    let mut window_end = (pos + SEAM_SEARCH_SIZE).min(bytes.len());
+    while window_start < pos && (bytes[window_start] & 0xC0) == 0x80 {
+        window_start += 1;
+    }
+    while window_end > pos && window_end < bytes.len() && (bytes[window_end] & 0xC0) == 0x80 {
+        window_end -= 1;
+    }
+    if let Ok(window) = str::from_utf8(&bytes[window_start..window_end]) {
+        let boundary = window_start + nearest_grapheme_boundary(window, pos - window_start);
+        if boundary > min_pos && boundary < bytes.len() {
+            return boundary;
+        }
+    }
+
+    // Failing that (e.g. for invalid UTF-8), split at a char boundary
+    let mut i = pos;
+    while i > (min_pos + 1) && (bytes[i] & 0xC0) == 0x80 {
+        i -= 1;
+    }
+    return i;
+}
@@ -0,0 +1,281 @@
+//! Three-way merges of ropes.
+
+use std::cmp::max;
+use std::ops::Range;
+use diff::{DiffKind, diff_slices, line_range_to_char_range, MAX_CHAR_DIFF_SIZE};
+use string_utils::is_line_ending;
+use super::Rope;
+
+
+/// Options for Rope::merge().
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MergeOptions {
+    /// Write both versions of each conflicting region into the merged
+    /// rope, between "<<<<<<< ours", "=======" and ">>>>>>> theirs"
+    /// lines.  Otherwise the conflicting regions keep our version.
+    pub conflict_markers: bool,
+}
+
+
+/// The result of Rope::merge().
+#[derive(Debug, Clone)]
+pub struct RopeMerge {
+    pub rope: Rope,
+
+    /// The char ranges of the conflicting regions in the merged rope,
+    /// including the conflict markers if they were written.
+    pub conflicts: Vec<Range<usize>>,
+}
+
+
+impl RopeMerge {
+    pub fn has_conflicts(&self) -> bool {
+        !self.conflicts.is_empty()
+    }
+}
+
+
+impl Rope {
+    /// Merges the changes that two versions of a rope made to a common
+    /// base version of it.
+    ///
+    /// Changes to different lines are both kept.  Changes to the same
+    /// lines are merged by char if they don't overlap there either, and
+    /// are conflicts otherwise, unless both made the same change.  The
+    /// merged rope is built by editing a clone of ours, so it shares the
+    /// unchanged parts of its structure.
+    pub fn merge(base: &Rope, ours: &Rope, theirs: &Rope, options: MergeOptions) -> RopeMerge {
+        let our_hunks = hunks(base.diff(ours).lines.iter().map(|op| (op.kind, op.old.len(), op.new.len())));
+        let their_hunks = hunks(base.diff(theirs).lines.iter().map(|op| (op.kind, op.old.len(), op.new.len())));
+        let regions = merge_hunks(&our_hunks[..], &their_hunks[..]);
+
+        // Find the replacement text of each region, as (char range in
+        // ours, replacement, is conflict).  None keeps our text.
+        let mut edits: Vec<(Range<usize>, Option<String>, bool)> = Vec::new();
+        for region in regions.iter() {
+            let base_chars = line_range_to_char_range(base, &region.base);
+            let our_chars = line_range_to_char_range(ours, &region.ours);
+            let their_chars = line_range_to_char_range(theirs, &region.theirs);
+
+            if !region.changed_by_theirs {
+                continue;
+            }
+            if !region.changed_by_ours {
+                let text = theirs.char_iter_between_indices(their_chars.start, their_chars.end).collect();
+                edits.push((our_chars, Some(text), false));
+                continue;
+            }
+
+            // Both changed these lines, maybe in the same way
+            let our_iter = ours.char_iter_between_indices(our_chars.start, our_chars.end);
+            if our_iter.eq(theirs.char_iter_between_indices(their_chars.start, their_chars.end)) {
+                continue;
+            }
+
+            // Or in different places of them
+            let size = base_chars.len() + our_chars.len() + their_chars.len();
+            if size <= MAX_CHAR_DIFF_SIZE {
+                let base_text: Vec<char> = base.char_iter_between_indices(base_chars.start, base_chars.end).collect();
+                let our_text: Vec<char> = ours.char_iter_between_indices(our_chars.start, our_chars.end).collect();
+                let their_text: Vec<char> = theirs.char_iter_between_indices(their_chars.start, their_chars.end).collect();
+                if let Some(text) = merge_chars(&base_text[..], &our_text[..], &their_text[..]) {
+                    if text.chars().ne(our_text.iter().cloned()) {
+                        edits.push((our_chars, Some(text), false));
+                    }
+                    continue;
+                }
+            }
+
+            if options.conflict_markers {
+                let mut text = String::from("<<<<<<< ours\u{000A}");
+                push_lines(&mut text, ours, &our_chars);
+                text.push_str("=======\u{000A}");
+                push_lines(&mut text, theirs, &their_chars);
+                text.push_str(">>>>>>> theirs\u{000A}");
+                edits.push((our_chars, Some(text), true));
+            }
+            else {
+                edits.push((our_chars, None, true));
+            }
+        }
+
+        // Edit from the end, so that the earlier char ranges stay valid
+        let mut rope = ours.clone();
+        for (range, text, _) in edits.iter().rev() {
+            if let Some(text) = text {
+                rope.remove_text_between_char_indices(range.start, range.end);
+                rope.insert_text_at_char_index(&text[..], range.start);
+            }
+        }
+
+        // And find where the conflicts ended up
+        let mut conflicts = Vec::new();
+        let mut shift = 0isize;
+        for &(ref range, ref text, is_conflict) in edits.iter() {
+            let start = (range.start as isize + shift) as usize;
+            let len = match *text {
+                Some(ref text) => text.chars().count(),
+                None => range.len(),
+            };
+            if is_conflict {
+                conflicts.push(start..(start + len));
+            }
+            shift += len as isize - range.len() as isize;
+        }
+
+        return RopeMerge {
+            rope,
+            conflicts,
+        };
+    }
+}
+
+
+/// A run of changes that one side made to the base.
+struct Hunk {
+    base: Range<usize>,
+    side: Range<usize>,
+}
+
+
+/// A region of the base that was changed by at least one side, with the
+/// corresponding ranges in both sides.
+struct Region {
+    base: Range<usize>,
+    ours: Range<usize>,
+    theirs: Range<usize>,
+    changed_by_ours: bool,
+    changed_by_theirs: bool,
+}
+
+
+/// Groups the steps of an edit script into hunks.
+fn hunks<I: Iterator<Item = (DiffKind, usize, usize)>>(steps: I) -> Vec<Hunk> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    let mut base_pos = 0;
+    let mut side_pos = 0;
+    let mut in_hunk = false;
+
+    for (kind, old_len, new_len) in steps {
+        if old_len == 0 && new_len == 0 {
+            continue;
+        }
+        if kind == DiffKind::Equal {
+            in_hunk = false;
+        }
+        else if in_hunk {
+            let hunk = hunks.last_mut().unwrap();
+            hunk.base.end += old_len;
+            hunk.side.end += new_len;
+        }
+        else {
+            hunks.push(Hunk {
+                base: base_pos..(base_pos + old_len),
+                side: side_pos..(side_pos + new_len),
+            });
+            in_hunk = true;
+        }
+        base_pos += old_len;
+        side_pos += new_len;
+    }
+
+    return hunks;
+}
+
+
+/// Combines the hunks of both sides into regions.  Hunks that overlap or
+/// touch in the base end up in the same region.
+fn merge_hunks(ours: &[Hunk], theirs: &[Hunk]) -> Vec<Region> {
+    let mut regions = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let (mut our_shift, mut their_shift) = (0isize, 0isize);
+
+    while i < ours.len() || j < theirs.len() {
+        let (i_start, j_start) = (i, j);
+        let mut base = if j >= theirs.len() || (i < ours.len() && ours[i].base.start <= theirs[j].base.start) {
+            i += 1;
+            ours[i - 1].base.clone()
+        }
+        else {
+            j += 1;
+            theirs[j - 1].base.clone()
+        };
+        loop {
+            if i < ours.len() && ours[i].base.start <= base.end {
+                base.end = max(base.end, ours[i].base.end);
+                i += 1;
+            }
+            else if j < theirs.len() && theirs[j].base.start <= base.end {
+                base.end = max(base.end, theirs[j].base.end);
+                j += 1;
+            }
+            else {
+                break;
+            }
+        }
+
+        let our_growth: isize = ours[i_start..i].iter().map(|h| h.side.len() as isize - h.base.len() as isize).sum();
+        let their_growth: isize = theirs[j_start..j].iter().map(|h| h.side.len() as isize - h.base.len() as isize).sum();
+        regions.push(Region {
+            ours: shift_range(&base, our_shift, our_growth),
+            theirs: shift_range(&base, their_shift, their_growth),
+            base,
+            changed_by_ours: i > i_start,
+            changed_by_theirs: j > j_start,
+        });
+        our_shift += our_growth;
+        their_shift += their_growth;
+    }
+
+    return regions;
+}
+
+
+fn shift_range(range: &Range<usize>, shift: isize, growth: isize) -> Range<usize> {
+    let start = range.start as isize + shift;
+    let end = range.end as isize + shift + growth;
+    return (start as usize)..(end as usize);
+}
+
+
+/// Merges the changes to a slice of chars, or returns None if they
+/// conflict.
+fn merge_chars(base: &[char], ours: &[char], theirs: &[char]) -> Option<String> {
+    let our_hunks = hunks(diff_slices(base, ours).into_iter());
+    let their_hunks = hunks(diff_slices(base, theirs).into_iter());
+
+    let mut text = String::new();
+    let mut pos = 0;
+    for region in merge_hunks(&our_hunks[..], &their_hunks[..]) {
+        text.extend(base[pos..region.base.start].iter());
+        let our_text = &ours[region.ours.clone()];
+        let their_text = &theirs[region.theirs.clone()];
+        if !region.changed_by_theirs || our_text == their_text {
+            text.extend(our_text.iter());
+        }
+        else if !region.changed_by_ours {
+            text.extend(their_text.iter());
+        }
+        else {
+            return None;
+        }
+        pos = region.base.end;
+    }
+    text.extend(base[pos..].iter());
+
+    return Some(text);
+}
+
+
+/// Appends a range of a rope's text, ending it with a line ending if it
+/// doesn't already end with one.
+fn push_lines(text: &mut String, rope: &Rope, chars: &Range<usize>) {
+    if chars.is_empty() {
+        return;
+    }
+    text.extend(rope.char_iter_between_indices(chars.start, chars.end));
+    let last = rope.grapheme_at_index(rope.char_index_to_grapheme_index(chars.end) - 1);
+    if !is_line_ending(last) {
+        text.push('\u{000A}');
+    }
+}
@@ -0,0 +1,244 @@
+//! The child array of a branch node in the rope's B-tree.
+
+use std::slice;
+use std::sync::{Arc, OnceLock};
+use super::{Rope, MAX_CHILDREN};
+
+
+/// A fixed-capacity array of child nodes, stored inline in its branch
+/// node rather than in a separate heap allocation.
+///
+/// There is room for one child more than MAX_CHILDREN, so that a node
+/// can temporarily overflow during an edit before being split.
+///
+/// The children are reference counted, so that cloning the array is
+/// cheap and the children can be shared between ropes (for example with
+/// snapshots).  Shared children are copied on write by get_mut().
+#[derive(Debug, Clone)]
+pub struct NodeChildren {
+    nodes: [Option<Arc<Rope>>; MAX_CHILDREN + 1],
+    len: usize,
+}
+
+
+impl NodeChildren {
+    pub fn new() -> NodeChildren {
+        NodeChildren {
+            nodes: Default::default(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> &Rope {
+        assert!(index < self.len, "NodeChildren::get(): attempted to access a child beyond the end of the array.");
+        self.nodes[index].as_ref().unwrap()
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> &mut Rope {
+        assert!(index < self.len, "NodeChildren::get_mut(): attempted to access a child beyond the end of the array.");
+        let node = Arc::make_mut(self.nodes[index].as_mut().unwrap());
+
+        // The node is about to be modified, so its hash is stale
+        node.content_hash_ = OnceLock::new();
+        node.bracket_summary_ = OnceLock::new();
+        node.lf_count_ = OnceLock::new();
+        return node;
+    }
+
+    pub fn iter(&self) -> NodeChildrenIter<'_> {
+        NodeChildrenIter {
+            iter: self.nodes[..self.len].iter(),
+        }
+    }
+
+    pub fn push(&mut self, node: Rope) {
+        let len = self.len;
+        self.insert(len, node);
+    }
+
+    pub fn insert(&mut self, index: usize, node: Rope) {
+        self.insert_shared(index, Arc::new(node));
+    }
+
+    pub fn remove(&mut self, index: usize) -> Rope {
+        Arc::unwrap_or_clone(self.remove_shared(index))
+    }
+
+
+    /// Splits the array in two at the given index.  Children before the
+    /// index stay in this array, the rest are returned as a new array.
+    pub fn split_off(&mut self, index: usize) -> NodeChildren {
+        assert!(index <= self.len, "NodeChildren::split_off(): attempted to split beyond the end of the array.");
+
+        let mut other = NodeChildren::new();
+        for i in index..self.len {
+            other.nodes[i - index] = self.nodes[i].take();
+        }
+        other.len = self.len - index;
+        self.len = index;
+
+        other
+    }
+
+
+    /// Moves all of the children of another array onto the end of this
+    /// one.
+    pub fn append(&mut self, other: &mut NodeChildren) {
+        assert!(self.len + other.len <= self.nodes.len(), "NodeChildren::append(): not enough room for the appended children.");
+
+        for i in 0..other.len {
+            self.nodes[self.len + i] = other.nodes[i].take();
+        }
+        self.len += other.len;
+        other.len = 0;
+    }
+
+
+    /// Moves children between this array and the array of its right
+    /// sibling until they hold (nearly) the same number of children.
+    pub fn balance_with(&mut self, right: &mut NodeChildren) {
+        let target = (self.len + right.len) / 2;
+
+        while self.len < target {
+            let node = right.remove_shared(0);
+            let len = self.len;
+            self.insert_shared(len, node);
+        }
+
+        while self.len > target {
+            let len = self.len;
+            let node = self.remove_shared(len - 1);
+            right.insert_shared(0, node);
+        }
+    }
+
+
+    /// Finds the child that contains the given char index.  Returns the
+    /// index of the child along with the total char, grapheme, and line
+    /// ending counts of the children before it.
+    ///
+    /// A char index on the boundary between two children belongs to the
+    /// child on the right, except at the very end where it belongs to the
+    /// last child.
+    pub fn search_char_index(&self, pos: usize) -> (usize, usize, usize, usize) {
+        let mut cc = 0;
+        let mut gc = 0;
+        let mut lec = 0;
+
+        for (i, node) in self.iter().enumerate() {
+            if pos < cc + node.char_count_ || i + 1 == self.len {
+                return (i, cc, gc, lec);
+            }
+            cc += node.char_count_;
+            gc += node.grapheme_count_;
+            lec += node.line_ending_count_;
+        }
+
+        panic!("NodeChildren::search_char_index(): attempted to search an empty array.");
+    }
+
+
+    /// Finds the child that contains the given grapheme index.  Returns
+    /// the index of the child along with the total char and grapheme
+    /// counts of the children before it.
+    pub fn search_grapheme_index(&self, pos: usize) -> (usize, usize, usize) {
+        let mut cc = 0;
+        let mut gc = 0;
+
+        for (i, node) in self.iter().enumerate() {
+            if pos < gc + node.grapheme_count_ || i + 1 == self.len {
+                return (i, cc, gc);
+            }
+            cc += node.char_count_;
+            gc += node.grapheme_count_;
+        }
+
+        panic!("NodeChildren::search_grapheme_index(): attempted to search an empty array.");
+    }
+
+
+    /// Finds the child that contains the start of the given line.
+    /// Returns the index of the child along with the total char and line
+    /// ending counts of the children before it.
+    ///
+    /// Line index zero is not handled specially here, so the caller
+    /// should do so.
+    pub fn search_line_index(&self, li: usize) -> (usize, usize, usize) {
+        let mut cc = 0;
+        let mut lec = 0;
+
+        for (i, node) in self.iter().enumerate() {
+            if li <= lec + node.line_ending_count_ || i + 1 == self.len {
+                return (i, cc, lec);
+            }
+            cc += node.char_count_;
+            lec += node.line_ending_count_;
+        }
+
+        panic!("NodeChildren::search_line_index(): attempted to search an empty array.");
+    }
+
+
+    //================================================================
+    // Private utility functions
+    //================================================================
+
+    fn insert_shared(&mut self, index: usize, node: Arc<Rope>) {
+        assert!(index <= self.len, "NodeChildren::insert(): attempted to insert beyond the end of the array.");
+        assert!(self.len < self.nodes.len(), "NodeChildren::insert(): attempted to insert into a full array.");
+
+        let mut i = self.len;
+        while i > index {
+            self.nodes[i] = self.nodes[i - 1].take();
+            i -= 1;
+        }
+        self.nodes[index] = Some(node);
+        self.len += 1;
+    }
+
+    fn remove_shared(&mut self, index: usize) -> Arc<Rope> {
+        assert!(index < self.len, "NodeChildren::remove(): attempted to remove a child beyond the end of the array.");
+
+        let node = self.nodes[index].take().unwrap();
+        for i in index..(self.len - 1) {
+            self.nodes[i] = self.nodes[i + 1].take();
+        }
+        self.len -= 1;
+
+        node
+    }
+}
+
+
+/// An iterator over the children of a branch node
+pub struct NodeChildrenIter<'a> {
+    iter: slice::Iter<'a, Option<Arc<Rope>>>,
+}
+
+impl<'a> Iterator for NodeChildrenIter<'a> {
+    type Item = &'a Rope;
+
+    fn next(&mut self) -> Option<&'a Rope> {
+        self.iter.next().map(|node| &**node.as_ref().unwrap())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a> DoubleEndedIterator for NodeChildrenIter<'a> {
+    fn next_back(&mut self) -> Option<&'a Rope> {
+        self.iter.next_back().map(|node| &**node.as_ref().unwrap())
+    }
+}
+
+impl<'a> ExactSizeIterator for NodeChildrenIter<'a> {}
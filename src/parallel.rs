@@ -0,0 +1,88 @@
+//! Parallel construction and iteration of ropes, using rayon.
+
+use rayon::prelude::*;
+use rayon::vec;
+use super::{Rope, RopeConfig};
+
+
+/// Texts at least this large (in bytes) are built in parallel by
+/// Rope::from_str() and friends.
+pub const MIN_PARALLEL_SIZE: usize = 1 << 20;
+
+/// The smallest piece of text (in bytes) that is worth building on a
+/// thread of its own.
+const MIN_PIECE_SIZE: usize = 1 << 18;
+
+/// How far to look for a safe place to split the text into pieces, in
+/// bytes.
+const MAX_SEAM_SEARCH: usize = 256;
+
+
+/// Builds a rope from a string slice by splitting it into pieces, building
+/// the pieces in parallel, and joining them with the usual balanced
+/// append.
+pub fn from_str(s: &str, config: RopeConfig) -> Rope {
+    let piece_count = (s.len() / MIN_PIECE_SIZE).clamp(1, rayon::current_num_threads() * 4);
+    let pieces = split_into_pieces(s, piece_count);
+
+    let ropes: Vec<Rope> = pieces.par_iter()
+        .map(|piece| Rope::build_from_str(piece, config))
+        .collect();
+
+    // Appending repairs any graphemes that were split at the seams
+    let mut rope = Rope::with_config(config);
+    for piece_rope in ropes {
+        rope.append(piece_rope);
+    }
+
+    return rope;
+}
+
+
+/// Splits a string into roughly equal pieces.  Where possible the pieces
+/// are split between two ASCII chars (other than CRLF), where there is
+/// always a grapheme boundary.  Otherwise they're split at a char
+/// boundary, and the grapheme seam is left to be repaired when the pieces
+/// are joined back together.
+fn split_into_pieces(s: &str, piece_count: usize) -> Vec<&str> {
+    let bytes = s.as_bytes();
+    let mut pieces = Vec::with_capacity(piece_count);
+
+    let mut start = 0;
+    for i in 1..piece_count {
+        let target = (s.len() * i) / piece_count;
+        if target <= start {
+            continue;
+        }
+
+        let mut split = target;
+        while split < s.len() && (split - target) < MAX_SEAM_SEARCH {
+            if bytes[split - 1] < 0x80 && bytes[split] < 0x80 && !(bytes[split - 1] == 0x0D && bytes[split] == 0x0A) {
+                break;
+            }
+            split += 1;
+        }
+        while !s.is_char_boundary(split) {
+            split += 1;
+        }
+
+        pieces.push(&s[start..split]);
+        start = split;
+    }
+    pieces.push(&s[start..]);
+
+    return pieces;
+}
+
+
+impl Rope {
+    /// Creates a parallel iterator over the rope's string chunks, for
+    /// counting, searching, etc. on multiple threads.  The chunks are
+    /// in the same order as with chunk_iter().
+    pub fn par_chunk_iter(&self) -> vec::IntoIter<&str> {
+        // Gathering the chunks up front is cheap compared to the work
+        // done on them, and gives rayon an indexed iterator to split.
+        let chunks: Vec<&str> = self.chunk_iter().collect();
+        return chunks.into_par_iter();
+    }
+}
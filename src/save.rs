@@ -0,0 +1,258 @@
+//! Saving ropes to files atomically.
+
+use std::ffi::OsString;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use encoding::{InvalidUtf8, unescape_bytes};
+use hash::ContentHash;
+use super::Rope;
+
+
+/// Distinguishes the temp files of saves running at the same time.
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+
+/// A line ending style to convert a rope's line endings to when saving.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    Cr,
+}
+
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            LineEnding::Lf => "\u{000A}",
+            LineEnding::CrLf => "\u{000D}\u{000A}",
+            LineEnding::Cr => "\u{000D}",
+        }
+    }
+}
+
+
+/// Options for Rope::save_to_path().
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SaveOptions {
+    /// Keep the file that is being replaced, with a '~' appended to its
+    /// name.
+    pub backup: bool,
+
+    /// Convert the LF, CRLF and CR line endings to the given style.  The
+    /// other line endings are left as they are.
+    pub line_ending: Option<LineEnding>,
+
+    /// Write a UTF-8 byte order mark at the start of the file.
+    pub bom: bool,
+
+    /// How the rope's text was loaded with Rope::from_bytes().  With
+    /// InvalidUtf8::Preserve, the escaped bytes are written as they were,
+    /// the same as with Rope::write_bytes_to().
+    pub invalid_utf8: InvalidUtf8,
+}
+
+
+impl Rope {
+    /// Saves the rope's text to a file as UTF-8.
+    ///
+    /// The text is written to a temp file next to the file, which is
+    /// synced to disk and then renamed over the file, so the file is never
+    /// left partly written.  A file that already exists keeps its
+    /// permissions, but not its owner and group, which become those of
+    /// the user saving it, or any other metadata.  A symlink is followed
+    /// to the file that it points to.  Returns the hash of the bytes that
+    /// were written, which can be compared to ContentHash::from_path()
+    /// later to detect changes made by others.
+    pub fn save_to_path<P: AsRef<Path>>(&self, path: P, options: SaveOptions) -> io::Result<ContentHash> {
+        let path = match fs::canonicalize(path.as_ref()) {
+            Ok(path) => path,
+            Err(_) => path.as_ref().to_path_buf(),
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let file_name = match path.file_name() {
+            Some(file_name) => file_name.to_os_string(),
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Rope::save_to_path(): path does not name a file."));
+            },
+        };
+        let metadata = fs::metadata(&path).ok();
+
+        // Write the temp file
+        let (temp_path, temp_file) = create_temp_file(&dir, &file_name)?;
+        let result = self.write_for_save(&temp_file, &options)
+            .and_then(|hash| {
+                if let Some(ref metadata) = metadata {
+                    temp_file.set_permissions(metadata.permissions())?;
+                }
+                temp_file.sync_all()?;
+                Ok(hash)
+            });
+        drop(temp_file);
+        let hash = match result {
+            Ok(hash) => hash,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e);
+            },
+        };
+
+        // Keep the old file as a backup
+        if options.backup && metadata.is_some() {
+            let mut backup_name = file_name.clone();
+            backup_name.push("~");
+            let backup_path = dir.join(backup_name);
+            let _ = fs::remove_file(&backup_path);
+            if fs::hard_link(&path, &backup_path).is_err() {
+                if let Err(e) = fs::copy(&path, &backup_path) {
+                    let _ = fs::remove_file(&temp_path);
+                    return Err(e);
+                }
+            }
+        }
+
+        // And replace it
+        if let Err(e) = fs::rename(&temp_path, &path) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        sync_dir(&dir);
+
+        return Ok(hash);
+    }
+
+
+    /// Writes the rope's text with the given options applied, returning
+    /// the hash of the written bytes.
+    fn write_for_save(&self, file: &File, options: &SaveOptions) -> io::Result<ContentHash> {
+        let mut writer = SaveWriter {
+            writer: BufWriter::new(file),
+            hash: ContentHash::new(),
+            invalid_utf8: options.invalid_utf8,
+            buffer: Vec::new(),
+        };
+        let mut converted = String::new();
+
+        if options.bom {
+            writer.write("\u{FEFF}")?;
+        }
+
+        // A CR at the end of a chunk is held back until the next chunk
+        // shows whether it's part of a CRLF
+        let mut pending_cr = false;
+        for chunk in self.chunk_iter() {
+            let line_ending = match options.line_ending {
+                Some(line_ending) => line_ending,
+                None => {
+                    writer.write(chunk)?;
+                    continue;
+                },
+            };
+
+            let mut chunk = chunk;
+            if pending_cr {
+                writer.write(line_ending.as_str())?;
+                chunk = chunk.strip_prefix('\u{000A}').unwrap_or(chunk);
+                pending_cr = false;
+            }
+            if let Some(rest) = chunk.strip_suffix('\u{000D}') {
+                chunk = rest;
+                pending_cr = true;
+            }
+
+            if chunk.contains(['\u{000A}', '\u{000D}']) {
+                converted.clear();
+                convert_line_endings(chunk, line_ending, &mut converted);
+                writer.write(&converted[..])?;
+            }
+            else {
+                writer.write(chunk)?;
+            }
+        }
+        if let (true, Some(line_ending)) = (pending_cr, options.line_ending) {
+            writer.write(line_ending.as_str())?;
+        }
+
+        writer.writer.flush()?;
+        return Ok(writer.hash);
+    }
+}
+
+
+/// Writes and hashes the bytes of a save.
+struct SaveWriter<'a> {
+    writer: BufWriter<&'a File>,
+    hash: ContentHash,
+    invalid_utf8: InvalidUtf8,
+    buffer: Vec<u8>,
+}
+
+
+impl<'a> SaveWriter<'a> {
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        // Escapes are four bytes long, starting with 0xF4
+        let bytes = if self.invalid_utf8 == InvalidUtf8::Preserve && text.as_bytes().contains(&0xF4) {
+            self.buffer.clear();
+            unescape_bytes(text, &mut self.buffer);
+            &self.buffer[..]
+        }
+        else {
+            text.as_bytes()
+        };
+        self.writer.write_all(bytes)?;
+        self.hash.update(bytes);
+        return Ok(());
+    }
+}
+
+
+fn convert_line_endings(text: &str, line_ending: LineEnding, converted: &mut String) {
+    let mut rest = text;
+    while let Some(i) = rest.find(['\u{000A}', '\u{000D}']) {
+        converted.push_str(&rest[..i]);
+        converted.push_str(line_ending.as_str());
+        let len = if rest[i..].starts_with("\u{000D}\u{000A}") { 2 } else { 1 };
+        rest = &rest[(i + len)..];
+    }
+    converted.push_str(rest);
+}
+
+
+fn create_temp_file(dir: &Path, file_name: &OsString) -> io::Result<(PathBuf, File)> {
+    loop {
+        let mut temp_name = OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".{}.{}.tmp", process::id(), TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let temp_path = dir.join(temp_name);
+
+        match OpenOptions::new().write(true).create_new(true).open(&temp_path) {
+            Ok(file) => return Ok((temp_path, file)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+
+/// Syncs a directory to disk, so that a rename in it is durable.  This
+/// is only possible on some platforms, and is best effort.
+fn sync_dir(dir: &Path) {
+    #[cfg(unix)]
+    {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+    }
+}
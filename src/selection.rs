@@ -0,0 +1,286 @@
+//! Multiple selections in a rope, for multi-cursor editing.
+
+use std::cmp::{min, max};
+use std::ops::Range;
+use document::Edit;
+use super::Rope;
+
+
+/// A selection of the chars between two char indices.  The anchor is the
+/// end that stays put when the selection is extended, and the head the
+/// end that moves.  A selection whose anchor and head are the same is a
+/// cursor.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Selection {
+    pub anchor: usize,
+    pub head: usize,
+}
+
+
+impl Selection {
+    pub fn new(anchor: usize, head: usize) -> Selection {
+        Selection {
+            anchor,
+            head,
+        }
+    }
+
+
+    /// Creates a cursor.
+    pub fn point(pos: usize) -> Selection {
+        Selection::new(pos, pos)
+    }
+
+
+    pub fn start(&self) -> usize {
+        min(self.anchor, self.head)
+    }
+
+
+    pub fn end(&self) -> usize {
+        max(self.anchor, self.head)
+    }
+
+
+    pub fn range(&self) -> Range<usize> {
+        self.start()..self.end()
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+        self.anchor == self.head
+    }
+
+
+    /// Returns whether the head is before the anchor.
+    pub fn is_backward(&self) -> bool {
+        self.head < self.anchor
+    }
+
+
+    /// Creates a selection of a range, in the direction of this one.
+    fn with_range(&self, start: usize, end: usize) -> Selection {
+        if self.is_backward() {
+            Selection::new(end, start)
+        }
+        else {
+            Selection::new(start, end)
+        }
+    }
+}
+
+
+/// A set of selections, one of which is the primary one.
+///
+/// The selections are kept sorted, and overlapping ones are merged, as
+/// are cursors that touch another selection.  Adjacent selections that
+/// aren't cursors are kept apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectionSet {
+    selections: Vec<Selection>,
+    primary: usize,
+}
+
+
+impl SelectionSet {
+    pub fn new(selection: Selection) -> SelectionSet {
+        SelectionSet {
+            selections: vec![selection],
+            primary: 0,
+        }
+    }
+
+
+    /// Creates a set of selections, with the one at the given index in
+    /// the list being the primary one.
+    pub fn from_selections(selections: Vec<Selection>, primary: usize) -> SelectionSet {
+        assert!(primary < selections.len(), "SelectionSet::from_selections(): primary must be the index of one of the selections.");
+
+        let mut set = SelectionSet {
+            selections,
+            primary,
+        };
+        set.merge();
+        return set;
+    }
+
+
+    pub fn len(&self) -> usize {
+        self.selections.len()
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+        self.selections.is_empty()
+    }
+
+
+    /// Returns the selections, in order.
+    pub fn selections(&self) -> &[Selection] {
+        &self.selections[..]
+    }
+
+
+    pub fn primary(&self) -> Selection {
+        self.selections[self.primary]
+    }
+
+
+    pub fn primary_index(&self) -> usize {
+        self.primary
+    }
+
+
+    /// Adds a selection, which becomes the primary one.
+    pub fn add(&mut self, selection: Selection) {
+        self.selections.push(selection);
+        self.primary = self.selections.len() - 1;
+        self.merge();
+    }
+
+
+    /// Removes the selection at an index.  The set always keeps at least
+    /// one selection.
+    pub fn remove(&mut self, index: usize) {
+        assert!(self.selections.len() > 1, "SelectionSet::remove(): attempted to remove the last selection.");
+
+        self.selections.remove(index);
+        if self.primary > index || self.primary == self.selections.len() {
+            self.primary -= 1;
+        }
+    }
+
+
+    /// Makes the selections valid for a rope: ends past the end of the
+    /// text are moved to it, and ends inside of graphemes are moved to
+    /// the start of the grapheme.
+    pub fn normalize(&mut self, rope: &Rope) {
+        let snap = |pos: usize| {
+            let pos = min(pos, rope.char_count());
+            rope.grapheme_index_to_char_index(rope.char_index_to_grapheme_index(pos))
+        };
+        for selection in self.selections.iter_mut() {
+            *selection = Selection::new(snap(selection.anchor), snap(selection.head));
+        }
+        self.merge();
+    }
+
+
+    /// Updates the selections for an edit made to the rope, e.g. one from
+    /// a Document observer.
+    pub fn map_through_edit(&mut self, edit: &Edit) {
+        let changes = [(edit.start.char..edit.old_end.char, edit.new_end.char - edit.start.char)];
+        self.map_through_changes(&changes[..]);
+    }
+
+
+    /// Applies an edit at every selection in a single pass.  The function
+    /// is called with each selection, and returns the char range of the
+    /// rope to replace and the text to replace it with.  The ranges are
+    /// in the rope from before any of the edits, and must be in order and
+    /// not overlap.
+    ///
+    /// Afterwards, selection ends that were inside of or at the ends of a
+    /// replaced range are at the end of its replacement, and the rest are
+    /// shifted along with the text.
+    pub fn edit<F>(&mut self, rope: &mut Rope, f: F)
+        where F: FnMut(&Rope, Selection) -> (Range<usize>, String)
+    {
+        let mut f = f;
+        let edits: Vec<(Range<usize>, String)> = self.selections.iter().map(|&selection| f(rope, selection)).collect();
+
+        let mut prev_end = 0;
+        for (range, _) in edits.iter() {
+            assert!(prev_end <= range.start && range.start <= range.end, "SelectionSet::edit(): the edited ranges must be in order and not overlap.");
+            prev_end = range.end;
+        }
+        assert!(prev_end <= rope.char_count(), "SelectionSet::edit(): attempted to edit past the end of the text.");
+
+        // Edit front to back, shifting each edit by the ones before it
+        let mut shift = 0isize;
+        let mut changes = Vec::with_capacity(edits.len());
+        for (range, text) in edits {
+            let start = (range.start as isize + shift) as usize;
+            let text_len = text.chars().count();
+            rope.remove_text_between_char_indices(start, start + range.len());
+            rope.insert_text_at_char_index(&text[..], start);
+            shift += text_len as isize - range.len() as isize;
+            changes.push((range, text_len));
+        }
+
+        self.map_through_changes(&changes[..]);
+    }
+
+
+    /// Replaces the text of every selection, leaving cursors after the
+    /// new text.
+    pub fn replace(&mut self, rope: &mut Rope, text: &str) {
+        self.edit(rope, |_, selection| (selection.range(), text.to_string()));
+    }
+
+
+    /// Removes the text of every selection.
+    pub fn delete(&mut self, rope: &mut Rope) {
+        self.replace(rope, "");
+    }
+
+
+    /// Maps the selections through ordered, non-overlapping changes, each
+    /// a replaced char range and the length of its replacement.
+    fn map_through_changes(&mut self, changes: &[(Range<usize>, usize)]) {
+        // The starts and ends of the selections are in order, so a single
+        // pass over the changes maps all of them.  A position between two
+        // changes belongs to the later one if it's a selection start, and
+        // to the earlier one if it's an end.
+        let mut i = 0;
+        let mut shift = 0isize;
+        let mut map = |pos: usize, is_start: bool| {
+            while i < changes.len() && (changes[i].0.end < pos || (is_start && changes[i].0.end == pos && i + 1 < changes.len() && changes[i + 1].0.start == pos)) {
+                shift += changes[i].1 as isize - changes[i].0.len() as isize;
+                i += 1;
+            }
+            if i < changes.len() && changes[i].0.start <= pos {
+                return (changes[i].0.start as isize + shift) as usize + changes[i].1;
+            }
+            return (pos as isize + shift) as usize;
+        };
+
+        for selection in self.selections.iter_mut() {
+            let start = map(selection.start(), true);
+            let end = map(selection.end(), false);
+            *selection = selection.with_range(start, end);
+        }
+        self.merge();
+    }
+
+
+    /// Sorts the selections and merges the ones that overlap, keeping
+    /// track of the primary one.
+    fn merge(&mut self) {
+        let mut indexed: Vec<(usize, Selection)> = self.selections.iter().cloned().enumerate().collect();
+        indexed.sort_by_key(|&(_, selection)| (selection.start(), selection.end()));
+
+        let mut merged: Vec<Selection> = Vec::with_capacity(indexed.len());
+        let mut primary = 0;
+        for (i, selection) in indexed {
+            let overlaps = match merged.last() {
+                Some(last) => selection.start() < last.end() || (selection.start() == last.end() && (selection.is_empty() || last.is_empty())),
+                None => false,
+            };
+            if overlaps {
+                let last = merged.last_mut().unwrap();
+                let direction = if last.is_empty() { selection } else { *last };
+                *last = direction.with_range(last.start(), max(last.end(), selection.end()));
+            }
+            else {
+                merged.push(selection);
+            }
+            if i == self.primary {
+                primary = merged.len() - 1;
+            }
+        }
+
+        self.selections = merged;
+        self.primary = primary;
+    }
+}
@@ -0,0 +1,82 @@
+//! Serde support for ropes.
+//!
+//! By default a rope is serialized as a plain string.  The chunks module
+//! can be used with `#[serde(with = "ropey::serde_chunks")]` to serialize
+//! it as a sequence of its chunks instead, which avoids building the
+//! whole text as one string first.  Deserializing a sequence of chunks
+//! likewise appends each chunk to the rope as it arrives.
+//!
+//! Either way, the rope comes out balanced.
+
+use std::fmt;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{Visitor, SeqAccess};
+use super::{Rope, RopeSnapshot};
+
+
+impl Serialize for Rope {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string()[..])
+    }
+}
+
+
+impl Serialize for RopeSnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+
+impl<'de> Deserialize<'de> for Rope {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Rope, D::Error> {
+        deserializer.deserialize_str(RopeVisitor)
+    }
+}
+
+
+/// Serializes and deserializes a rope as a sequence of its chunks.
+pub mod chunks {
+    use serde::{Serializer, Deserializer};
+    use super::RopeVisitor;
+    use super::super::Rope;
+
+    pub fn serialize<S: Serializer>(rope: &Rope, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(rope.chunk_iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Rope, D::Error> {
+        deserializer.deserialize_seq(RopeVisitor)
+    }
+}
+
+
+/// Builds a rope from either a string or a sequence of string chunks.
+struct RopeVisitor;
+
+impl<'de> Visitor<'de> for RopeVisitor {
+    type Value = Rope;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string or a sequence of strings")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Rope, E> {
+        Ok(Rope::from_str(value))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Rope, A::Error> {
+        // The last grapheme of a chunk may continue in the next one, so
+        // it's carried over, the same as when reading from a stream
+        let mut seq = seq;
+        let mut rope = Rope::new();
+        let mut text = String::new();
+        while let Some(chunk) = seq.next_element::<String>()? {
+            text.push_str(&chunk[..]);
+            let end = rope.append_complete_graphemes(&text[..], false);
+            text.drain(..end);
+        }
+        rope.append_complete_graphemes(&text[..], true);
+        Ok(rope)
+    }
+}
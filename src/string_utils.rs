@@ -2,12 +2,12 @@
 //! Misc helpful utility functions for TextBuffer related stuff.
 
 use std::str::CharIndices;
-use std::iter::repeat;
+use std::iter::repeat_n;
 use unicode_segmentation::UnicodeSegmentation;
 
 
 pub fn is_line_ending(text: &str) -> bool {
-    match text {
+    matches!(text,
         "\u{000D}\u{000A}"
         | "\u{000A}"
         | "\u{000B}"
@@ -15,36 +15,63 @@ pub fn is_line_ending(text: &str) -> bool {
         | "\u{000D}"
         | "\u{0085}"
         | "\u{2028}"
-        | "\u{2029}" => true,
-        
-        _ => false
-    }
+        | "\u{2029}"
+    )
 }
 
 pub fn line_ending_count(text: &str) -> usize {
+    let bytes = text.as_bytes();
     let mut count = 0;
-    for g in UnicodeSegmentation::graphemes(text, true) {
-        if is_line_ending(g) {
-            count += 1;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        // Skip whole words of plain ASCII text that can't contain any
+        // line endings
+        if (i + WORD_SIZE) <= bytes.len() {
+            let word = read_word(bytes, i);
+            if !word_has_byte_less_than(word, 0x0E) && !word_has_non_ascii(word) {
+                i += WORD_SIZE;
+                continue;
+            }
         }
+
+        // Line endings are never merged into graphemes with other chars,
+        // except for CRLF, so they can be found by their bytes alone
+        match bytes[i..] {
+            [0x0A, ..] if i > 0 && bytes[i - 1] == 0x0D => {},
+            [0x0A..=0x0D, ..] | [0xC2, 0x85, ..] | [0xE2, 0x80, 0xA8..=0xA9, ..] => {
+                count += 1;
+            },
+            _ => {},
+        }
+        i += 1;
     }
+
     return count;
 }
 
 pub fn char_count(text: &str) -> usize {
-    let mut count = 0;
-    for _ in text.chars() {
-        count += 1;
+    // Every byte that isn't a UTF-8 continuation byte starts a char
+    let bytes = text.as_bytes();
+    let mut continuation_count = 0;
+
+    let mut i = 0;
+    while (i + WORD_SIZE) <= bytes.len() {
+        continuation_count += word_continuation_byte_count(read_word(bytes, i));
+        i += WORD_SIZE;
+    }
+    for &byte in bytes[i..].iter() {
+        if is_continuation_byte(byte) {
+            continuation_count += 1;
+        }
     }
-    return count;
+
+    return bytes.len() - continuation_count;
 }
 
 pub fn grapheme_count(text: &str) -> usize {
-    let mut count = 0;
-    for _ in UnicodeSegmentation::graphemes(text, true) {
-        count += 1;
-    }
-    return count;
+    let (_, gc, _) = char_grapheme_line_ending_count(text);
+    return gc;
 }
 
 pub fn grapheme_count_is_less_than(text: &str, n: usize) -> bool {
@@ -59,19 +86,58 @@ pub fn grapheme_count_is_less_than(text: &str, n: usize) -> bool {
     return true;
 }
 
+/// Counts the chars, graphemes, and line endings in a string.
+///
+/// Runs of ASCII text take a fast path that works on bytes, since in
+/// ASCII every char is its own grapheme except for CRLF pairs.  Only the
+/// text around non-ASCII chars is segmented into graphemes.
 pub fn char_grapheme_line_ending_count(text: &str) -> (usize, usize, usize) {
     let mut cc = 0;
     let mut gc = 0;
     let mut lec = 0;
-    
-    for g in UnicodeSegmentation::graphemes(text, true) {
-        cc += char_count(g);
-        gc += 1;
-        if is_line_ending(g) {
-            lec += 1;
+
+    let mut rest = text;
+    while !rest.is_empty() {
+        let ascii_len = ascii_prefix_len(rest);
+        if ascii_len == rest.len() {
+            let (acc, agc, alec) = ascii_char_grapheme_line_ending_count(rest);
+            cc += acc;
+            gc += agc;
+            lec += alec;
+            break;
+        }
+
+        // Count the ASCII run, holding back its last char since it may
+        // combine with the non-ASCII char that follows it.  A CR before
+        // that is held back as well, so as not to split a CRLF pair.
+        let mut fast_len = ascii_len.saturating_sub(1);
+        if fast_len > 0 && rest.as_bytes()[fast_len - 1] == 0x0D {
+            fast_len -= 1;
         }
+        let (acc, agc, alec) = ascii_char_grapheme_line_ending_count(&rest[..fast_len]);
+        cc += acc;
+        gc += agc;
+        lec += alec;
+        rest = &rest[fast_len..];
+
+        // Count the graphemes around the non-ASCII text the slow way,
+        // until reaching a grapheme that starts with ASCII again
+        let non_ascii_i = ascii_len - fast_len;
+        let mut slow_len = rest.len();
+        for (i, g) in UnicodeSegmentation::grapheme_indices(rest, true) {
+            if i > non_ascii_i && g.as_bytes()[0] < 0x80 {
+                slow_len = i;
+                break;
+            }
+            cc += char_count(g);
+            gc += 1;
+            if is_line_ending(g) {
+                lec += 1;
+            }
+        }
+        rest = &rest[slow_len..];
     }
-    
+
     return (cc, gc, lec);
 }
 
@@ -84,16 +150,32 @@ pub fn graphemes_are_mergeable(s1: &str, s2: &str) -> bool {
 }
 
 pub fn char_pos_to_byte_pos(text: &str, pos: usize) -> usize {
-    let mut i: usize = 0;
-    
-    for (offset, _) in text.char_indices() {
-        if i == pos {
-            return offset;
+    let bytes = text.as_bytes();
+    let mut byte_i = 0;
+    let mut char_i = 0;
+
+    // Skip ahead a word at a time while the char is further along
+    while (byte_i + WORD_SIZE) <= bytes.len() {
+        let n = WORD_SIZE - word_continuation_byte_count(read_word(bytes, byte_i));
+        if (char_i + n) > pos {
+            break;
         }
-        i += 1;
+        char_i += n;
+        byte_i += WORD_SIZE;
+    }
+
+    // Find the exact byte position one byte at a time
+    while byte_i < bytes.len() {
+        if !is_continuation_byte(bytes[byte_i]) {
+            if char_i == pos {
+                return byte_i;
+            }
+            char_i += 1;
+        }
+        byte_i += 1;
     }
     
-    if i == pos {
+    if char_i == pos {
         return text.len();
     }
     
@@ -171,7 +253,7 @@ pub fn insert_text_at_char_index(s: &mut String, text: &str, pos: usize) {
     let byte_vec = unsafe { s.as_mut_vec() };
     
     // Grow data size        
-    byte_vec.extend(repeat(0).take(text.len()));
+    byte_vec.extend(repeat_n(0, text.len()));
     
     // Move old bytes forward
     // TODO: use copy_memory()...?
@@ -186,10 +268,8 @@ pub fn insert_text_at_char_index(s: &mut String, text: &str, pos: usize) {
     
     // Copy new bytes in
     // TODO: use copy_memory()
-    let mut i = byte_pos;
-    for b in text.bytes() {
+    for (i, b) in (byte_pos..).zip(text.bytes()) {
         byte_vec[i] = b;
-        i += 1
     }
 }
 
@@ -202,7 +282,7 @@ pub fn insert_text_at_grapheme_index(s: &mut String, text: &str, pos: usize) {
     let byte_vec = unsafe { s.as_mut_vec() };
     
     // Grow data size        
-    byte_vec.extend(repeat(0).take(text.len()));
+    byte_vec.extend(repeat_n(0, text.len()));
     
     // Move old bytes forward
     // TODO: use copy_memory()...?
@@ -304,7 +384,7 @@ pub fn split_string_at_char_index(s1: &mut String, pos: usize) -> String {
         let byte_vec_1 = unsafe { s1.as_mut_vec() };
         let byte_vec_2 = unsafe { s2.as_mut_vec() };
         
-        byte_vec_2.extend((&byte_vec_1[byte_pos..]).iter().map(|&i| i));
+        byte_vec_2.extend_from_slice(&byte_vec_1[byte_pos..]);
         byte_vec_1.truncate(byte_pos);
     }
     
@@ -324,7 +404,7 @@ pub fn split_string_at_grapheme_index(s1: &mut String, pos: usize) -> String {
         let byte_vec_1 = unsafe { s1.as_mut_vec() };
         let byte_vec_2 = unsafe { s2.as_mut_vec() };
         
-        byte_vec_2.extend((&byte_vec_1[byte_pos..]).iter().map(|&i| i));
+        byte_vec_2.extend_from_slice(&byte_vec_1[byte_pos..]);
         byte_vec_1.truncate(byte_pos);
     }
     
@@ -332,6 +412,160 @@ pub fn split_string_at_grapheme_index(s1: &mut String, pos: usize) -> String {
 }
 
 
+/// Returns the grapheme boundary nearest to the given byte index.
+/// Boundaries strictly inside the string are preferred over its start
+/// and end, so that splitting there leaves neither half empty whenever
+/// the string has more than one grapheme.
+pub fn nearest_grapheme_boundary(text: &str, byte_pos: usize) -> usize {
+    let mut best = text.len();
+    let mut best_dist = usize::MAX;
+
+    for (i, _) in UnicodeSegmentation::grapheme_indices(text, true).skip(1) {
+        let dist = i.abs_diff(byte_pos);
+        if dist < best_dist {
+            best = i;
+            best_dist = dist;
+        }
+        else if i > byte_pos {
+            break;
+        }
+    }
+
+    return best;
+}
+
+
+/// Returns the last grapheme boundary at or before the given byte index.
+pub fn prev_grapheme_boundary(text: &str, byte_pos: usize) -> usize {
+    if byte_pos >= text.len() {
+        return text.len();
+    }
+
+    // There is always a grapheme boundary between two ASCII chars, other
+    // than in CRLF, so look back a little for one to start segmenting
+    // from rather than segmenting the whole string.
+    let bytes = text.as_bytes();
+    let mut start = 0;
+    let mut i = byte_pos;
+    while i > 0 && (byte_pos - i) < 64 {
+        if bytes[i - 1] < 0x80 && bytes[i] < 0x80 && !(bytes[i - 1] == 0x0D && bytes[i] == 0x0A) {
+            start = i;
+            break;
+        }
+        i -= 1;
+    }
+
+    let mut prev = start;
+    for (i, g) in UnicodeSegmentation::grapheme_indices(&text[start..], true) {
+        if (start + i + g.len()) > byte_pos {
+            break;
+        }
+        prev = start + i + g.len();
+    }
+    return prev;
+}
+
+/// Returns the first grapheme boundary after the given byte index, or the
+/// end of the string if there is none.
+pub fn next_grapheme_boundary(text: &str, byte_pos: usize) -> usize {
+    for (i, g) in UnicodeSegmentation::grapheme_indices(text, true) {
+        if (i + g.len()) > byte_pos {
+            return i + g.len();
+        }
+    }
+    return text.len();
+}
+
+
+//----------------------------------------------------------------
+// Word-at-a-time byte scanning utilities
+//----------------------------------------------------------------
+
+const WORD_SIZE: usize = 8;
+const LO_BITS: u64 = 0x0101_0101_0101_0101;
+const HI_BITS: u64 = 0x8080_8080_8080_8080;
+
+/// Reads the eight bytes starting at the given byte index as a word.
+#[inline(always)]
+fn read_word(bytes: &[u8], i: usize) -> u64 {
+    let mut word = [0u8; WORD_SIZE];
+    word.copy_from_slice(&bytes[i..(i + WORD_SIZE)]);
+    return u64::from_le_bytes(word);
+}
+
+#[inline(always)]
+fn is_continuation_byte(byte: u8) -> bool {
+    (byte & 0xC0) == 0x80
+}
+
+/// Counts the UTF-8 continuation bytes (0b10xxxxxx) in a word.
+#[inline(always)]
+fn word_continuation_byte_count(word: u64) -> usize {
+    // Each byte's high bit is set when bit 7 is set and bit 6 isn't
+    return (word & !(word << 1) & HI_BITS).count_ones() as usize;
+}
+
+#[inline(always)]
+fn word_has_non_ascii(word: u64) -> bool {
+    (word & HI_BITS) != 0
+}
+
+/// Tests whether any byte in a word is less than n, which must be no
+/// greater than 128.
+#[inline(always)]
+fn word_has_byte_less_than(word: u64, n: u8) -> bool {
+    (word.wrapping_sub(LO_BITS * (n as u64)) & !word & HI_BITS) != 0
+}
+
+/// Returns the length in bytes of the ASCII text at the start of a string.
+fn ascii_prefix_len(text: &str) -> usize {
+    let bytes = text.as_bytes();
+
+    let mut i = 0;
+    while (i + WORD_SIZE) <= bytes.len() && !word_has_non_ascii(read_word(bytes, i)) {
+        i += WORD_SIZE;
+    }
+    while i < bytes.len() && bytes[i] < 0x80 {
+        i += 1;
+    }
+
+    return i;
+}
+
+/// Counts the chars, graphemes, and line endings in a string of ASCII
+/// text.  Every ASCII char is its own grapheme, except for CRLF pairs.
+fn ascii_char_grapheme_line_ending_count(text: &str) -> (usize, usize, usize) {
+    let bytes = text.as_bytes();
+    let mut line_break_count = 0;
+    let mut crlf_count = 0;
+
+    let mut i = 0;
+    while i < bytes.len() {
+        // Skip whole words without any line breaking chars
+        if (i + WORD_SIZE) <= bytes.len() && !word_has_byte_less_than(read_word(bytes, i), 0x0E) {
+            i += WORD_SIZE;
+            continue;
+        }
+
+        match bytes[i] {
+            0x0A => {
+                line_break_count += 1;
+                if i > 0 && bytes[i - 1] == 0x0D {
+                    crlf_count += 1;
+                }
+            },
+            0x0B..=0x0D => {
+                line_break_count += 1;
+            },
+            _ => {},
+        }
+        i += 1;
+    }
+
+    return (bytes.len(), bytes.len() - crlf_count, line_break_count - crlf_count);
+}
+
+
 /// A grapheme iterator that only recognizes CRLF as a composite grapheme.
 /// This is only temporary, a stand-in for the proper Graphemes iterator
 /// from stdlib which is currently marked unstable and thus is unavailable
@@ -403,6 +637,32 @@ impl<'a> Iterator for TempGraphemeIndices<'a> {
 mod tests {
     use super::*;
     
+    #[test]
+    fn nearest_grapheme_boundary_1() {
+        let s = "Hello\u{000D}\u{000A}there!";
+
+        assert_eq!(nearest_grapheme_boundary(s, 0), 1);
+        assert_eq!(nearest_grapheme_boundary(s, 3), 3);
+        assert_eq!(nearest_grapheme_boundary(s, 6), 5);
+        assert_eq!(nearest_grapheme_boundary(s, 7), 7);
+        assert_eq!(nearest_grapheme_boundary(s, 13), 12);
+        assert_eq!(nearest_grapheme_boundary("a", 0), 1);
+    }
+
+    #[test]
+    fn prev_next_grapheme_boundary_1() {
+        let s = "Hello\u{000D}\u{000A}there!";
+
+        assert_eq!(prev_grapheme_boundary(s, 0), 0);
+        assert_eq!(prev_grapheme_boundary(s, 6), 5);
+        assert_eq!(prev_grapheme_boundary(s, 7), 7);
+        assert_eq!(prev_grapheme_boundary(s, 100), 13);
+        assert_eq!(next_grapheme_boundary(s, 0), 1);
+        assert_eq!(next_grapheme_boundary(s, 5), 7);
+        assert_eq!(next_grapheme_boundary(s, 6), 7);
+        assert_eq!(next_grapheme_boundary(s, 13), 13);
+    }
+
     #[test]
     fn graphemes_are_mergeable_1() {
         assert!(graphemes_are_mergeable("\u{000D}", "\u{000A}"));
@@ -478,4 +738,90 @@ mod tests {
         
         assert_eq!(grapheme_pos_to_char_pos(s, 0), 0);
     }
-}
\ No newline at end of file
+
+    // Straightforward grapheme-by-grapheme versions of the counting
+    // functions, to check the optimized ones against.
+    fn slow_char_grapheme_line_ending_count(text: &str) -> (usize, usize, usize) {
+        let mut cc = 0;
+        let mut gc = 0;
+        let mut lec = 0;
+        for g in UnicodeSegmentation::graphemes(text, true) {
+            cc += g.chars().count();
+            gc += 1;
+            if is_line_ending(g) {
+                lec += 1;
+            }
+        }
+        return (cc, gc, lec);
+    }
+
+    fn slow_char_pos_to_byte_pos(text: &str, pos: usize) -> usize {
+        text.char_indices().map(|(i, _)| i).chain(Some(text.len())).nth(pos).unwrap()
+    }
+
+    fn counting_test_strings() -> Vec<String> {
+        let pieces = [
+            "a", "Hello there!", "\u{000D}", "\u{000A}", "\u{000D}\u{000A}", "\u{000B}", "\u{000C}",
+            "\u{0085}", "\u{2028}", "\u{2029}", "\u{0301}", "e\u{0301}", "\u{00E8}", "日本語",
+            "\u{1F600}", "\u{1F1EF}\u{1F1F5}", " ", "\u{000D}\u{0301}", "\u{200D}",
+        ];
+
+        let mut strings: Vec<String> = pieces.iter().map(|p| p.to_string()).collect();
+        strings.push(String::new());
+        for a in pieces.iter() {
+            for b in pieces.iter() {
+                strings.push(format!("{}{}", a, b));
+                strings.push(format!("abcdefg{}{}hijklmnop{}", a, b, a));
+            }
+        }
+
+        // Longer pseudo-random mixes, to cross word boundaries at
+        // different alignments
+        let mut seed: u32 = 12345;
+        for _ in 0..200 {
+            let mut text = String::new();
+            for _ in 0..40 {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                text.push_str(pieces[((seed >> 16) as usize) % pieces.len()]);
+            }
+            strings.push(text);
+        }
+
+        return strings;
+    }
+
+    #[test]
+    fn char_grapheme_line_ending_count_1() {
+        for text in counting_test_strings() {
+            let (cc, gc, lec) = slow_char_grapheme_line_ending_count(&text[..]);
+            assert_eq!(char_grapheme_line_ending_count(&text[..]), (cc, gc, lec), "{:?}", text);
+            assert_eq!(char_count(&text[..]), cc, "{:?}", text);
+            assert_eq!(grapheme_count(&text[..]), gc, "{:?}", text);
+            assert_eq!(line_ending_count(&text[..]), lec, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn char_grapheme_line_ending_count_2() {
+        let s = "Hello\u{000D}\u{000A}there!\u{000A}How are you?\u{000D}\u{000D}";
+
+        assert_eq!(char_grapheme_line_ending_count(s), (28, 27, 4));
+        assert_eq!(char_grapheme_line_ending_count(""), (0, 0, 0));
+    }
+
+    #[test]
+    fn char_pos_to_byte_pos_1() {
+        for text in counting_test_strings() {
+            let cc = text.chars().count();
+            for pos in 0..(cc + 1) {
+                assert_eq!(char_pos_to_byte_pos(&text[..], pos), slow_char_pos_to_byte_pos(&text[..], pos));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn char_pos_to_byte_pos_2() {
+        char_pos_to_byte_pos("Hello\u{00E8} there!", 14);
+    }
+}
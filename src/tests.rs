@@ -1,9 +1,20 @@
 #![cfg(test)]
 #![allow(unused_imports)]
+#![allow(unused_parens)]
+#![allow(clippy::partialeq_to_none, clippy::char_lit_as_u8, clippy::single_char_add_str, clippy::identity_op)]
 
+use std::env;
+use std::fs;
+use std::io;
 use std::iter;
-use string_utils::{remove_text_between_char_indices};
-use super::{Rope, RopeData, RopeGraphemeIter, MAX_NODE_SIZE};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::thread;
+use string_utils::{insert_text_at_char_index, remove_text_between_char_indices, char_pos_to_byte_pos};
+use super::{Rope, RopeConfig, LeafStorage, Encoding, Replacement, InvalidUtf8, ContentHash, SaveOptions, LineEnding, DiffKind, DiffOp, MergeOptions, CrdtText, CrdtOp, OpId, Document, Edit, TextPosition, Selection, SelectionSet, BlockSelection, IndentStyle, RopeData, RopeSnapshot, RopeGraphemeIter, MAX_NODE_SIZE, MAX_CHILDREN, READ_BLOCK_SIZE};
+use node_children::NodeChildren;
+#[cfg(feature = "memmap2")]
+use mapped::{MappedRope, BlockCounts, CountIndex, edge_grapheme_of_bytes, split_into_blocks};
 //use std::old_path::Path;
 //use std::old_io::fs::File;
 //use std::old_io::BufferedWriter;
@@ -85,6 +96,16 @@ fn new_4() {
 }
 
 
+#[test]
+fn new_5() {
+    let rope = Rope::from_str(&(String::from_utf8(vec!['c' as u8; MAX_NODE_SIZE * MAX_CHILDREN * MAX_CHILDREN]).unwrap())[..]);
+    
+    assert_eq!(rope.char_count(), MAX_NODE_SIZE * MAX_CHILDREN * MAX_CHILDREN);
+    assert_eq!(rope.tree_height, 3);
+    assert!(rope.is_balanced());
+}
+
+
 #[test]
 fn counts() {
     let rope = Rope::from_str("Hello\u{000D}\u{000A}world!");
@@ -931,6 +952,22 @@ fn split_at_char_index_6() {
 }
 
 
+#[test]
+fn split_at_char_index_7() {
+    let s = String::from_utf8(vec!['c' as u8; MAX_NODE_SIZE * 397]).unwrap();
+    
+    for &pos in [0, 1, MAX_NODE_SIZE, MAX_NODE_SIZE * 11 + 3, MAX_NODE_SIZE * 200, MAX_NODE_SIZE * 397 - 1].iter() {
+        let mut rope1 = Rope::from_str(&s[..]);
+        let rope2 = rope1.split_at_char_index(pos);
+        
+        assert!(rope1.is_balanced());
+        assert!(rope2.is_balanced());
+        assert_eq!(rope1.char_count(), pos);
+        assert_eq!(rope2.char_count(), (MAX_NODE_SIZE * 397) - pos);
+    }
+}
+
+
 #[test]
 fn append_1() {
     let mut rope1 = Rope::from_str("Hello there good p");
@@ -1007,6 +1044,43 @@ fn append_6() {
 }
 
 
+#[test]
+fn append_7() {
+    let mut rope1 = Rope::from_str(&(String::from_utf8(vec!['a' as u8; MAX_NODE_SIZE * 300]).unwrap())[..]);
+    let rope2 = Rope::from_str(&(String::from_utf8(vec!['b' as u8; 7]).unwrap())[..]);
+    let rope3 = Rope::from_str(&(String::from_utf8(vec!['c' as u8; MAX_NODE_SIZE * 5]).unwrap())[..]);
+    
+    rope1.append(rope2);
+    assert!(rope1.is_balanced());
+    
+    let mut rope3 = rope3;
+    rope3.append(rope1);
+    
+    assert!(rope3.is_balanced());
+    assert_eq!(rope3.char_count(), (MAX_NODE_SIZE * 305) + 7);
+    assert_eq!(rope3.char_at_index(MAX_NODE_SIZE * 5), 'a');
+    assert_eq!(rope3.char_at_index((MAX_NODE_SIZE * 305) + 6), 'b');
+}
+
+
+#[test]
+fn append_8() {
+    // A rope with a different config is rebuilt with the config of the
+    // rope it's appended to
+    let mut rope1 = Rope::from_str_with_config("Hello ", RopeConfig::new(8, 32));
+    let rope2 = Rope::from_str_with_config(&"world! ".repeat(100)[..], RopeConfig::new(64, 128).with_hash_caching(true));
+
+    rope1.append(rope2);
+
+    assert!(rope1.is_balanced());
+    assert_eq!(rope1.to_string(), "Hello ".to_string() + &"world! ".repeat(100));
+    assert_eq!(rope1.char_count(), 706);
+    for chunk in rope1.chunk_iter() {
+        assert!(chunk.len() <= 32);
+    }
+    assert_eq!(rope1.config(), RopeConfig::new(8, 32));
+}
+
 #[test]
 fn insert_text_at_char_index_1() {
     let mut rope = Rope::from_str("Hello there!\u{000D}\u{000A}How are you?");
@@ -1668,17 +1742,52 @@ fn remove_text_11() {
 }
 
 
+#[test]
+fn insert_remove_many() {
+    let mut rope = Rope::new();
+    let mut s = String::new();
+    
+    for i in 0..5000 {
+        let pos = (i * 7) % (s.len() + 1);
+        rope.insert_text_at_char_index("Hi!", pos);
+        s.insert_str(pos, "Hi!");
+    }
+    
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), s);
+    
+    for i in 0..3500 {
+        let pos = (i * 13) % (s.len() - 3);
+        rope.remove_text_between_char_indices(pos, pos + 3);
+        remove_text_between_char_indices(&mut s, pos, pos + 3);
+    }
+    
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), s);
+}
+
+
 #[test]
 fn rebalance_1() {
     let left = Rope::from_str(&(String::from_utf8(vec!['c' as u8; MAX_NODE_SIZE * 64]).unwrap())[..]);
     let right = Rope::from_str(&(String::from_utf8(vec!['c' as u8; MAX_NODE_SIZE * 1]).unwrap())[..]);
     
+    let mut children = NodeChildren::new();
+    children.push(left);
+    children.push(right);
+    
     let mut rope = Rope {
-        data: RopeData::Branch(Box::new(left), Box::new(right)),
+        data: RopeData::Branch(children),
         char_count_: 0,
         grapheme_count_: 0,
         line_ending_count_: 0,
+        byte_count_: 0,
+        leaf_count_: 0,
         tree_height: 1,
+        content_hash_: OnceLock::new(),
+        bracket_summary_: OnceLock::new(),
+        lf_count_: OnceLock::new(),
+        config: RopeConfig::default(),
     };
     rope.update_stats();
     
@@ -1699,12 +1808,22 @@ fn rebalance_2() {
     let left = Rope::from_str(&(String::from_utf8(vec!['c' as u8; MAX_NODE_SIZE * 1]).unwrap())[..]);
     let right = Rope::from_str(&(String::from_utf8(vec!['c' as u8; MAX_NODE_SIZE * 64]).unwrap())[..]);
     
+    let mut children = NodeChildren::new();
+    children.push(left);
+    children.push(right);
+    
     let mut rope = Rope {
-        data: RopeData::Branch(Box::new(left), Box::new(right)),
+        data: RopeData::Branch(children),
         char_count_: 0,
         grapheme_count_: 0,
         line_ending_count_: 0,
+        byte_count_: 0,
+        leaf_count_: 0,
         tree_height: 1,
+        content_hash_: OnceLock::new(),
+        bracket_summary_: OnceLock::new(),
+        lf_count_: OnceLock::new(),
+        config: RopeConfig::default(),
     };
     rope.update_stats();
     
@@ -1725,12 +1844,22 @@ fn rebalance_3() {
     let left = Rope::from_str(&(String::from_utf8(vec!['c' as u8; MAX_NODE_SIZE * 53]).unwrap())[..]);
     let right = Rope::from_str(&(String::from_utf8(vec!['c' as u8; MAX_NODE_SIZE * 1]).unwrap())[..]);
     
+    let mut children = NodeChildren::new();
+    children.push(left);
+    children.push(right);
+    
     let mut rope = Rope {
-        data: RopeData::Branch(Box::new(left), Box::new(right)),
+        data: RopeData::Branch(children),
         char_count_: 0,
         grapheme_count_: 0,
         line_ending_count_: 0,
+        byte_count_: 0,
+        leaf_count_: 0,
         tree_height: 1,
+        content_hash_: OnceLock::new(),
+        bracket_summary_: OnceLock::new(),
+        lf_count_: OnceLock::new(),
+        config: RopeConfig::default(),
     };
     rope.update_stats();
     
@@ -1751,12 +1880,22 @@ fn rebalance_4() {
     let left = Rope::from_str(&(String::from_utf8(vec!['c' as u8; MAX_NODE_SIZE * 1]).unwrap())[..]);
     let right = Rope::from_str(&(String::from_utf8(vec!['c' as u8; MAX_NODE_SIZE * 53]).unwrap())[..]);
     
+    let mut children = NodeChildren::new();
+    children.push(left);
+    children.push(right);
+    
     let mut rope = Rope {
-        data: RopeData::Branch(Box::new(left), Box::new(right)),
+        data: RopeData::Branch(children),
         char_count_: 0,
         grapheme_count_: 0,
         line_ending_count_: 0,
+        byte_count_: 0,
+        leaf_count_: 0,
         tree_height: 1,
+        content_hash_: OnceLock::new(),
+        bracket_summary_: OnceLock::new(),
+        lf_count_: OnceLock::new(),
+        config: RopeConfig::default(),
     };
     rope.update_stats();
     
@@ -1770,3 +1909,2167 @@ fn rebalance_4() {
 
     assert!(rope.is_balanced());
 }
+
+
+#[test]
+fn leaf_size_1() {
+    // Multi-byte text is split into leaves by size in bytes
+    let s = "こんにちは世界！\u{1F600}e\u{0301}".repeat(500);
+    let rope = Rope::from_str(&s[..]);
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), s);
+    assert_eq!(rope.grapheme_count(), 500 * 10);
+    for chunk in rope.chunk_iter() {
+        assert!(chunk.len() <= MAX_NODE_SIZE);
+    }
+}
+
+
+#[test]
+fn leaf_size_2() {
+    let config = RopeConfig::new(16, 32);
+    let mut rope = Rope::with_config(config);
+    let mut s = String::new();
+
+    for i in 0..500 {
+        let pos = (i * 7) % (rope.char_count() + 1);
+        rope.insert_text_at_char_index("日本\u{1F600}", pos);
+        insert_text_at_char_index(&mut s, "日本\u{1F600}", pos);
+    }
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.config(), config);
+    assert_eq!(rope.to_string(), s);
+    for chunk in rope.chunk_iter() {
+        assert!(chunk.len() <= 32);
+    }
+}
+
+
+#[test]
+fn leaf_size_3() {
+    // A single grapheme larger than the maximum leaf size gets a leaf of
+    // its own
+    let config = RopeConfig::new(4, 8);
+    let big = "e\u{0301}\u{0301}\u{0301}\u{0301}\u{0301}";
+    let s = format!("ab{}cd", big);
+    let rope = Rope::from_str_with_config(&s[..], config);
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), s);
+    assert_eq!(rope.grapheme_count(), 5);
+    assert!(rope.chunk_iter().any(|chunk| chunk == big));
+}
+
+
+#[test]
+fn leaf_size_4() {
+    // Appending a rope with a different config adopts this rope's config
+    let mut rope1 = Rope::from_str_with_config(&"a".repeat(1000)[..], RopeConfig::new(16, 32));
+    let rope2 = Rope::from_str(&"b".repeat(3000)[..]);
+    rope1.append(rope2);
+
+    assert!(rope1.is_balanced());
+    assert_eq!(rope1.char_count(), 4000);
+    assert_eq!(rope1.config(), RopeConfig::new(16, 32));
+}
+
+
+#[test]
+#[should_panic]
+fn rope_config_new_1() {
+    RopeConfig::new(16, 24);
+}
+
+
+fn fragmented_rope(n: usize) -> Rope {
+    let mut children = NodeChildren::new();
+    for _ in 0..n {
+        children.push(Rope::from_str("Hi\u{000D}\u{000A}"));
+    }
+
+    let mut rope = Rope {
+        data: RopeData::Branch(children),
+        char_count_: 0,
+        grapheme_count_: 0,
+        line_ending_count_: 0,
+        byte_count_: 0,
+        leaf_count_: 0,
+        tree_height: 1,
+        content_hash_: OnceLock::new(),
+        bracket_summary_: OnceLock::new(),
+        lf_count_: OnceLock::new(),
+        config: RopeConfig::default(),
+    };
+    rope.update_stats();
+
+    return rope;
+}
+
+
+#[test]
+fn compact_1() {
+    let mut rope = fragmented_rope(MAX_CHILDREN);
+    assert_eq!(rope.leaf_count_, MAX_CHILDREN);
+
+    rope.compact();
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.leaf_count_, 1);
+    assert_eq!(rope.to_string(), "Hi\u{000D}\u{000A}".repeat(MAX_CHILDREN));
+    assert_eq!(rope.line_ending_count(), MAX_CHILDREN);
+}
+
+
+#[test]
+fn compact_2() {
+    let s = "Hello\u{000D}\u{000A}wörld, \u{1F600}日本語e\u{0301}! ".repeat(1000);
+    let mut rope = Rope::new();
+    for (i, c) in s.chars().enumerate() {
+        let mut buf = [0u8; 4];
+        rope.insert_text_at_char_index(c.encode_utf8(&mut buf), i);
+    }
+
+    rope.compact();
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), s);
+    assert_eq!(rope.grapheme_count(), Rope::from_str(&s[..]).grapheme_count());
+    let chunks: Vec<&str> = rope.chunk_iter().collect();
+    for chunk in chunks[..(chunks.len() - 2)].iter() {
+        assert!(chunk.len() > (MAX_NODE_SIZE - 4));
+    }
+}
+
+
+#[test]
+fn compact_3() {
+    // Removing text compacts a badly fragmented rope automatically
+    let mut rope = fragmented_rope(MAX_CHILDREN);
+    rope.remove_text_between_char_indices(0, 1);
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.leaf_count_, 1);
+    assert_eq!(rope.char_count(), (MAX_CHILDREN * 4) - 1);
+}
+
+
+#[test]
+fn compact_4() {
+    // Splitting compacts both halves automatically
+    let mut rope = fragmented_rope(MAX_CHILDREN);
+    let right = rope.split_at_char_index(20);
+
+    assert!(rope.is_balanced());
+    assert!(right.is_balanced());
+    assert_eq!(rope.leaf_count_, 1);
+    assert_eq!(right.leaf_count_, 1);
+    assert_eq!(rope.to_string(), "Hi\u{000D}\u{000A}".repeat(5));
+    assert_eq!(right.to_string(), "Hi\u{000D}\u{000A}".repeat(MAX_CHILDREN - 5));
+}
+
+
+#[test]
+fn compact_5() {
+    let mut rope = Rope::new();
+    rope.compact();
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.char_count(), 0);
+}
+
+
+#[test]
+fn stats_1() {
+    let rope = Rope::new();
+    let stats = rope.stats();
+
+    assert_eq!(stats.leaf_count, 1);
+    assert_eq!(stats.branch_count, 0);
+    assert_eq!(stats.tree_height, 1);
+    assert_eq!(stats.text_bytes, 0);
+    assert_eq!(stats.heap_bytes, 0);
+    assert_eq!(stats.wasted_leaf_bytes, 0);
+    assert_eq!(stats.leaf_size_histogram, vec![1]);
+}
+
+
+#[test]
+fn stats_2() {
+    let s = String::from_utf8(vec!['c' as u8; MAX_NODE_SIZE * MAX_CHILDREN * 2]).unwrap();
+    let rope = Rope::from_str(&s[..]);
+    let stats = rope.stats();
+
+    assert_eq!(stats.leaf_count, MAX_CHILDREN * 2);
+    assert_eq!(stats.branch_count, 3);
+    assert_eq!(stats.tree_height, 3);
+    assert_eq!(stats.text_bytes, s.len());
+    assert_eq!(stats.wasted_leaf_bytes, 0);
+    let node_bytes = ::std::mem::size_of::<Rope>() + (2 * ::std::mem::size_of::<usize>());
+    assert_eq!(stats.heap_bytes, s.len() + ((MAX_CHILDREN * 2) + 2) * node_bytes);
+
+    // All leaves are full, and MAX_NODE_SIZE is a power of two
+    let bucket = MAX_NODE_SIZE.trailing_zeros() as usize + 1;
+    assert_eq!(stats.leaf_size_histogram.len(), bucket + 1);
+    assert_eq!(stats.leaf_size_histogram[bucket], MAX_CHILDREN * 2);
+    assert_eq!(stats.leaf_size_histogram.iter().sum::<usize>(), stats.leaf_count);
+}
+
+
+#[test]
+fn stats_3() {
+    let mut rope = Rope::from_str(&"Hello world! ".repeat(1000)[..]);
+    rope.remove_text_between_char_indices(10, 6000);
+    let stats = rope.stats();
+
+    assert_eq!(stats.text_bytes, 13000 - 5990);
+    assert_eq!(stats.leaf_size_histogram.iter().sum::<usize>(), stats.leaf_count);
+    assert_eq!(stats.wasted_leaf_bytes, (stats.leaf_count * MAX_NODE_SIZE) - stats.text_bytes);
+}
+
+
+#[test]
+fn snapshot_1() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<RopeSnapshot>();
+
+    let s = "Hello\u{000D}\u{000A}wörld!\u{000A}".repeat(500);
+    let mut rope = Rope::from_str(&s[..]);
+    let snapshot = rope.snapshot();
+
+    rope.insert_text_at_char_index("Hi", 10);
+    rope.remove_text_between_char_indices(100, 5000);
+    rope.append(Rope::from_str("The end."));
+
+    assert_eq!(snapshot.to_string(), s);
+    assert_eq!(snapshot.char_count(), rope.char_count() - 2 + 4900 - 8);
+    assert!(snapshot.is_balanced());
+    assert!(rope.is_balanced());
+}
+
+
+#[test]
+fn snapshot_2() {
+    let s = "Hello\u{000D}\u{000A}wörld!\u{000A}".repeat(500);
+    let mut rope = Rope::from_str(&s[..]);
+    let snapshot = rope.snapshot();
+
+    // Read the snapshot on another thread while editing the rope
+    let handle = {
+        let snapshot = snapshot.clone();
+        let s = s.clone();
+        thread::spawn(move || {
+            assert_eq!(snapshot.to_string(), s);
+            assert_eq!(snapshot.char_iter().count(), s.chars().count());
+            assert_eq!(snapshot.line_iter().count(), 1001);
+            assert_eq!(snapshot.char_index_to_line_index(10), 1);
+            assert_eq!(snapshot.line_index_to_char_index(2), 14);
+            assert_eq!(snapshot.slice(5, 13).char_iter().collect::<String>(), "\u{000D}\u{000A}wörld!");
+        })
+    };
+
+    for i in 0..100 {
+        rope.insert_text_at_char_index("edit", i * 7);
+    }
+
+    handle.join().unwrap();
+    assert_eq!(rope.char_count(), s.chars().count() + 400);
+}
+
+
+#[test]
+fn snapshot_3() {
+    let rope = Rope::from_str(&"Hello world! ".repeat(1000)[..]);
+    let snapshot = rope.snapshot();
+    let mut rope2 = snapshot.to_rope();
+    rope2.remove_text_between_char_indices(0, 6);
+
+    assert_eq!(rope.to_string(), "Hello world! ".repeat(1000));
+    assert_eq!(snapshot.to_string(), "Hello world! ".repeat(1000));
+    assert_eq!(rope2.to_string(), &("Hello world! ".repeat(1000))[6..]);
+}
+
+
+#[test]
+fn from_reader_1() {
+    let s = "Hello\u{000D}\u{000A}wörld!\u{000A}日本語".repeat(100);
+    let rope = Rope::from_reader(io::Cursor::new(s.as_bytes())).unwrap();
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), s);
+    assert_eq!(rope.grapheme_count(), 1600);
+    assert_eq!(rope.line_ending_count(), 200);
+}
+
+
+#[test]
+fn from_reader_2() {
+    // A CRLF split between two blocks
+    let mut s = String::from_utf8(vec!['c' as u8; READ_BLOCK_SIZE - 1]).unwrap();
+    s.push_str("\u{000D}\u{000A}Hello!");
+    let rope = Rope::from_reader(io::Cursor::new(s.as_bytes())).unwrap();
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.char_count(), READ_BLOCK_SIZE + 7);
+    assert_eq!(rope.grapheme_count(), READ_BLOCK_SIZE + 6);
+    assert_eq!(rope.line_ending_count(), 1);
+    assert_eq!(rope.grapheme_at_index(READ_BLOCK_SIZE - 1), "\u{000D}\u{000A}");
+}
+
+
+#[test]
+fn from_reader_3() {
+    // A char and a grapheme split between two blocks
+    let mut s = String::from_utf8(vec!['c' as u8; READ_BLOCK_SIZE - 2]).unwrap();
+    s.push_str("e\u{0301}\u{00E8}Hello!");
+    let rope = Rope::from_reader(io::Cursor::new(s.as_bytes())).unwrap();
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), s);
+    assert_eq!(rope.grapheme_count(), READ_BLOCK_SIZE + 6);
+    assert_eq!(rope.grapheme_at_index(READ_BLOCK_SIZE - 2), "e\u{0301}");
+}
+
+
+#[test]
+fn from_reader_4() {
+    let bytes = [b'H', b'i', 0xFF, b'!'];
+    let result = Rope::from_reader(io::Cursor::new(&bytes[..]));
+
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
+
+#[test]
+fn from_reader_5() {
+    // Text that ends in the middle of a char
+    let bytes = [b'H', b'i', 0xC3];
+    let result = Rope::from_reader(io::Cursor::new(&bytes[..]));
+
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
+
+#[test]
+fn encoding_1() {
+    // UTF-16 with byte order mark detection
+    let s = "Hello\u{000D}\u{000A}wörld! 日本語 \u{1F600}";
+    let le: Vec<u8> = [0xFF, 0xFE].iter().cloned().chain(s.encode_utf16().flat_map(|unit| unit.to_le_bytes())).collect();
+    let be: Vec<u8> = [0xFE, 0xFF].iter().cloned().chain(s.encode_utf16().flat_map(|unit| unit.to_be_bytes())).collect();
+
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&le[..]), Encoding::Utf16, Replacement::Error).unwrap();
+    assert_eq!(rope.to_string(), s);
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&be[..]), Encoding::Utf16, Replacement::Error).unwrap();
+    assert_eq!(rope.to_string(), s);
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&be[2..]), Encoding::Utf16, Replacement::Error).unwrap();
+    assert_eq!(rope.to_string(), s);
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&le[2..]), Encoding::Utf16Le, Replacement::Error).unwrap();
+    assert_eq!(rope.to_string(), s);
+
+    let mut bytes = Vec::new();
+    rope.write_to_with_encoding(&mut bytes, Encoding::Utf16, Replacement::Error).unwrap();
+    assert_eq!(bytes, be);
+    bytes.clear();
+    rope.write_to_with_encoding(&mut bytes, Encoding::Utf16Le, Replacement::Error).unwrap();
+    assert_eq!(&bytes[..], &le[2..]);
+}
+
+
+#[test]
+fn encoding_2() {
+    // Invalid UTF-16: a lone low surrogate, a high surrogate followed by
+    // a regular char, and an odd byte at the end
+    let bytes = [0x00, b'a', 0xDC, 0x00, 0xD8, 0x00, 0x00, b'b', 0x00];
+
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&bytes[..]), Encoding::Utf16Be, Replacement::default()).unwrap();
+    assert_eq!(rope.to_string(), "a\u{FFFD}\u{FFFD}b\u{FFFD}");
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&bytes[..]), Encoding::Utf16Be, Replacement::Skip).unwrap();
+    assert_eq!(rope.to_string(), "ab");
+    let result = Rope::from_reader_with_encoding(io::Cursor::new(&bytes[..]), Encoding::Utf16Be, Replacement::Error);
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
+
+#[test]
+fn encoding_3() {
+    // Surrogate pair split between blocks
+    let mut bytes = [0x00, b'c'].repeat((READ_BLOCK_SIZE / 2) - 1);
+    bytes.extend_from_slice(&[0xD8, 0x3D, 0xDE, 0x00, 0x00, b'!']);
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&bytes[..]), Encoding::Utf16Be, Replacement::Error).unwrap();
+
+    assert_eq!(rope.char_count(), (READ_BLOCK_SIZE / 2) + 1);
+    assert_eq!(rope.char_at_index((READ_BLOCK_SIZE / 2) - 1), '\u{1F600}');
+    assert!(rope.is_balanced());
+}
+
+
+#[test]
+fn encoding_4() {
+    // Latin-1 and Windows-1252
+    let bytes = [b'H', 0xE9, 0x80, 0x81, 0x93, b'x', 0x94, 0xFF];
+
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&bytes[..]), Encoding::Latin1, Replacement::Error).unwrap();
+    assert_eq!(rope.to_string(), "H\u{00E9}\u{0080}\u{0081}\u{0093}x\u{0094}\u{00FF}");
+    let mut out = Vec::new();
+    rope.write_to_with_encoding(&mut out, Encoding::Latin1, Replacement::Error).unwrap();
+    assert_eq!(&out[..], &bytes[..]);
+
+    let bytes = [b'H', 0xE9, 0x80, 0x93, b'x', 0x94, 0xFF];
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&bytes[..]), Encoding::Windows1252, Replacement::Error).unwrap();
+    assert_eq!(rope.to_string(), "H\u{00E9}\u{20AC}\u{201C}x\u{201D}\u{00FF}");
+    out.clear();
+    rope.write_to_with_encoding(&mut out, Encoding::Windows1252, Replacement::Error).unwrap();
+    assert_eq!(&out[..], &bytes[..]);
+}
+
+
+#[test]
+fn encoding_5() {
+    // Chars that can't be encoded
+    let rope = Rope::from_str("a\u{20AC}日b");
+    let mut out = Vec::new();
+
+    rope.write_to_with_encoding(&mut out, Encoding::Latin1, Replacement::default()).unwrap();
+    assert_eq!(&out[..], b"a??b");
+    out.clear();
+    rope.write_to_with_encoding(&mut out, Encoding::Windows1252, Replacement::Char('_')).unwrap();
+    assert_eq!(&out[..], b"a\x80_b");
+    out.clear();
+    rope.write_to_with_encoding(&mut out, Encoding::Latin1, Replacement::Skip).unwrap();
+    assert_eq!(&out[..], b"ab");
+    let result = rope.write_to_with_encoding(&mut out, Encoding::Latin1, Replacement::Error);
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
+
+#[test]
+fn encoding_6() {
+    // Invalid UTF-8 is replaced rather than rejected
+    let bytes = [b'H', b'i', 0xFF, b'!', 0xE6, 0x97];
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&bytes[..]), Encoding::Utf8, Replacement::default()).unwrap();
+
+    assert_eq!(rope.to_string(), "Hi\u{FFFD}!\u{FFFD}");
+}
+
+
+#[test]
+fn encoding_7() {
+    // Decoding with a config
+    let config = RopeConfig::new(8, 32).with_leaf_storage(LeafStorage::PieceTable).with_hash_caching(true);
+    let bytes = [0x00, b'h', 0x00, b'i'].repeat(100);
+    let rope = Rope::from_reader_with_encoding_and_config(io::Cursor::new(&bytes[..]), Encoding::Utf16Be, Replacement::Error, config).unwrap();
+    assert_eq!(rope.config(), config);
+    assert_eq!(rope.to_string(), "hi".repeat(100));
+    assert!(rope.is_balanced());
+
+    let (rope, invalid) = Rope::from_bytes_with_config(&b"Hi\xFF!".repeat(20)[..], InvalidUtf8::Replace, config);
+    assert_eq!(rope.config(), config);
+    assert_eq!(rope.to_string(), "Hi\u{FFFD}!".repeat(20));
+    assert_eq!(invalid.len(), 20);
+}
+
+
+#[test]
+fn encoding_8() {
+    // The undefined bytes of Windows-1252 are invalid
+    let bytes = [b'a', 0x81, 0x8D, b'b', 0x8F, 0x90, 0x9D];
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&bytes[..]), Encoding::Windows1252, Replacement::default()).unwrap();
+    assert_eq!(rope.to_string(), "a\u{FFFD}\u{FFFD}b\u{FFFD}\u{FFFD}\u{FFFD}");
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&bytes[..]), Encoding::Windows1252, Replacement::Skip).unwrap();
+    assert_eq!(rope.to_string(), "ab");
+    let result = Rope::from_reader_with_encoding(io::Cursor::new(&bytes[..]), Encoding::Windows1252, Replacement::Error);
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    let mut out = Vec::new();
+    Rope::from_str("a\u{0081}b").write_to_with_encoding(&mut out, Encoding::Windows1252, Replacement::Char('_')).unwrap();
+    assert_eq!(&out[..], b"a_b");
+
+    // A matching byte order mark is skipped with explicit byte orders,
+    // and a mismatched one is read as a char
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&[0xFF, 0xFE, b'a', 0x00][..]), Encoding::Utf16Le, Replacement::Error).unwrap();
+    assert_eq!(rope.to_string(), "a");
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&[0xFE, 0xFF, 0x00, b'a'][..]), Encoding::Utf16Be, Replacement::Error).unwrap();
+    assert_eq!(rope.to_string(), "a");
+    let rope = Rope::from_reader_with_encoding(io::Cursor::new(&[0xFE, 0xFF, b'a', 0x00][..]), Encoding::Utf16Le, Replacement::Error).unwrap();
+    assert_eq!(rope.to_string(), "\u{FFFE}a");
+}
+
+
+#[test]
+fn from_bytes_1() {
+    let bytes = b"Hi\xFF\xFE!\xE6\x97\xA5\xE6\x97";
+    let (rope, invalid) = Rope::from_bytes(&bytes[..], InvalidUtf8::Replace);
+
+    assert_eq!(rope.to_string(), "Hi\u{FFFD}\u{FFFD}!日\u{FFFD}");
+    assert_eq!(invalid.len(), 3);
+    assert_eq!(invalid[0].byte_range, 2..3);
+    assert_eq!(invalid[0].char_index, 2);
+    assert_eq!(invalid[1].byte_range, 3..4);
+    assert_eq!(invalid[1].char_index, 3);
+    assert_eq!(invalid[2].byte_range, 8..10);
+    assert_eq!(invalid[2].char_index, 6);
+}
+
+
+#[test]
+fn from_bytes_2() {
+    let bytes = b"Hi\xFF\xFE!\xE6\x97\xA5\xE6\x97";
+    let (rope, invalid) = Rope::from_bytes(&bytes[..], InvalidUtf8::Preserve);
+
+    assert_eq!(rope.char_count(), 8);
+    assert_eq!(rope.char_at_index(6), '\u{10FFE6}');
+    assert_eq!(invalid[2].byte_range, 8..10);
+    assert_eq!(invalid[2].char_index, 6);
+    assert_eq!(&rope.to_bytes()[..], &bytes[..]);
+}
+
+
+#[test]
+fn from_bytes_3() {
+    // Valid chars in the escape range survive, as do edits
+    let s = "a\u{10FF80}b\u{10FFFF}\u{10FF7F}";
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0xC0);
+    let (mut rope, invalid) = Rope::from_bytes(&bytes[..], InvalidUtf8::Preserve);
+
+    assert_eq!(invalid.len(), 1);
+    assert_eq!(invalid[0].char_index, 11);
+    assert_eq!(&rope.to_bytes()[..], &bytes[..]);
+
+    rope.insert_text_at_char_index("日本", 1);
+    rope.remove_text_between_char_indices(0, 1);
+    let mut expected = "日本".as_bytes().to_vec();
+    expected.extend_from_slice(&bytes[1..]);
+    assert_eq!(rope.to_bytes(), expected);
+}
+
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("ropey_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    return dir;
+}
+
+
+#[test]
+fn content_hash_1() {
+    let hash = ContentHash::of_bytes(b"Hello world!");
+
+    assert_eq!(hash.len(), 12);
+    assert_eq!(hash, ContentHash::of_bytes(b"Hello ").concat(&ContentHash::of_bytes(b"world!")));
+    assert_eq!(hash, ContentHash::from_reader(io::Cursor::new(b"Hello world!")).unwrap());
+    assert!(hash != ContentHash::of_bytes(b"Hello world?"));
+    assert!(ContentHash::of_bytes(b"\0") != ContentHash::of_bytes(b"\0\0"));
+    assert!(ContentHash::new().is_empty());
+}
+
+
+#[test]
+fn content_hash_2() {
+    // The hash doesn't depend on the tree structure
+    let s = "Hello\u{000D}\u{000A}wörld!\u{000A}日本語e\u{0301}".repeat(100);
+    let rope1 = Rope::from_str(&s[..]);
+    let rope2 = Rope::from_str_with_config(&s[..], RopeConfig::new(8, 16).with_hash_caching(true));
+    let mut rope3 = Rope::new();
+    for c in s.chars().rev() {
+        rope3.insert_text_at_char_index(&c.to_string()[..], 0);
+    }
+
+    let hash = ContentHash::of_bytes(s.as_bytes());
+    assert_eq!(rope1.content_hash(), hash);
+    assert_eq!(rope2.content_hash(), hash);
+    assert_eq!(rope3.content_hash(), hash);
+}
+
+
+#[test]
+fn content_hash_3() {
+    // Cached hashes are kept up to date through edits and clones
+    let s = "Hello world!\u{000A}".repeat(200);
+    let mut rope = Rope::from_str_with_config(&s[..], RopeConfig::new(8, 16).with_hash_caching(true));
+    let mut s2 = s.clone();
+    assert_eq!(rope.content_hash(), ContentHash::of_bytes(s.as_bytes()));
+    let clone = rope.clone();
+
+    for i in 0..50 {
+        let pos = (i * 37) % rope.char_count();
+        rope.insert_text_at_char_index("日", pos);
+        insert_text_at_char_index(&mut s2, "日", pos);
+        rope.remove_text_between_char_indices(pos / 2, (pos / 2) + 3);
+        remove_text_between_char_indices(&mut s2, pos / 2, (pos / 2) + 3);
+        assert_eq!(rope.content_hash(), ContentHash::of_bytes(s2.as_bytes()));
+    }
+
+    let right = rope.split_at_char_index(1000);
+    assert_eq!(right.content_hash(), ContentHash::of_bytes(&s2.as_bytes()[char_pos_to_byte_pos(&s2, 1000)..]));
+    assert_eq!(clone.content_hash(), ContentHash::of_bytes(s.as_bytes()));
+}
+
+
+#[test]
+fn eq_1() {
+    let s = "Hello\u{000D}\u{000A}wörld!\u{000A}日本語e\u{0301}".repeat(100);
+    let rope1 = Rope::from_str(&s[..]);
+    let rope2 = Rope::from_str_with_config(&s[..], RopeConfig::new(8, 16).with_hash_caching(true));
+    let mut rope3 = rope2.clone();
+    rope3.insert_text_at_char_index("!", 1000);
+
+    assert_eq!(rope1, rope2);
+    assert!(rope1 != rope3);
+    assert!(rope2 != rope3);
+    assert!(rope1 != Rope::from_str(&s[1..]));
+    assert_eq!(Rope::new(), Rope::from_str(""));
+}
+
+
+// The cached hashes inside ropes don't change their text, and with it
+// their Hash
+#[allow(clippy::mutable_key_type)]
+#[test]
+fn hash_1() {
+    use std::collections::HashSet;
+
+    let s = "Hello world!\u{000A}".repeat(100);
+    let mut set = HashSet::new();
+    set.insert(Rope::from_str(&s[..]));
+    set.insert(Rope::from_str_with_config(&s[..], RopeConfig::new(8, 16).with_hash_caching(true)));
+    set.insert(Rope::from_str(&s[1..]));
+
+    assert_eq!(set.len(), 2);
+}
+
+
+#[test]
+fn save_to_path_4() {
+    // The hash of a save without conversions is the rope's own
+    let dir = temp_dir("save_to_path_4");
+    let path = dir.join("text.txt");
+    let rope = Rope::from_str(&"Hello world!\u{000A}".repeat(100)[..]);
+
+    assert_eq!(rope.save_to_path(&path, SaveOptions::default()).unwrap(), rope.content_hash());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn save_to_path_1() {
+    let dir = temp_dir("save_to_path_1");
+    let path = dir.join("text.txt");
+    let s = "Hello\u{000D}\u{000A}wörld!\u{000A}".repeat(500);
+    let rope = Rope::from_str(&s[..]);
+
+    let hash = rope.save_to_path(&path, SaveOptions::default()).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), s);
+    assert_eq!(hash, ContentHash::from_path(&path).unwrap());
+    assert_eq!(hash, ContentHash::of_bytes(s.as_bytes()));
+
+    // Only the file itself is left in the directory
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn save_to_path_2() {
+    // Line endings, byte order mark, and backups
+    let dir = temp_dir("save_to_path_2");
+    let path = dir.join("text.txt");
+    fs::write(&path, "old").unwrap();
+    let rope = Rope::from_str("a\u{000D}\u{000A}b\u{000A}c\u{000D}d\u{2028}");
+
+    let options = SaveOptions {
+        backup: true,
+        line_ending: Some(LineEnding::CrLf),
+        bom: true,
+        invalid_utf8: InvalidUtf8::Replace,
+    };
+    let hash = rope.save_to_path(&path, options).unwrap();
+
+    let expected = "\u{FEFF}a\u{000D}\u{000A}b\u{000D}\u{000A}c\u{000D}\u{000A}d\u{2028}";
+    assert_eq!(fs::read_to_string(&path).unwrap(), expected);
+    assert_eq!(fs::read_to_string(dir.join("text.txt~")).unwrap(), "old");
+    assert_eq!(hash, ContentHash::from_path(&path).unwrap());
+
+    let options = SaveOptions {
+        line_ending: Some(LineEnding::Lf),
+        ..SaveOptions::default()
+    };
+    rope.save_to_path(&path, options).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "a\u{000A}b\u{000A}c\u{000A}d\u{2028}");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[cfg(unix)]
+#[test]
+fn save_to_path_3() {
+    // Permissions are kept, and symlinks followed
+    use std::os::unix::fs::{PermissionsExt, symlink};
+
+    let dir = temp_dir("save_to_path_3");
+    let path = dir.join("text.txt");
+    let link = dir.join("link.txt");
+    fs::write(&path, "old").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+    symlink(&path, &link).unwrap();
+
+    Rope::from_str("new").save_to_path(&link, SaveOptions::default()).unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn save_to_path_5() {
+    // Invalid UTF-8 that was preserved is saved as it was
+    let dir = temp_dir("save_to_path_5");
+    let path = dir.join("text.txt");
+    let bytes = b"Hi\xFF\xFE!\r\n\xF4\x8F\xBF\xBF\xE6\x97".repeat(100);
+    let (rope, _) = Rope::from_bytes(&bytes[..], InvalidUtf8::Preserve);
+
+    let options = SaveOptions {
+        invalid_utf8: InvalidUtf8::Preserve,
+        ..SaveOptions::default()
+    };
+    let hash = rope.save_to_path(&path, options).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+    assert_eq!(hash, ContentHash::of_bytes(&bytes[..]));
+
+    let options = SaveOptions {
+        invalid_utf8: InvalidUtf8::Preserve,
+        line_ending: Some(LineEnding::Lf),
+        ..SaveOptions::default()
+    };
+    rope.save_to_path(&path, options).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"Hi\xFF\xFE!\n\xF4\x8F\xBF\xBF\xE6\x97".repeat(100));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn save_to_path_6() {
+    // Line ending conversion of a CRLF that's split between chunks
+    let dir = temp_dir("save_to_path_6");
+    let path = dir.join("text.txt");
+    let mut rope = Rope::from_str_with_config("a\u{000D}", RopeConfig::new(2, 4));
+    rope.append_without_seam_check(Rope::from_str_with_config("\u{000A}b\u{000D}", RopeConfig::new(2, 4)));
+    assert_eq!(rope.chunk_iter().count(), 2);
+
+    let options = SaveOptions {
+        line_ending: Some(LineEnding::Lf),
+        ..SaveOptions::default()
+    };
+    rope.save_to_path(&path, options).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "a\u{000A}b\u{000A}");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn piece_table_config() -> RopeConfig {
+    RopeConfig::new(8, 32).with_leaf_storage(LeafStorage::PieceTable)
+}
+
+
+#[test]
+fn piece_table_1() {
+    let mut s = "Hello\u{000D}\u{000A}wörld!\u{000A}日本語e\u{0301}".repeat(20);
+    let mut rope = Rope::from_str_with_config(&s[..], piece_table_config());
+
+    for i in 0..200 {
+        let pos = (i * 7) % (rope.char_count() + 1);
+        insert_text_at_char_index(&mut s, "a\u{0301}b", pos);
+        rope.insert_text_at_char_index("a\u{0301}b", pos);
+
+        let pos = (i * 13) % rope.char_count();
+        let end = pos + (i % 5).min(rope.char_count() - pos);
+        remove_text_between_char_indices(&mut s, pos, end);
+        rope.remove_text_between_char_indices(pos, end);
+
+        assert_eq!(rope.config(), piece_table_config());
+    }
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), s);
+    assert_eq!(rope.grapheme_count(), Rope::from_str(&s[..]).grapheme_count());
+}
+
+
+#[test]
+fn piece_table_2() {
+    // Splitting only narrows the spans of the leaves, which still
+    // reference the text of the original rope
+    let s = "Hello world! 日本語 ".repeat(100);
+    let mut rope = Rope::from_str_with_config(&s[..], piece_table_config());
+    let right = rope.split_at_char_index(500);
+
+    assert!(rope.is_balanced());
+    assert!(right.is_balanced());
+    assert_eq!(rope.to_string() + &right.to_string()[..], s);
+    assert_eq!(rope.stats().wasted_leaf_bytes, 0);
+
+    let mut leaf_count = 0;
+    rope.visit_nodes("s".to_string(), &mut |node, _| {
+        if let RopeData::Leaf(ref text) = node.data {
+            assert!(text.is_span());
+            leaf_count += 1;
+        }
+    });
+    assert_eq!(leaf_count, rope.stats().leaf_count);
+}
+
+
+#[test]
+fn piece_table_3() {
+    // Typing at the end of the text, and clones that don't see the edits
+    let mut rope = Rope::with_config(piece_table_config());
+    let mut snapshots = Vec::new();
+    let mut s = String::new();
+
+    for i in 0..300 {
+        let text = if (i % 10) == 9 { "\u{000A}" } else { "ab" };
+        rope.insert_text_at_char_index(text, rope.char_count());
+        s.push_str(text);
+        if (i % 50) == 0 {
+            snapshots.push((rope.clone(), s.clone()));
+        }
+    }
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), s);
+    assert_eq!(rope.line_ending_count(), 30);
+    for (rope, s) in snapshots.iter() {
+        assert_eq!(&rope.to_string(), s);
+    }
+}
+
+
+#[test]
+fn piece_table_4() {
+    // Clones of a rope edited on different threads, which both append to
+    // the buffers that they share
+    let s = "Hello world!\u{000A}".repeat(50);
+    let rope = Rope::from_str_with_config(&s[..], piece_table_config());
+
+    let handles: Vec<_> = (0..4).map(|t| {
+        let mut rope = rope.clone();
+        thread::spawn(move || {
+            for i in 0..200 {
+                let pos = (i * 31 + t) % rope.char_count();
+                rope.insert_text_at_char_index("xy", pos);
+                rope.insert_text_at_char_index("z", rope.char_count());
+            }
+            rope
+        })
+    }).collect();
+
+    for handle in handles {
+        let rope = handle.join().unwrap();
+        assert!(rope.is_balanced());
+        assert_eq!(rope.char_count(), s.len() + 600);
+        assert_eq!(rope.to_string().replace(['x', 'y', 'z'], ""), s);
+    }
+    assert_eq!(rope.to_string(), s);
+}
+
+
+#[test]
+fn piece_table_5() {
+    // Mixing storage backends
+    let mut rope = Rope::from_str_with_config("Hello ", piece_table_config());
+    rope.append(Rope::from_str_with_config("world!", RopeConfig::new(8, 32)));
+
+    assert_eq!(rope.to_string(), "Hello world!");
+    assert_eq!(rope.config().leaf_storage(), LeafStorage::PieceTable);
+}
+
+
+#[test]
+fn piece_table_6() {
+    // A few leaves that outlive the rest of the text only keep the
+    // buffers that they're in alive
+    let s = "Hello world! 日本語\u{000A}".repeat(100_000);
+    let config = RopeConfig::default().with_leaf_storage(LeafStorage::PieceTable);
+    let mut rope = Rope::from_str_with_config(&s[..], config);
+    assert!(rope.stats().span_buffer_bytes >= s.len());
+
+    let char_count = rope.char_count();
+    for i in (0..10).rev() {
+        let start = (i * char_count / 10) + 10;
+        let end = if i == 9 { char_count } else { (i + 1) * char_count / 10 };
+        rope.remove_text_between_char_indices(start, end);
+    }
+    assert_eq!(rope.char_count(), 100);
+    assert!(rope.stats().span_buffer_bytes <= 12 * (1 << 16));
+
+    rope.compact();
+    assert!(rope.stats().span_buffer_bytes <= 2 * (1 << 16));
+    assert_eq!(rope.to_string(), s.chars().take(10).collect::<String>().repeat(10));
+}
+
+#[test]
+fn diff_1() {
+    let rope1 = Rope::from_str("one\ntwo\nthree\nfour\n");
+    let rope2 = Rope::from_str("one\ntoo\nthree\nfour\nfive\n");
+    let diff = rope1.diff(&rope2);
+
+    assert_eq!(diff.lines, vec![
+        DiffOp { kind: DiffKind::Equal, old: 0..1, new: 0..1 },
+        DiffOp { kind: DiffKind::Delete, old: 1..2, new: 1..1 },
+        DiffOp { kind: DiffKind::Insert, old: 2..2, new: 1..2 },
+        DiffOp { kind: DiffKind::Equal, old: 2..4, new: 2..4 },
+        DiffOp { kind: DiffKind::Insert, old: 4..4, new: 4..5 },
+        DiffOp { kind: DiffKind::Equal, old: 4..5, new: 5..6 },
+    ]);
+    assert_eq!(diff.chars, vec![
+        DiffOp { kind: DiffKind::Equal, old: 0..5, new: 0..5 },
+        DiffOp { kind: DiffKind::Delete, old: 5..6, new: 5..5 },
+        DiffOp { kind: DiffKind::Equal, old: 6..7, new: 5..6 },
+        DiffOp { kind: DiffKind::Insert, old: 7..7, new: 6..7 },
+        DiffOp { kind: DiffKind::Equal, old: 7..19, new: 7..19 },
+        DiffOp { kind: DiffKind::Insert, old: 19..19, new: 19..24 },
+    ]);
+}
+
+
+#[test]
+fn diff_2() {
+    // Identical and empty ropes
+    let rope = Rope::from_str("Hello\nworld!");
+    let diff = rope.diff(&rope.clone());
+    assert_eq!(diff.lines, vec![DiffOp { kind: DiffKind::Equal, old: 0..2, new: 0..2 }]);
+    assert_eq!(diff.chars, vec![DiffOp { kind: DiffKind::Equal, old: 0..12, new: 0..12 }]);
+
+    let empty = Rope::new();
+    let diff = empty.diff(&Rope::new());
+    assert_eq!(diff.lines, vec![DiffOp { kind: DiffKind::Equal, old: 0..1, new: 0..1 }]);
+    assert!(diff.chars.is_empty());
+
+    let diff = empty.diff(&rope);
+    assert_eq!(diff.chars, vec![DiffOp { kind: DiffKind::Insert, old: 0..0, new: 0..12 }]);
+    let mut rope2 = empty.clone();
+    diff.apply(&mut rope2, &rope);
+    assert_eq!(rope2.to_string(), "Hello\nworld!");
+}
+
+
+#[test]
+fn diff_3() {
+    // Applying the scripts reproduces the other rope, also when an edit
+    // joins or splits a CRLF
+    let texts = [
+        "a\u{000D}\u{000A}b\u{000D}\u{000A}c",
+        "a\u{000D}b\u{000D}\u{000A}c",
+        "a\u{000D}\u{000A}\u{000A}b\u{000D}c",
+        "a\u{000A}b\u{000D}\u{000A}c\u{000D}",
+        "\u{000D}\u{000A}b\u{000D}\u{000A}",
+        "x\u{000D}y\u{000A}\u{000D}\u{000A}",
+        "日本e\u{0301}\u{000A}語\u{000D}\u{000A}",
+        "",
+    ];
+    for text1 in texts.iter() {
+        for text2 in texts.iter() {
+            let rope1 = Rope::from_str(text1);
+            let rope2 = Rope::from_str(text2);
+            let diff = rope1.diff(&rope2);
+
+            let mut rope = rope1.clone();
+            diff.apply(&mut rope, &rope2);
+            assert_eq!(rope.to_string(), *text2);
+
+            let mut rope = rope1.clone();
+            diff.apply_lines(&mut rope, &rope2);
+            assert_eq!(rope.to_string(), *text2);
+        }
+    }
+}
+
+
+#[test]
+fn diff_4() {
+    // An edited clone of a large rope
+    let text = "Hello\u{000D}\u{000A}wörld!\u{000A}日本語e\u{0301}\u{000A}".repeat(5000);
+    let rope1 = Rope::from_str(&text[..]);
+    let mut rope2 = rope1.clone();
+    rope2.insert_text_at_char_index("Inserted\u{000A}", 20000);
+    rope2.remove_text_between_char_indices(50000, 50100);
+    rope2.insert_text_at_char_index("X", 70000);
+    let diff = rope1.diff(&rope2);
+
+    let changed: usize = diff.chars.iter()
+        .filter(|op| op.kind != DiffKind::Equal)
+        .map(|op| op.old.len() + op.new.len())
+        .sum();
+    assert_eq!(changed, 9 + 100 + 1);
+
+    let mut rope = rope1.clone();
+    diff.apply(&mut rope, &rope2);
+    assert_eq!(rope.to_string(), rope2.to_string());
+
+    let mut rope = rope1.clone();
+    diff.apply_lines(&mut rope, &rope2);
+    assert_eq!(rope.to_string(), rope2.to_string());
+}
+
+
+#[test]
+fn merge_1() {
+    // Changes to different lines
+    let base = Rope::from_str("one\ntwo\nthree\nfour\nfive\n");
+    let ours = Rope::from_str("one\n2\nthree\nfour\nfive\n");
+    let theirs = Rope::from_str("zero\none\ntwo\nthree\nfive\nsix");
+    let merge = Rope::merge(&base, &ours, &theirs, MergeOptions::default());
+
+    assert_eq!(merge.rope.to_string(), "zero\none\n2\nthree\nfive\nsix");
+    assert!(!merge.has_conflicts());
+
+    // Unchanged sides
+    let merge = Rope::merge(&base, &base, &theirs, MergeOptions::default());
+    assert_eq!(merge.rope.to_string(), theirs.to_string());
+    let merge = Rope::merge(&base, &ours, &base, MergeOptions::default());
+    assert_eq!(merge.rope.to_string(), ours.to_string());
+}
+
+
+#[test]
+fn merge_2() {
+    // Changes to different places of the same line, and the same change
+    // on both sides
+    let base = Rope::from_str("let x = foo(a, b);\nlet y = 1;\n");
+    let ours = Rope::from_str("let x = bar(a, b);\nlet y = 2;\n");
+    let theirs = Rope::from_str("let x = foo(a, c);\nlet y = 2;\n");
+    let merge = Rope::merge(&base, &ours, &theirs, MergeOptions::default());
+
+    assert_eq!(merge.rope.to_string(), "let x = bar(a, c);\nlet y = 2;\n");
+    assert!(!merge.has_conflicts());
+}
+
+
+#[test]
+fn merge_3() {
+    // Conflicting changes
+    let base = Rope::from_str("one\ntwo\nthree\nfour");
+    let ours = Rope::from_str("one\nTWO\nthree\nfour!");
+    let theirs = Rope::from_str("ONE\ntwo!\nthree\nfour?");
+
+    let merge = Rope::merge(&base, &ours, &theirs, MergeOptions::default());
+    assert_eq!(merge.rope.to_string(), "one\nTWO\nthree\nfour!");
+    assert_eq!(merge.conflicts, vec![0..8, 14..19]);
+
+    let options = MergeOptions { conflict_markers: true };
+    let merge = Rope::merge(&base, &ours, &theirs, options);
+    let text = merge.rope.to_string();
+    assert_eq!(text, "<<<<<<< ours\none\nTWO\n=======\nONE\ntwo!\n>>>>>>> theirs\nthree\n<<<<<<< ours\nfour!\n=======\nfour?\n>>>>>>> theirs\n");
+    assert_eq!(merge.conflicts.len(), 2);
+    let conflict: String = merge.rope.char_iter_between_indices(merge.conflicts[1].start, merge.conflicts[1].end).collect();
+    assert_eq!(conflict, "<<<<<<< ours\nfour!\n=======\nfour?\n>>>>>>> theirs\n");
+    assert_eq!(merge.conflicts[1].end, merge.rope.char_count());
+}
+
+
+#[test]
+fn crdt_1() {
+    let mut text = CrdtText::new(1);
+    text.insert_text_at_char_index("Hello world!", 0);
+    text.insert_text_at_char_index(" there,", 5);
+    text.remove_text_between_char_indices(12, 13);
+
+    assert_eq!(text.rope().to_string(), "Hello there,world!");
+    assert_eq!(text.char_index_to_id(0), OpId::new(1, 0));
+    assert_eq!(text.char_index_to_id(5), OpId::new(1, 12));
+    assert_eq!(text.char_index_to_id(12), OpId::new(1, 6));
+    assert_eq!(text.id_to_char_index(OpId::new(1, 6)), Some(12));
+    assert_eq!(text.id_to_char_index(OpId::new(1, 18)), Some(11));
+
+    // Removed chars map to where they were
+    assert_eq!(text.id_to_char_index(OpId::new(1, 5)), Some(12));
+    assert_eq!(text.id_to_char_index(OpId::new(2, 0)), None);
+}
+
+
+#[test]
+fn crdt_2() {
+    // Concurrent inserts at the same place end up in the same order on
+    // both replicas, and ops can arrive before the ones they depend on
+    let mut a = CrdtText::new(1);
+    let mut b = CrdtText::new(2);
+    let op1 = a.insert_text_at_char_index("Hello!", 0);
+    b.apply(op1.clone());
+
+    let op2 = a.insert_text_at_char_index(" Alice", 5);
+    let op3 = a.remove_text_between_char_indices(0, 1);
+    let op4 = b.insert_text_at_char_index(" Bob", 5);
+    let op5 = b.insert_text_at_char_index(" and", 9);
+
+    b.apply(op3.clone());
+    assert_eq!(b.pending_op_count(), 0);
+    b.apply(op2.clone());
+    a.apply(op5.clone());
+    assert_eq!(a.pending_op_count(), 1);
+    a.apply(op4.clone());
+    assert_eq!(a.pending_op_count(), 0);
+
+    assert_eq!(a.rope().to_string(), b.rope().to_string());
+    assert!(a.rope().to_string() == "ello Alice Bob and!" || a.rope().to_string() == "ello Bob and Alice!");
+
+    // Applying ops again does nothing
+    for op in [op1, op2, op3, op4, op5].iter() {
+        a.apply(op.clone());
+    }
+    assert_eq!(a.rope().to_string(), b.rope().to_string());
+}
+
+
+#[test]
+fn crdt_3() {
+    // Several replicas making random edits, with the ops delivered in
+    // random orders
+    let mut seed = 12345u64;
+    let mut random = move |n: usize| {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        return ((seed >> 33) as usize) % n;
+    };
+    let words = ["a", "bc", "日本", "e\u{0301}", "\u{000D}\u{000A}", "xyz "];
+
+    let mut replicas: Vec<CrdtText> = (0..4).map(CrdtText::new).collect();
+    let mut inboxes: Vec<Vec<CrdtOp>> = vec![Vec::new(); 4];
+    for _ in 0..400 {
+        let r = random(4);
+        match random(5) {
+            0 | 1 => {
+                let pos = random(replicas[r].char_count() + 1);
+                let op = replicas[r].insert_text_at_char_index(words[random(words.len())], pos);
+                for (i, inbox) in inboxes.iter_mut().enumerate() {
+                    if i != r {
+                        inbox.push(op.clone());
+                    }
+                }
+            },
+            2 if replicas[r].char_count() > 0 => {
+                let pos_a = random(replicas[r].char_count());
+                let pos_b = pos_a + random(replicas[r].char_count() - pos_a + 1).min(4);
+                let op = replicas[r].remove_text_between_char_indices(pos_a, pos_b);
+                for (i, inbox) in inboxes.iter_mut().enumerate() {
+                    if i != r {
+                        inbox.push(op.clone());
+                    }
+                }
+            },
+            _ => {
+                // Deliver some of the ops, in a random order
+                for _ in 0..random(4) {
+                    if !inboxes[r].is_empty() {
+                        let i = random(inboxes[r].len());
+                        let op = inboxes[r].swap_remove(i);
+                        replicas[r].apply(op);
+                    }
+                }
+            },
+        }
+    }
+
+    // Deliver the rest
+    for (replica, inbox) in replicas.iter_mut().zip(inboxes.drain(..)) {
+        for op in inbox.into_iter().rev() {
+            replica.apply(op);
+        }
+        assert_eq!(replica.pending_op_count(), 0);
+    }
+
+    let text = replicas[0].rope().to_string();
+    assert!(!text.is_empty());
+    for replica in replicas.iter() {
+        assert_eq!(replica.rope().to_string(), text);
+        for pos in 0..replica.char_count() {
+            assert_eq!(replica.id_to_char_index(replica.char_index_to_id(pos)), Some(pos));
+        }
+    }
+}
+
+
+#[test]
+fn char_index_to_byte_index_1() {
+    let s = "Hello wörld!\u{000D}\u{000A}日本語 e\u{0301}".repeat(100);
+    let rope = Rope::from_str_with_config(&s[..], RopeConfig::new(8, 32));
+
+    assert_eq!(rope.byte_count(), s.len());
+    for (ci, (bi, _)) in s.char_indices().enumerate() {
+        assert_eq!(rope.char_index_to_byte_index(ci), bi);
+    }
+    assert_eq!(rope.char_index_to_byte_index(rope.char_count()), s.len());
+}
+
+
+#[test]
+fn byte_index_to_char_index_1() {
+    let s = "Hello wörld!\u{000D}\u{000A}日本語 e\u{0301}".repeat(100);
+    let rope = Rope::from_str_with_config(&s[..], RopeConfig::new(8, 32));
+
+    for bi in 0..s.len() {
+        // Bytes inside of a char map to that char
+        let ci = s.char_indices().take_while(|&(i, _)| i <= bi).count() - 1;
+        assert_eq!(rope.byte_index_to_char_index(bi), ci);
+    }
+    assert_eq!(rope.byte_index_to_char_index(s.len()), rope.char_count());
+}
+
+
+#[test]
+fn lf_count_before_char_index_1() {
+    let s = "a\nb\r\nc\rd\u{2028}e\n\n".repeat(50);
+    let mut rope = Rope::from_str_with_config(&s[..], RopeConfig::new(4, 16));
+
+    // The second round checks that edits clear the cached counts
+    for _ in 0..2 {
+        rope.remove_text_between_char_indices(100, 140);
+        rope.insert_text_at_char_index("\n\rx\n", 37);
+        let text = rope.to_string();
+
+        for i in 0..(text.chars().count() + 1) {
+            let expected = text.chars().take(i).filter(|&c| c == '\n').count();
+            assert_eq!(rope.lf_count_before_char_index(i), expected);
+        }
+    }
+}
+
+
+#[test]
+fn document_1() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut doc = Document::new(Rope::from_str("Hello\nwörld!\n"));
+    let edits = Rc::new(RefCell::new(Vec::new()));
+    let edits2 = edits.clone();
+    let id = doc.add_observer(move |edit: &Edit, rope: &Rope| {
+        edits2.borrow_mut().push((*edit, rope.to_string()));
+    });
+
+    doc.insert_text_at_char_index("日本\n", 8);
+    doc.remove_text_between_char_indices(3, 7);
+    assert!(doc.remove_observer(id));
+    assert!(!doc.remove_observer(id));
+    doc.insert_text_at_char_index("!", 0);
+
+    let edits = edits.borrow();
+    assert_eq!(edits.len(), 2);
+    assert_eq!(edits[0].0, Edit {
+        start: TextPosition { char: 8, byte: 9, line: 1, col: 2, byte_col: 3, lf_line: 1, lf_byte_col: 3 },
+        old_end: TextPosition { char: 8, byte: 9, line: 1, col: 2, byte_col: 3, lf_line: 1, lf_byte_col: 3 },
+        new_end: TextPosition { char: 11, byte: 16, line: 2, col: 0, byte_col: 0, lf_line: 2, lf_byte_col: 0 },
+    });
+    assert_eq!(edits[0].1, "Hello\nwö日本\nrld!\n");
+    assert_eq!(edits[1].0, Edit {
+        start: TextPosition { char: 3, byte: 3, line: 0, col: 3, byte_col: 3, lf_line: 0, lf_byte_col: 3 },
+        old_end: TextPosition { char: 7, byte: 7, line: 1, col: 1, byte_col: 1, lf_line: 1, lf_byte_col: 1 },
+        new_end: TextPosition { char: 3, byte: 3, line: 0, col: 3, byte_col: 3, lf_line: 0, lf_byte_col: 3 },
+    });
+    assert_eq!(edits[1].1, "Helö日本\nrld!\n");
+    assert_eq!(doc.rope().to_string(), "!Helö日本\nrld!\n");
+}
+
+
+#[test]
+fn document_2() {
+    let mut doc = Document::new(Rope::from_str("one\ntwo\n"));
+    let edit = doc.replace_text_between_char_indices(4, 7, "2\n2");
+
+    assert_eq!(doc.rope().to_string(), "one\n2\n2\n");
+    assert_eq!(edit.start.char, 4);
+    assert_eq!(edit.old_end.char, 7);
+    assert_eq!((edit.new_end.char, edit.new_end.line, edit.new_end.col), (7, 2, 1));
+}
+
+
+#[test]
+fn document_3() {
+    // Replaying each reported edit on a copy of the text keeps it equal
+    // to the document's text
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let shadow = Rc::new(RefCell::new(String::from("  one  \n\ttwo\n\n\n\nthree four ")));
+    let shadow2 = shadow.clone();
+    let count = Rc::new(RefCell::new(0));
+    let count2 = count.clone();
+    let mut doc = Document::new(Rope::from_str(&shadow.borrow()[..]));
+    doc.add_observer(move |edit: &Edit, rope: &Rope| {
+        let mut shadow = shadow2.borrow_mut();
+        let new_text = rope.char_iter_between_indices(edit.start.char, edit.new_end.char).collect::<String>();
+        let old_text = shadow.clone();
+        *shadow = format!("{}{}{}", &old_text[..edit.start.byte], new_text, &old_text[edit.old_end.byte..]);
+        *count2.borrow_mut() += 1;
+    });
+
+    let edit = doc.indent_lines(0..2, IndentStyle::Spaces(2));
+    assert_eq!((edit.start.line, edit.old_end.line, edit.new_end.line), (0, 2, 2));
+    doc.dedent_lines(1..2, IndentStyle::Spaces(2));
+    assert!(doc.indentation_spaces_to_tabs(2).is_some());
+    assert!(doc.indentation_tabs_to_spaces(4).is_some());
+    doc.insert_text_at_block(&BlockSelection::new(0..2, 1..1), "|");
+    doc.remove_block(&BlockSelection::new(0..2, 0..1));
+    doc.titlecase_between_char_indices(0, doc.rope().char_count());
+    let edit = doc.uppercase_between_char_indices(2, 3);
+    assert_eq!((edit.start.char, edit.old_end.char, edit.new_end.char), (2, 3, 3));
+    assert!(doc.trim_trailing_whitespace().is_some());
+    assert!(doc.trim_trailing_whitespace().is_none());
+    assert!(doc.collapse_blank_lines(1).is_some());
+    assert!(doc.ensure_trailing_newline(LineEnding::Lf).is_some());
+    assert!(doc.ensure_trailing_newline(LineEnding::Lf).is_none());
+
+    let mut set = SelectionSet::from_selections(vec![Selection::point(0), Selection::point(doc.rope().char_count())], 0);
+    let edit = doc.edit_selections(&mut set, |_, selection| (selection.head..selection.head, "#".to_string())).unwrap();
+    assert_eq!((edit.start.char, edit.old_end.char), (0, doc.rope().char_count() - 2));
+
+    assert_eq!(*count.borrow(), 12);
+    assert_eq!(doc.rope().to_string(), "#|       One\n|   Two\n\nThree Four\n#");
+    assert_eq!(*shadow.borrow(), doc.rope().to_string());
+}
+
+
+#[test]
+fn selection_set_1() {
+    // Sorting and merging
+    let set = SelectionSet::from_selections(vec![
+        Selection::new(10, 12),
+        Selection::point(3),
+        Selection::new(5, 2),
+        Selection::new(11, 15),
+        Selection::new(15, 17),
+        Selection::point(17),
+    ], 3);
+
+    assert_eq!(set.selections(), &[
+        Selection::new(5, 2),
+        Selection::new(10, 15),
+        Selection::new(15, 17),
+    ]);
+    assert_eq!(set.primary_index(), 1);
+    assert_eq!(set.primary(), Selection::new(10, 15));
+}
+
+
+#[test]
+fn selection_set_2() {
+    // Typing at several cursors, and over a selection
+    let mut rope = Rope::from_str("one\ntwo\nthree\n");
+    let mut set = SelectionSet::from_selections(vec![
+        Selection::point(0),
+        Selection::point(4),
+        Selection::new(8, 13),
+    ], 0);
+    set.replace(&mut rope, "> ");
+
+    assert_eq!(rope.to_string(), "> one\n> two\n> \n");
+    assert_eq!(set.selections(), &[Selection::point(2), Selection::point(8), Selection::point(14)]);
+
+    // Adjacent selections stay apart
+    let mut rope = Rope::from_str("abcdef");
+    let mut set = SelectionSet::from_selections(vec![Selection::new(0, 2), Selection::new(2, 4)], 1);
+    set.replace(&mut rope, "XYZ");
+
+    assert_eq!(rope.to_string(), "XYZXYZef");
+    assert_eq!(set.selections(), &[Selection::point(3), Selection::point(6)]);
+    assert_eq!(set.primary_index(), 1);
+
+    // Deleting the char before each cursor merges cursors that meet
+    let mut rope = Rope::from_str("abcdef");
+    let mut set = SelectionSet::from_selections(vec![Selection::point(2), Selection::point(3), Selection::point(6)], 0);
+    set.edit(&mut rope, |_, selection| ((selection.head - 1)..selection.head, String::new()));
+
+    assert_eq!(rope.to_string(), "ade");
+    assert_eq!(set.selections(), &[Selection::point(1), Selection::point(3)]);
+}
+
+
+#[test]
+fn selection_set_3() {
+    // Mapping through the edits of a document
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let set = Rc::new(RefCell::new(SelectionSet::from_selections(vec![Selection::new(2, 4), Selection::new(9, 6)], 0)));
+    let set2 = set.clone();
+    let mut doc = Document::new(Rope::from_str("0123456789"));
+    doc.add_observer(move |edit: &Edit, _: &Rope| set2.borrow_mut().map_through_edit(edit));
+
+    doc.insert_text_at_char_index("ab", 0);
+    assert_eq!(set.borrow().selections(), &[Selection::new(4, 6), Selection::new(11, 8)]);
+    doc.remove_text_between_char_indices(5, 9);
+    assert_eq!(set.borrow().selections(), &[Selection::new(4, 5), Selection::new(7, 5)]);
+    assert_eq!(set.borrow().len(), 2);
+}
+
+
+#[test]
+fn selection_set_4() {
+    let rope = Rope::from_str("e\u{0301}e\u{0301}\u{000D}\u{000A}");
+    let mut set = SelectionSet::from_selections(vec![Selection::new(1, 5), Selection::point(9)], 1);
+    set.normalize(&rope);
+
+    assert_eq!(set.selections(), &[Selection::new(0, 4), Selection::point(6)]);
+    assert_eq!(set.primary_index(), 1);
+
+    set.add(Selection::point(2));
+    assert_eq!(set.selections(), &[Selection::new(0, 4), Selection::point(6)]);
+    assert_eq!(set.primary_index(), 0);
+    set.remove(0);
+    assert_eq!(set.selections(), &[Selection::point(6)]);
+    assert_eq!(set.primary_index(), 0);
+}
+
+
+#[test]
+fn block_selection_1() {
+    let rope = Rope::from_str("abcdef\nab\n\tcdef\r\ne\u{0301}bcdef");
+    let block = BlockSelection::new(1..4, 1..5);
+    let slices: Vec<String> = rope.block_slices(&block).iter().map(|slice| slice.char_iter().collect()).collect();
+    assert_eq!(slices, vec!["b", "c", "bcde"]);
+
+    let block = BlockSelection::new(0..4, 2..6).with_tab_width(2);
+    let slices: Vec<String> = rope.block_slices(&block).iter().map(|slice| slice.char_iter().collect()).collect();
+    assert_eq!(slices, vec!["cdef", "", "cdef", "cdef"]);
+
+    // Wide chars are a single column each
+    let rope = Rope::from_str("日本語\nabc");
+    let slices: Vec<String> = rope.block_slices(&BlockSelection::new(0..2, 1..2)).iter().map(|slice| slice.char_iter().collect()).collect();
+    assert_eq!(slices, vec!["本", "b"]);
+}
+
+
+#[test]
+fn block_selection_2() {
+    let mut rope = Rope::from_str("abcdef\nab\n\tcdef\r\ne\u{0301}bcdef");
+    rope.insert_text_at_block(&BlockSelection::new(0..4, 4..4), "|");
+    assert_eq!(rope.to_string(), "abcd|ef\nab  |\n\t|cdef\r\ne\u{0301}bcd|ef");
+
+    rope.remove_block(&BlockSelection::new(1..4, 2..5));
+    assert_eq!(rope.to_string(), "abcd|ef\nab\n\tcdef\r\ne\u{0301}bef");
+}
+
+
+#[test]
+fn block_selection_3() {
+    // Many lines are edited at once, leaving the tree balanced
+    let s = "abc\n\tdefgh\nij\r\n".repeat(500);
+    let config = RopeConfig::new(8, 32);
+    let mut rope = Rope::from_str_with_config(&s[..], config);
+    rope.insert_text_at_block(&BlockSelection::new(1..1499, 3..3), "日本");
+    assert!(rope.is_balanced());
+    assert!(rope.stats().leaf_count <= rope.byte_count() / config.min_leaf_size() + 2);
+
+    let mut expected = String::new();
+    for (li, line) in s.split_inclusive('\n').enumerate() {
+        let mut line = line.to_string();
+        if (1..1499).contains(&li) {
+            match li % 3 {
+                0 => line.insert_str(3, "日本"),
+                1 => line.insert_str(1, "日本"),
+                _ => line.insert_str(2, " 日本"),
+            }
+        }
+        expected.push_str(&line[..]);
+    }
+    assert_eq!(rope.to_string(), expected);
+
+    rope.remove_block(&BlockSelection::new(0..1500, 3..5));
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), expected.replace("abc日本", "abc").replace("\t日本", "\t本").replace("ij 日本", "ij "));
+}
+
+
+#[test]
+fn matching_bracket_1() {
+    let rope = Rope::from_str("fn f(a: [u8; 2]) { g(a[0], (1)); }");
+
+    assert_eq!(rope.matching_bracket(4), Some(15));
+    assert_eq!(rope.matching_bracket(15), Some(4));
+    assert_eq!(rope.matching_bracket(8), Some(14));
+    assert_eq!(rope.matching_bracket(17), Some(33));
+    assert_eq!(rope.matching_bracket(33), Some(17));
+    assert_eq!(rope.matching_bracket(27), Some(29));
+    assert_eq!(rope.matching_bracket(0), None);
+
+    // Unmatched brackets, and other pairs
+    let rope = Rope::from_str("(a<b>)) <");
+    assert_eq!(rope.matching_bracket(6), None);
+    assert_eq!(rope.matching_bracket(2), None);
+    assert_eq!(rope.matching_bracket_with_pairs(2, &[('<', '>')]), Some(4));
+    assert_eq!(rope.matching_bracket_with_pairs(8, &[('<', '>')]), None);
+}
+
+
+#[test]
+fn matching_bracket_2() {
+    // Bracket caching gives the same results as scanning
+    let s = "{ f(x[1], (y)); [(]) }\n<{()}>\n)(".repeat(200);
+    let rope = Rope::from_str_with_config(&s[..], RopeConfig::new(8, 32));
+    let mut cached = Rope::from_str_with_config(&s[..], RopeConfig::new(8, 32).with_bracket_caching(true));
+    let pairs = [('(', ')'), ('[', ']'), ('{', '}'), ('<', '>')];
+
+    for i in 0..rope.char_count() {
+        assert_eq!(cached.matching_bracket_with_pairs(i, &pairs[..]), rope.matching_bracket_with_pairs(i, &pairs[..]));
+    }
+
+    // Including after edits
+    let mut rope = rope;
+    for &(pos, text) in [(0, "("), (5000, "))"), (100, "{[<"), (3000, "}")].iter() {
+        rope.insert_text_at_char_index(text, pos);
+        cached.insert_text_at_char_index(text, pos);
+    }
+    rope.remove_text_between_char_indices(2000, 2100);
+    cached.remove_text_between_char_indices(2000, 2100);
+    for i in 0..rope.char_count() {
+        assert_eq!(cached.matching_bracket_with_pairs(i, &pairs[..]), rope.matching_bracket_with_pairs(i, &pairs[..]));
+    }
+}
+
+
+#[test]
+fn detect_indent_style_1() {
+    let rope = Rope::from_str("fn f() {\n    if x {\n        y();\n\n    }\n}\n");
+    assert_eq!(rope.detect_indent_style(), Some(IndentStyle::Spaces(4)));
+
+    let rope = Rope::from_str("a:\n  b:\n    c\n  d\n");
+    assert_eq!(rope.detect_indent_style(), Some(IndentStyle::Spaces(2)));
+
+    let rope = Rope::from_str("fn f() {\n\tif x {\n\t\ty();\n\t}\n   \n}\n");
+    assert_eq!(rope.detect_indent_style(), Some(IndentStyle::Tabs));
+
+    let rope = Rope::from_str("a\n  \nb\n");
+    assert_eq!(rope.detect_indent_style(), None);
+}
+
+
+#[test]
+fn indent_lines_1() {
+    let mut rope = Rope::from_str("a\n\tb\n\n  c\r\nd");
+    assert_eq!(rope.line_indentation(1).char_iter().collect::<String>(), "\t");
+    assert_eq!(rope.line_indentation(3).char_iter().collect::<String>(), "  ");
+    assert_eq!(rope.line_indentation(4).char_count(), 0);
+
+    rope.indent_lines(1..5, IndentStyle::Spaces(2));
+    assert_eq!(rope.to_string(), "a\n  \tb\n\n    c\r\n  d");
+
+    rope.dedent_lines(0..5, IndentStyle::Spaces(4));
+    assert_eq!(rope.to_string(), "a\n\tb\n\nc\r\nd");
+
+    rope.dedent_lines(0..2, IndentStyle::Tabs);
+    assert_eq!(rope.to_string(), "a\nb\n\nc\r\nd");
+
+    rope.indent_lines(0..2, IndentStyle::Tabs);
+    rope.indent_lines(2..3, IndentStyle::Tabs);
+    assert_eq!(rope.to_string(), "\ta\n\tb\n\nc\r\nd");
+}
+
+
+#[test]
+fn indent_lines_2() {
+    let mut rope = Rope::from_str("\tx\t= 1;\n  \t y\n    \tz\n");
+    rope.indentation_tabs_to_spaces(4);
+    assert_eq!(rope.to_string(), "    x\t= 1;\n     y\n        z\n");
+
+    rope.indentation_spaces_to_tabs(4);
+    assert_eq!(rope.to_string(), "\tx\t= 1;\n\t y\n\t\tz\n");
+
+    // Large text stays balanced
+    let line = "    if x {\n\ty();\n    }\n";
+    let mut rope = Rope::from_str_with_config(&line.repeat(500)[..], RopeConfig::new(8, 32));
+    rope.indent_lines(100..1400, IndentStyle::Spaces(4));
+    rope.indentation_tabs_to_spaces(4);
+    assert!(rope.is_balanced());
+    let mut expected = String::new();
+    for (i, l) in line.repeat(500).split_inclusive('\n').enumerate() {
+        let l = l.replace('\t', "    ");
+        if (100..1400).contains(&i) {
+            expected.push_str("    ");
+        }
+        expected.push_str(&l[..]);
+    }
+    assert_eq!(rope.to_string(), expected);
+    assert_eq!(rope.char_count(), expected.chars().count());
+    assert_eq!(rope.line_ending_count(), 1500);
+}
+
+
+#[test]
+fn trim_trailing_whitespace_1() {
+    let mut rope = Rope::from_str("a  \n\t\r\nb\u{3000}c \u{2028}  \n \u{301}\nd \t");
+    rope.trim_trailing_whitespace();
+    assert_eq!(rope.to_string(), "a\n\r\nb\u{3000}c\u{2028}\n \u{301}\nd");
+
+    let mut rope = Rope::from_str("abc\n");
+    rope.trim_trailing_whitespace();
+    assert_eq!(rope.to_string(), "abc\n");
+
+    // Large text stays balanced, with correct stats
+    let mut rope = Rope::from_str_with_config(&"x = 1;  \n\n  \ty\t\n".repeat(500)[..], RopeConfig::new(8, 32));
+    rope.trim_trailing_whitespace();
+    let expected = "x = 1;\n\n  \ty\n".repeat(500);
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), expected);
+    assert_eq!(rope.char_count(), expected.chars().count());
+    assert_eq!(rope.line_ending_count(), 1500);
+}
+
+
+#[test]
+fn ensure_trailing_newline_1() {
+    let mut rope = Rope::from_str("a\nb");
+    rope.ensure_trailing_newline(LineEnding::CrLf);
+    assert_eq!(rope.to_string(), "a\nb\r\n");
+    rope.ensure_trailing_newline(LineEnding::Lf);
+    assert_eq!(rope.to_string(), "a\nb\r\n");
+
+    let mut rope = Rope::from_str("a\u{2029}");
+    rope.ensure_trailing_newline(LineEnding::Lf);
+    assert_eq!(rope.to_string(), "a\u{2029}");
+
+    let mut rope = Rope::new();
+    rope.ensure_trailing_newline(LineEnding::Lf);
+    assert_eq!(rope.to_string(), "");
+}
+
+
+#[test]
+fn collapse_blank_lines_1() {
+    let text = "\n\na\n\n \n\t\nb\r\n\r\nc\n\n\n";
+
+    let mut rope = Rope::from_str(text);
+    rope.collapse_blank_lines(1);
+    assert_eq!(rope.to_string(), "\na\n\nb\r\n\r\nc\n\n");
+
+    let mut rope = Rope::from_str(text);
+    rope.collapse_blank_lines(0);
+    assert_eq!(rope.to_string(), "a\nb\r\nc\n");
+
+    let mut rope = Rope::from_str(text);
+    rope.collapse_blank_lines(3);
+    assert_eq!(rope.to_string(), text);
+
+    let mut rope = Rope::from_str("a\n\n  ");
+    rope.collapse_blank_lines(0);
+    assert_eq!(rope.to_string(), "a\n");
+
+    // Large text stays balanced, with correct stats
+    let mut rope = Rope::from_str_with_config(&"x\n\n\n  \n".repeat(500)[..], RopeConfig::new(8, 32));
+    rope.collapse_blank_lines(1);
+    let expected = "x\n\n".repeat(500);
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), expected);
+    assert_eq!(rope.line_ending_count(), 1000);
+}
+
+
+#[test]
+fn case_conversion_1() {
+    let mut rope = Rope::from_str("Hello wörld, straße ǆ!");
+    assert_eq!(rope.uppercase_between_char_indices(6, 20), 21);
+    assert_eq!(rope.to_string(), "Hello WÖRLD, STRASSE ǆ!");
+    assert_eq!(rope.char_count(), 23);
+    assert_eq!(rope.byte_count(), rope.to_string().len());
+
+    assert_eq!(rope.lowercase_between_char_indices(0, 23), 23);
+    assert_eq!(rope.to_string(), "hello wörld, strasse ǆ!");
+
+    let mut rope = Rope::from_str("the cAT's hat-trick\nde\u{301}ja\u{300} vu");
+    assert_eq!(rope.titlecase_between_char_indices(0, 29), 29);
+    assert_eq!(rope.to_string(), "The Cat's Hat-Trick\nDe\u{301}ja\u{300} Vu");
+
+    // A range that starts inside of a word continues it
+    let mut rope = Rope::from_str("abc DEF");
+    rope.titlecase_between_char_indices(1, 7);
+    assert_eq!(rope.to_string(), "abc Def");
+
+    // Digraphs have their own title case forms, while other chars with
+    // one are upper cased
+    let mut rope = Rope::from_str("ǆungla ǅ Ǉ ǌ ǲ ǳ \u{1FB3}");
+    assert_eq!(rope.titlecase_between_char_indices(0, 18), 19);
+    assert_eq!(rope.to_string(), "ǅungla ǅ ǈ ǋ ǲ ǲ \u{391}\u{399}");
+}
+
+
+#[test]
+fn map_chars_1() {
+    let mut rope = Rope::from_str("a-b-c");
+    assert_eq!(rope.map_chars_between_char_indices(1, 4, |c| if c == '-' { vec![] } else { vec![c, c] }), 3);
+    assert_eq!(rope.to_string(), "abbc");
+
+    // Expanded text at a leaf boundary keeps the tree balanced and the
+    // stats correct
+    let text = "ab\ncd ".repeat(300);
+    let mut rope = Rope::from_str_with_config(&text[..], RopeConfig::new(8, 32));
+    assert_eq!(rope.map_chars_between_char_indices(10, 1500, |c| iter::repeat_n(c, 3)), 10 + 1490 * 3);
+    let mut expected: String = text.chars().take(10).collect();
+    expected.extend(text.chars().skip(10).take(1490).flat_map(|c| iter::repeat_n(c, 3)));
+    expected.extend(text.chars().skip(1500));
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), expected);
+    assert_eq!(rope.char_count(), expected.chars().count());
+    assert_eq!(rope.grapheme_count(), expected.chars().count());
+    assert_eq!(rope.line_ending_count(), expected.matches('\n').count());
+}
+
+
+#[test]
+fn map_graphemes_1() {
+    // Composing graphemes, as normalization would
+    let mut rope = Rope::from_str("e\u{301}te\u{301}, cafe\u{301}");
+    let end = rope.map_graphemes_between_char_indices(0, 7, |g| {
+        match g {
+            "e\u{301}" => "\u{e9}",
+            _ => g,
+        }.to_string()
+    });
+    assert_eq!(end, 5);
+    assert_eq!(rope.to_string(), "\u{e9}t\u{e9}, cafe\u{301}");
+    assert_eq!(rope.grapheme_count(), 9);
+
+    // Text mapped to combine with the text before the range makes a
+    // single grapheme of it
+    let mut rope = Rope::from_str("ax");
+    rope.map_graphemes_between_char_indices(1, 2, |_| "\u{301}");
+    assert_eq!(rope.to_string(), "a\u{301}");
+    assert_eq!(rope.grapheme_count(), 1);
+    assert_eq!(rope.char_count(), 2);
+}
+
+
+#[test]
+fn map_graphemes_2() {
+    // A range that ends inside of a grapheme only maps the chars in it
+    let mut rope = Rope::from_str("e\u{301}x");
+    assert_eq!(rope.map_graphemes_between_char_indices(0, 1, |g| g.to_uppercase()), 1);
+    assert_eq!(rope.to_string(), "E\u{301}x");
+
+    let mut rope = Rope::from_str("ae\u{301}x");
+    assert_eq!(rope.titlecase_between_char_indices(0, 2), 2);
+    assert_eq!(rope.to_string(), "Ae\u{301}x");
+
+    let mut doc = Document::new(Rope::from_str("ae\u{301}x"));
+    let edit = doc.titlecase_between_char_indices(0, 2);
+    assert_eq!((edit.start.char, edit.old_end.char, edit.new_end.char), (0, 2, 2));
+    assert_eq!(doc.rope().to_string(), "Ae\u{301}x");
+
+    // Title case lower cases the rest of a word's first grapheme
+    let mut rope = Rope::from_str("ǆ\u{301}AB");
+    rope.titlecase_between_char_indices(0, 4);
+    assert_eq!(rope.to_string(), "ǅ\u{301}ab");
+}
+
+
+#[cfg(feature = "rayon")]
+#[test]
+fn par_from_str_1() {
+    use rayon::prelude::*;
+
+    let s = "Hello\u{000D}\u{000A}wörld!\u{000A}日本語e\u{0301}".repeat(100000);
+    let rope = Rope::from_str(&s[..]);
+    let rope2 = Rope::build_from_str(&s[..], RopeConfig::default());
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), s);
+    assert_eq!(rope.char_count(), rope2.char_count());
+    assert_eq!(rope.grapheme_count(), rope2.grapheme_count());
+    assert_eq!(rope.line_ending_count(), rope2.line_ending_count());
+
+    let line_endings: usize = rope.par_chunk_iter().map(|chunk| chunk.matches('\u{000A}').count()).sum();
+    assert_eq!(line_endings, 200000);
+    assert_eq!(rope.par_chunk_iter().collect::<Vec<_>>().concat(), s);
+}
+
+
+#[cfg(feature = "rayon")]
+#[test]
+fn par_from_str_2() {
+    // No safe ASCII seams at all, so the pieces are split between the
+    // chars of graphemes
+    let s = "e\u{0301}\u{0302}日".repeat(200000);
+    let rope = Rope::from_str(&s[..]);
+
+    assert!(rope.is_balanced());
+    assert_eq!(rope.to_string(), s);
+    assert_eq!(rope.grapheme_count(), 400000);
+}
+
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_1() {
+    use serde_json;
+
+    let s = "Hello \"wörld\"!\u{000D}\u{000A}日本語\u{000A}".repeat(200);
+    let rope = Rope::from_str(&s[..]);
+
+    let json = serde_json::to_string(&rope).unwrap();
+    assert_eq!(json, serde_json::to_string(&s).unwrap());
+    assert_eq!(serde_json::to_string(&rope.snapshot()).unwrap(), json);
+
+    let rope2: Rope = serde_json::from_str(&json[..]).unwrap();
+    assert!(rope2.is_balanced());
+    assert_eq!(rope2.to_string(), s);
+    assert_eq!(rope2.line_ending_count(), 400);
+}
+
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_2() {
+    use serde_json;
+    use serde_chunks;
+
+    let s = "Hello \"wörld\"!\u{000D}\u{000A}日本語\u{000A}".repeat(200);
+    let rope = Rope::from_str(&s[..]);
+
+    let mut json = Vec::new();
+    serde_chunks::serialize(&rope, &mut serde_json::Serializer::new(&mut json)).unwrap();
+    let chunks: Vec<String> = serde_json::from_slice(&json[..]).unwrap();
+    assert_eq!(chunks, rope.chunk_iter().map(|chunk| chunk.to_string()).collect::<Vec<_>>());
+
+    let rope2 = serde_chunks::deserialize(&mut serde_json::Deserializer::from_slice(&json[..])).unwrap();
+    assert!(rope2.is_balanced());
+    assert_eq!(rope2.to_string(), s);
+    assert_eq!(rope2.grapheme_count(), rope.grapheme_count());
+
+    // Graphemes that are split between chunks are joined
+    let json = "[\"a\\r\", \"\\ne\", \"\\u0301\", \"\\u0302b\", \"\"]";
+    let rope3 = serde_chunks::deserialize(&mut serde_json::Deserializer::from_str(json)).unwrap();
+    assert_eq!(rope3.to_string(), "a\r\ne\u{301}\u{302}b");
+    assert_eq!(rope3.grapheme_count(), 4);
+    assert_eq!(rope3.line_ending_count(), 1);
+}
+
+
+#[cfg(feature = "tree-sitter")]
+#[test]
+fn tree_sitter_1() {
+    let s = "Hello wörld!\u{000D}\u{000A}日本語 e\u{0301}".repeat(100);
+    let rope = Rope::from_str_with_config(&s[..], RopeConfig::new(8, 32));
+
+    // Reading the chunks the way tree-sitter does
+    let mut bytes = Vec::new();
+    loop {
+        let chunk = rope.chunk_bytes_at_byte_index(bytes.len());
+        if chunk.is_empty() {
+            break;
+        }
+        bytes.extend_from_slice(chunk);
+    }
+    assert_eq!(bytes, s.as_bytes());
+
+    // Starting inside of a char
+    let bi = s.find('ö').unwrap() + 1;
+    assert_eq!(rope.chunk_bytes_at_byte_index(bi)[0], s.as_bytes()[bi]);
+}
+
+
+#[cfg(feature = "tree-sitter")]
+#[test]
+fn tree_sitter_2() {
+    use tree_sitter::{Parser, Point};
+
+    let mut doc = Document::new(Rope::from_str("fn main() {\n    é\n}\n"));
+    let edit = doc.insert_text_at_char_index("日本\nx", 17).to_input_edit();
+
+    assert_eq!(edit.start_byte, 18);
+    assert_eq!(edit.old_end_byte, 18);
+    assert_eq!(edit.new_end_byte, 26);
+    assert_eq!(edit.start_position, Point::new(1, 6));
+    assert_eq!(edit.old_end_position, Point::new(1, 6));
+    assert_eq!(edit.new_end_position, Point::new(2, 1));
+
+    // No language is set, so there's nothing to parse with
+    let mut parser = Parser::new();
+    assert!(doc.rope().parse_with_tree_sitter(&mut parser, None).is_none());
+}
+
+
+#[cfg(feature = "tree-sitter")]
+#[test]
+fn tree_sitter_3() {
+    use tree_sitter::Point;
+
+    // Only LF starts a new row, not a lone CR or a line separator
+    let mut doc = Document::new(Rope::from_str("a\rb\u{2028}c\nd"));
+    let edit = doc.insert_text_at_char_index("é\r\u{2028}", 5).to_input_edit();
+
+    assert_eq!(edit.start_byte, 7);
+    assert_eq!(edit.start_position, Point::new(0, 7));
+    assert_eq!(edit.old_end_position, Point::new(0, 7));
+    assert_eq!(edit.new_end_position, Point::new(0, 13));
+
+    let edit = doc.insert_text_at_char_index("x", 10).to_input_edit();
+    assert_eq!(edit.start_position, Point::new(1, 1));
+    assert_eq!(doc.rope().char_index_to_line_index(10), 5);
+}
+
+
+#[cfg(feature = "unicode-normalization")]
+#[test]
+fn normalization_1() {
+    let mut rope = Rope::from_str("cafe\u{301} \u{e9}te\u{301} \u{1100}\u{1161}\u{11a8} \u{1e0b}\u{323}");
+    assert_eq!(rope.nfc_between_char_indices(0, 17), 13);
+    assert_eq!(rope.to_string(), "caf\u{e9} \u{e9}t\u{e9} \u{ac01} \u{1e0d}\u{307}");
+
+    assert_eq!(rope.nfd_between_char_indices(5, 11), 15);
+    assert_eq!(rope.to_string(), "caf\u{e9} e\u{301}te\u{301} \u{1100}\u{1161}\u{11a8} \u{1e0d}\u{307}");
+    assert_eq!(rope.grapheme_count(), 12);
+
+    // Already normalized text is left alone
+    assert_eq!(rope.nfd_between_char_indices(0, 3), 3);
+    assert_eq!(rope.nfc_between_char_indices(0, 4), 4);
+    assert_eq!(rope.char_count(), 17);
+}
+
+
+#[cfg(feature = "unicode-normalization")]
+#[test]
+fn normalization_2() {
+    // A range that ends inside of a grapheme only normalizes the chars
+    // in it
+    let mut rope = Rope::from_str("e\u{301}x");
+    assert_eq!(rope.nfc_between_char_indices(0, 1), 1);
+    assert_eq!(rope.to_string(), "e\u{301}x");
+
+    let mut rope = Rope::from_str("\u{e9}\u{323}x");
+    assert_eq!(rope.nfd_between_char_indices(0, 1), 2);
+    assert_eq!(rope.to_string(), "e\u{301}\u{323}x");
+}
+
+
+/// A temporary file that's deleted when dropped, even if a test fails.
+#[cfg(feature = "memmap2")]
+struct TempFile(PathBuf);
+
+
+#[cfg(feature = "memmap2")]
+impl TempFile {
+    fn new(name: &str, contents: &[u8]) -> TempFile {
+        let path = env::temp_dir().join(format!("ropey_mapped_{}_{}", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        return TempFile(path);
+    }
+
+    fn open_small(&self) -> MappedRope {
+        MappedRope::open_with_small_blocks(&self.0, 100).unwrap()
+    }
+}
+
+
+#[cfg(feature = "memmap2")]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+
+#[cfg(feature = "memmap2")]
+fn mapped_test_text() -> String {
+    "Hello\u{000D}\u{000A}wörld!\u{000A}日本語e\u{0301}\u{1F600}".repeat(50)
+}
+
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn mapped_open_1() {
+    let s = mapped_test_text();
+    let file = TempFile::new("open_1", s.as_bytes());
+    let rope = file.open_small();
+    let rope2 = Rope::from_str(&s[..]);
+
+    assert!(rope.block_count() > 10);
+    assert_eq!(rope.char_count(), rope2.char_count());
+    assert_eq!(rope.grapheme_count(), rope2.grapheme_count());
+    assert_eq!(rope.line_ending_count(), rope2.line_ending_count());
+    assert_eq!(rope.to_rope().to_string(), s);
+    assert_eq!(rope.chunk_iter().collect::<Vec<_>>().concat(), s);
+    assert!(rope.is_fully_counted());
+}
+
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn mapped_open_2() {
+    let file = TempFile::new("open_2", b"");
+    let rope = MappedRope::open(&file.0).unwrap();
+
+    assert_eq!(rope.char_count(), 0);
+    assert_eq!(rope.char_index_to_line_index(0), 0);
+    assert_eq!(rope.line_index_to_char_index(0), 0);
+}
+
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn mapped_open_3() {
+    // Invalid UTF-8 reads as U+FFFD
+    let file = TempFile::new("open_3", b"Hi\xFF!");
+    let rope = MappedRope::open(&file.0).unwrap();
+
+    assert_eq!(rope.char_count(), 4);
+    assert_eq!(rope.slice_to_string(0, 4), "Hi\u{FFFD}!");
+}
+
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn mapped_line_index_1() {
+    let s = mapped_test_text();
+    let file = TempFile::new("line_index_1", s.as_bytes());
+    let rope = file.open_small();
+    let rope2 = Rope::from_str(&s[..]);
+
+    for pos in 0..(rope2.char_count() + 1) {
+        assert_eq!(rope.char_index_to_line_index(pos), rope2.char_index_to_line_index(pos));
+    }
+    for li in 0..(rope2.line_ending_count() + 1) {
+        assert_eq!(rope.line_index_to_char_index(li), rope2.line_index_to_char_index(li));
+    }
+}
+
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn mapped_slice_to_string_1() {
+    let s = mapped_test_text();
+    let file = TempFile::new("slice_to_string_1", s.as_bytes());
+    let rope = file.open_small();
+    let rope2 = Rope::from_str(&s[..]);
+
+    for &(a, b) in [(0, 0), (0, 10), (5, 300), (123, 456), (0, rope2.char_count())].iter() {
+        assert_eq!(rope.slice_to_string(a, b), rope2.slice(a, b).char_iter().collect::<String>());
+    }
+}
+
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn mapped_edit_1() {
+    let s = mapped_test_text();
+    let file = TempFile::new("edit_1", s.as_bytes());
+    let mut rope = file.open_small();
+    let mut rope2 = Rope::from_str(&s[..]);
+
+    for i in 0..50 {
+        let pos = (i * 37) % rope2.char_count();
+        rope.insert_text_at_char_index("Hi\u{000D}", pos);
+        rope2.insert_text_at_char_index("Hi\u{000D}", pos);
+
+        let pos = (i * 53) % (rope2.char_count() - 30);
+        rope.remove_text_between_char_indices(pos, pos + 1 + (i % 30));
+        rope2.remove_text_between_char_indices(pos, pos + 1 + (i % 30));
+
+        assert_eq!(rope.char_count(), rope2.char_count());
+        assert_eq!(rope.grapheme_count(), rope2.grapheme_count());
+        assert_eq!(rope.line_ending_count(), rope2.line_ending_count());
+    }
+    assert_eq!(rope.to_rope().to_string(), rope2.to_string());
+
+    // The file itself is untouched
+    assert_eq!(fs::read(&file.0).unwrap(), s.as_bytes());
+}
+
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn mapped_edit_2() {
+    // Removing across many blocks, leaving a CR and LF to be joined
+    let s = format!("ab\u{000D}{}\u{000A}cd", "x".repeat(1000));
+    let file = TempFile::new("edit_2", s.as_bytes());
+    let mut rope = file.open_small();
+
+    rope.remove_text_between_char_indices(3, 1003);
+
+    assert_eq!(rope.slice_to_string(0, rope.char_count()), "ab\u{000D}\u{000A}cd");
+    assert_eq!(rope.grapheme_count(), 5);
+    assert_eq!(rope.line_ending_count(), 1);
+}
+
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn mapped_lazy_counting_1() {
+    // Edits and queries near the start only count the blocks up to them
+    let s = mapped_test_text();
+    let file = TempFile::new("lazy_counting_1", s.as_bytes());
+    let mut rope = file.open_small();
+    let mut rope2 = Rope::from_str(&s[..]);
+
+    rope.insert_text_at_char_index("Hi", 5);
+    rope2.insert_text_at_char_index("Hi", 5);
+    rope.remove_text_between_char_indices(20, 150);
+    rope2.remove_text_between_char_indices(20, 150);
+    assert_eq!(rope.char_index_to_line_index(10), rope2.char_index_to_line_index(10));
+    assert_eq!(rope.line_index_to_char_index(1), rope2.line_index_to_char_index(1));
+    assert!(rope.indexed_block_count() < 5);
+    assert!(rope.block_count() > 10);
+
+    // The mapped block that was queried last is kept as a rope
+    let pos = rope2.char_count() - 10;
+    assert_eq!(rope.char_index_to_line_index(pos), rope2.char_index_to_line_index(pos));
+    assert!(rope.is_block_rope_cached_at(pos));
+
+    assert_eq!(rope.indexed_block_count(), rope.block_count());
+    assert_eq!(rope.char_count(), rope2.char_count());
+    assert_eq!(rope.slice_to_string(0, rope.char_count()), rope2.to_string());
+}
+
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn mapped_count_index_1() {
+    let mut index = CountIndex::default();
+    let counts = |n: usize| BlockCounts { char_count: n, grapheme_count: n, line_ending_count: n % 3 };
+    for n in 0..37 {
+        index.push(counts(n));
+    }
+    index.update(5, counts(5), counts(50));
+    index.truncate(30);
+
+    let mut total = 0;
+    for k in 0..31 {
+        assert_eq!(index.prefix(k).char_count, total);
+        total += if k == 5 { 50 } else { k };
+    }
+    assert_eq!(index.search(|t| t.char_count <= 100).0, 11);
+    assert_eq!(index.search(|t| t.char_count <= 1000).0, 30);
+}
+
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn mapped_edge_grapheme_of_bytes_1() {
+    let s = format!("a{}e{}", "\u{0301}".repeat(50), "\u{0301}".repeat(100));
+    assert_eq!(edge_grapheme_of_bytes(s.as_bytes(), true), format!("a{}", "\u{0301}".repeat(50)));
+    assert_eq!(edge_grapheme_of_bytes(s.as_bytes(), false), format!("e{}", "\u{0301}".repeat(100)));
+    assert_eq!(edge_grapheme_of_bytes(b"\xFFab\r\n", false), "\r\n");
+    assert_eq!(edge_grapheme_of_bytes(b"\xFFab", true), "\u{FFFD}");
+    assert_eq!(edge_grapheme_of_bytes(b"", true), "");
+}
+
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn mapped_split_into_blocks_1() {
+    use unicode_segmentation::UnicodeSegmentation;
+
+    let s = "e\u{0301}\u{0302}日本".repeat(100);
+    let ranges = split_into_blocks(s.as_bytes(), 50);
+
+    assert!(ranges.len() > 10);
+    let mut grapheme_count = 0;
+    for &(start, end) in ranges.iter() {
+        grapheme_count += UnicodeSegmentation::graphemes(&s[start..end], true).count();
+    }
+    assert_eq!(grapheme_count, 300);
+}
@@ -0,0 +1,172 @@
+//! Case conversion and other transforms of the text in a range.
+//!
+//! Each transform reads the range once, and replaces it in a single edit
+//! that rebuilds only the leaves in the range, so a transform whose output
+//! has a different length than its input is fine.
+
+#[cfg(feature = "unicode-normalization")]
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+use super::Rope;
+
+
+impl Rope {
+    /// Replaces each char between the given char indices with the chars
+    /// that the function maps it to, e.g. `char::to_uppercase`.  Returns
+    /// the char index of the end of the transformed text.
+    /// Runs in O(log N) time plus time linear to the size of the range.
+    pub fn map_chars_between_char_indices<F, I>(&mut self, pos_a: usize, pos_b: usize, f: F) -> usize
+        where F: FnMut(char) -> I, I: IntoIterator<Item = char>
+    {
+        self.check_transform_range(pos_a, pos_b, "Rope::map_chars_between_char_indices()");
+
+        let mut f = f;
+        let mut text = String::new();
+        let mut changed = false;
+        for c in self.char_iter_between_indices(pos_a, pos_b) {
+            let mut mapped = f(c).into_iter();
+            match (mapped.next(), mapped.next()) {
+                (Some(m), None) => {
+                    changed |= m != c;
+                    text.push(m);
+                },
+                (first, second) => {
+                    changed = true;
+                    text.extend(first.into_iter().chain(second).chain(mapped));
+                },
+            }
+        }
+
+        return self.replace_transformed(pos_a, pos_b, text, changed);
+    }
+
+
+    /// Replaces each grapheme between the given char indices with the text
+    /// that the function maps it to.  Returns the char index of the end of
+    /// the transformed text.
+    ///
+    /// Canonical composition and decomposition never cross a grapheme
+    /// boundary, so this is also how the normalization transforms work.
+    /// Only the chars in the range are mapped: if it starts or ends inside
+    /// of a grapheme, the function gets just the part of it in the range.
+    /// Runs in O(log N) time plus time linear to the size of the range.
+    pub fn map_graphemes_between_char_indices<F, S>(&mut self, pos_a: usize, pos_b: usize, f: F) -> usize
+        where F: FnMut(&str) -> S, S: AsRef<str>
+    {
+        self.check_transform_range(pos_a, pos_b, "Rope::map_graphemes_between_char_indices()");
+
+        let mut f = f;
+        let mut text = String::new();
+        let mut changed = false;
+        let source: String = self.char_iter_between_indices(pos_a, pos_b).collect();
+        for g in UnicodeSegmentation::graphemes(&source[..], true) {
+            let mapped = f(g);
+            changed |= mapped.as_ref() != g;
+            text.push_str(mapped.as_ref());
+        }
+
+        return self.replace_transformed(pos_a, pos_b, text, changed);
+    }
+
+
+    /// Converts the text between the given char indices to upper case.
+    /// Returns the char index of the end of the converted text, which can
+    /// differ from pos_b, e.g. "ß" becomes "SS".
+    pub fn uppercase_between_char_indices(&mut self, pos_a: usize, pos_b: usize) -> usize {
+        self.map_chars_between_char_indices(pos_a, pos_b, char::to_uppercase)
+    }
+
+
+    /// Converts the text between the given char indices to lower case.
+    /// Returns the char index of the end of the converted text.
+    pub fn lowercase_between_char_indices(&mut self, pos_a: usize, pos_b: usize) -> usize {
+        self.map_chars_between_char_indices(pos_a, pos_b, char::to_lowercase)
+    }
+
+
+    /// Converts the text between the given char indices to title case: the
+    /// first grapheme of each word in title case, and the rest of it in
+    /// lower case.  Words are runs of alphanumeric chars and apostrophes,
+    /// and a range that starts inside of a word continues it.  Returns the
+    /// char index of the end of the converted text.
+    ///
+    /// The title case of a char is its upper case, except for the Latin
+    /// digraphs like "ǆ", whose title case is "ǅ".  Other chars whose
+    /// title case differs from their upper case, like Greek vowels with
+    /// an iota subscript, are upper cased.
+    pub fn titlecase_between_char_indices(&mut self, pos_a: usize, pos_b: usize) -> usize {
+        self.check_transform_range(pos_a, pos_b, "Rope::titlecase_between_char_indices()");
+
+        let mut in_word = pos_a > 0 && is_word_char(self.char_at_index(pos_a - 1));
+        self.map_graphemes_between_char_indices(pos_a, pos_b, |g| {
+            let mut chars = g.chars();
+            let first = match chars.next() {
+                Some(c) if is_word_char(c) => c,
+                _ => {
+                    in_word = false;
+                    return g.to_string();
+                },
+            };
+            if in_word {
+                return g.to_lowercase();
+            }
+            in_word = true;
+            return titlecase_char(first) + &chars.as_str().to_lowercase();
+        })
+    }
+
+
+    /// Converts the text between the given char indices to Unicode
+    /// Normalization Form C, canonically composed.  Returns the char index
+    /// of the end of the converted text.
+    #[cfg(feature = "unicode-normalization")]
+    pub fn nfc_between_char_indices(&mut self, pos_a: usize, pos_b: usize) -> usize {
+        self.map_graphemes_between_char_indices(pos_a, pos_b, |g| g.nfc().collect::<String>())
+    }
+
+
+    /// Converts the text between the given char indices to Unicode
+    /// Normalization Form D, canonically decomposed.  Returns the char
+    /// index of the end of the converted text.
+    #[cfg(feature = "unicode-normalization")]
+    pub fn nfd_between_char_indices(&mut self, pos_a: usize, pos_b: usize) -> usize {
+        self.map_graphemes_between_char_indices(pos_a, pos_b, |g| g.nfd().collect::<String>())
+    }
+
+
+    fn check_transform_range(&self, pos_a: usize, pos_b: usize, function: &str) {
+        assert!(pos_a <= pos_b, "{}: pos_a must be less than or equal to pos_b.", function);
+        assert!(pos_b <= self.char_count(), "{}: attempted to transform text beyond the end of the text.", function);
+    }
+
+
+    /// Replaces a range with its transformed text, unless the transform
+    /// didn't change anything.  Returns the end of the new text.
+    fn replace_transformed(&mut self, pos_a: usize, pos_b: usize, text: String, changed: bool) -> usize {
+        if !changed {
+            return pos_b;
+        }
+
+        let end = pos_a + text.chars().count();
+        self.rebuild_between_char_indices(pos_a, pos_b, &text[..]);
+        return end;
+    }
+}
+
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '\'' || c == '\u{2019}'
+}
+
+
+/// Returns the title case of a char, which is its upper case except for
+/// the digraphs that have a separate title case form.
+fn titlecase_char(c: char) -> String {
+    match c {
+        '\u{01C4}'..='\u{01C6}' => "\u{01C5}".to_string(),
+        '\u{01C7}'..='\u{01C9}' => "\u{01C8}".to_string(),
+        '\u{01CA}'..='\u{01CC}' => "\u{01CB}".to_string(),
+        '\u{01F1}'..='\u{01F3}' => "\u{01F2}".to_string(),
+        _ => c.to_uppercase().collect(),
+    }
+}
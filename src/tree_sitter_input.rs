@@ -0,0 +1,58 @@
+//! Feeding ropes and their edits to tree-sitter.
+//!
+//! Tree-sitter counts rows by LF, while ropes count all Unicode line
+//! endings as line breaks, so the points here use the LF-only line and
+//! column of text positions rather than their line and column.
+
+use tree_sitter::{InputEdit, Parser, Point, Tree};
+use document::{Edit, TextPosition};
+use super::Rope;
+
+
+impl Rope {
+    /// Returns the bytes of the rope's text from a byte index to the end
+    /// of the chunk that contains it, or an empty slice at the end of the
+    /// text.  This is what tree-sitter's input callback wants.
+    pub fn chunk_bytes_at_byte_index(&self, pos: usize) -> &[u8] {
+        let pos = ::std::cmp::min(pos, self.byte_count());
+        let char_index = self.byte_index_to_char_index(pos);
+        let (chunk_start, mut chunk_iter) = self.chunk_iter_at_char_index(char_index);
+        let chunk_start_byte = self.char_index_to_byte_index(chunk_start);
+        return match chunk_iter.next() {
+            Some(chunk) => &chunk.as_bytes()[(pos - chunk_start_byte)..],
+            None => &[],
+        };
+    }
+
+
+    /// Parses the rope's text with tree-sitter, reading it chunk by chunk.
+    /// The old tree has to have been edited to match the text already,
+    /// e.g. with the edits from Edit::to_input_edit().
+    pub fn parse_with_tree_sitter(&self, parser: &mut Parser, old_tree: Option<&Tree>) -> Option<Tree> {
+        parser.parse_with(&mut |pos, _| self.chunk_bytes_at_byte_index(pos), old_tree)
+    }
+}
+
+
+impl TextPosition {
+    /// Returns the position as a tree-sitter point, whose row counts only
+    /// LFs and whose column is in bytes since the last LF.
+    pub fn to_point(&self) -> Point {
+        Point::new(self.lf_line, self.lf_byte_col)
+    }
+}
+
+
+impl Edit {
+    /// Returns the edit as a tree-sitter edit, for Tree::edit().
+    pub fn to_input_edit(&self) -> InputEdit {
+        InputEdit {
+            start_byte: self.start.byte,
+            old_end_byte: self.old_end.byte,
+            new_end_byte: self.new_end.byte,
+            start_position: self.start.to_point(),
+            old_end_position: self.old_end.to_point(),
+            new_end_position: self.new_end.to_point(),
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Whitespace cleanup.
+
+use std::ops::Range;
+use save::LineEnding;
+use string_utils::is_line_ending;
+use super::Rope;
+
+
+impl Rope {
+    /// Removes the whitespace at the end of every line, and at the end of
+    /// the text.
+    /// Runs in O(N) time, with a single edit that rebuilds the text from
+    /// the first removal to the last.
+    pub fn trim_trailing_whitespace(&mut self) {
+        let mut ranges = Vec::new();
+        let mut pos = 0;
+        let mut run_start = None;
+
+        for g in self.grapheme_iter() {
+            if is_line_ending(g) {
+                if let Some(start) = run_start.take() {
+                    ranges.push(start..pos);
+                }
+            }
+            else if is_whitespace(g) {
+                run_start = run_start.or(Some(pos));
+            }
+            else {
+                run_start = None;
+            }
+            pos += g.chars().count();
+        }
+        if let Some(start) = run_start {
+            ranges.push(start..pos);
+        }
+
+        self.remove_char_ranges(&ranges[..]);
+    }
+
+
+    /// Adds a line ending to the end of the text if it doesn't end with
+    /// one already.  Empty text is left empty.
+    pub fn ensure_trailing_newline(&mut self, line_ending: LineEnding) {
+        let grapheme_count = self.grapheme_count();
+        if grapheme_count > 0 && !is_line_ending(self.grapheme_at_index(grapheme_count - 1)) {
+            let char_count = self.char_count();
+            self.insert_text_at_char_index(line_ending.as_str(), char_count);
+        }
+    }
+
+
+    /// Removes blank lines so that there are never more than `max` of them
+    /// in a row.  Lines that only have whitespace on them count as blank.
+    /// Runs in O(N) time, with a single edit that rebuilds the text from
+    /// the first removal to the last.
+    pub fn collapse_blank_lines(&mut self, max: usize) {
+        let mut ranges = Vec::new();
+        let mut pos = 0;
+        let mut line_start = 0;
+        let mut is_blank = true;
+        let mut blank_run = 0;
+        let mut remove_start = 0;
+
+        // Lines are handled as they end.  The empty line after a final
+        // line ending isn't counted as a line.
+        let char_count = self.char_count();
+        let mut graphemes = self.grapheme_iter();
+        loop {
+            let g = graphemes.next();
+            match g {
+                Some(g) if !is_line_ending(g) => {
+                    is_blank &= is_whitespace(g);
+                    pos += g.chars().count();
+                    continue;
+                },
+                Some(g) => {
+                    pos += g.chars().count();
+                },
+                None => {
+                    if line_start == char_count {
+                        break;
+                    }
+                },
+            }
+
+            if is_blank {
+                blank_run += 1;
+                if blank_run == max + 1 {
+                    remove_start = line_start;
+                }
+            }
+            else {
+                if blank_run > max {
+                    ranges.push(remove_start..line_start);
+                }
+                blank_run = 0;
+            }
+            line_start = pos;
+            is_blank = true;
+
+            if g.is_none() {
+                break;
+            }
+        }
+        if blank_run > max {
+            ranges.push(remove_start..char_count);
+        }
+
+        self.remove_char_ranges(&ranges[..]);
+    }
+
+
+    /// Removes ordered, non-overlapping char ranges from the text, as a
+    /// single edit that rebuilds the text from the start of the first to
+    /// the end of the last.
+    fn remove_char_ranges(&mut self, ranges: &[Range<usize>]) {
+        let (start, end) = match (ranges.first(), ranges.last()) {
+            (Some(first), Some(last)) => (first.start, last.end),
+            _ => return,
+        };
+
+        let mut text = String::new();
+        let mut pos = start;
+        for range in ranges {
+            text.extend(self.char_iter_between_indices(pos, range.start));
+            pos = range.end;
+        }
+
+        self.rebuild_between_char_indices(start, end, &text[..]);
+    }
+}
+
+
+/// Returns whether a grapheme is whitespace other than a line ending.
+fn is_whitespace(g: &str) -> bool {
+    !is_line_ending(g) && g.chars().all(char::is_whitespace)
+}